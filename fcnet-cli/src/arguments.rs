@@ -2,7 +2,7 @@ use std::{net::IpAddr, str::FromStr};
 
 use cidr::IpInet;
 use clap::{Args, Parser, Subcommand, ValueEnum};
-use fcnet_types::FirecrackerIpStack;
+use fcnet_types::{FirecrackerIpStack, FirecrackerNftConfig, FirecrackerPortForward, FirecrackerPortProtocol};
 
 #[derive(Parser)]
 #[command(
@@ -14,20 +14,93 @@ use fcnet_types::FirecrackerIpStack;
 pub struct Cli {
     #[arg(help = "Optional explicit path to the \"nft\" binary", long = "nft-path")]
     pub nft_path: Option<String>,
+    #[command(flatten)]
+    pub nft_config: NftConfigArgs,
+    #[arg(
+        help = "Apply the NAT and forwarding rules of simple and namespaced networks via iptables instead of nftables",
+        long = "iptables"
+    )]
+    pub iptables: bool,
+    #[arg(
+        help = "Optional explicit path to the \"iptables\" binary",
+        long = "iptables-path",
+        requires = "iptables"
+    )]
+    pub iptables_path: Option<String>,
+    #[arg(
+        help = "Optional explicit path to the \"ip6tables\" binary",
+        long = "ip6tables-path",
+        requires = "iptables"
+    )]
+    pub ip6tables_path: Option<String>,
+    #[arg(
+        help = "Print the nftables changes of the operation as an \"nft -f\" script instead of running it",
+        long = "print-rules"
+    )]
+    pub print_rules: bool,
     #[arg(help = "Which IP stack to use", long = "ip-stack", default_value_t)]
     pub ip_stack: IpStackWrapper,
     #[arg(help = "The CIDR IP of the guest", long = "guest-ip", default_value_t = IpInet::from_str("172.16.0.2/24").unwrap())]
     pub guest_ip: IpInet,
     #[arg(
-        help = "Network interface in the default netns that handles real connectivity",
-        long = "iface",
-        default_value = "eth0"
+        help = "Optionally, the MAC address of the guest, or \"auto\" to derive it from the guest IP",
+        long = "guest-mac"
     )]
-    pub iface_name: String,
+    pub guest_mac: Option<String>,
+    #[arg(
+        help = "Network interface in the default netns that handles real connectivity, detected from the default route if not set",
+        long = "iface"
+    )]
+    pub iface_name: Option<String>,
     #[arg(help = "Name of the tap device to create", long = "tap", default_value = "tap0")]
     pub tap_name: String,
     #[arg(help = "The CIDR IP of the tap device to create", long = "tap-ip", default_value_t = IpInet::from_str("172.16.0.1/24").unwrap())]
     pub tap_ip: IpInet,
+    #[arg(
+        help = "Optionally, a dedicated IP owned by the host to SNAT the guest to and DNAT back to the guest instead of masquerading",
+        long = "nat-ip"
+    )]
+    pub nat_ip: Option<IpAddr>,
+    #[arg(
+        help = "A port forward from the host to the guest in the format [HOST_IP:]HOST_PORT:GUEST_PORT[/tcp|/udp], can be repeated",
+        long = "port-forward",
+        value_parser = parse_port_forward
+    )]
+    pub port_forwards: Vec<FirecrackerPortForward>,
+    #[arg(
+        help = "Optionally, an 802.1Q VLAN ID to tag egress traffic with via a VLAN interface on the host interface",
+        long = "vlan-id"
+    )]
+    pub vlan_id: Option<u16>,
+    #[arg(
+        help = "Optionally, the IP of the host on the VLAN to assign to the VLAN interface, needed unless using macvtap or ipvlan",
+        long = "vlan-ip",
+        requires = "vlan_id"
+    )]
+    pub vlan_ip: Option<IpInet>,
+    #[arg(
+        help = "Optionally, a firewall mark to route the guest's egress traffic by via a dedicated routing table",
+        long = "policy-fwmark",
+        requires = "policy_table_id"
+    )]
+    pub policy_fwmark: Option<u32>,
+    #[arg(
+        help = "The ID of the dedicated routing table for policy routing",
+        long = "policy-table",
+        requires = "policy_fwmark"
+    )]
+    pub policy_table_id: Option<u32>,
+    #[arg(
+        help = "Optionally, the gateway of the default route in the dedicated routing table for policy routing",
+        long = "policy-gateway",
+        requires = "policy_fwmark"
+    )]
+    pub policy_gateway: Option<IpAddr>,
+    #[arg(
+        help = "Offload the guest's established connections to a flowtable fast path",
+        long = "flowtable"
+    )]
+    pub flowtable: bool,
     #[command(flatten)]
     pub operation_group: OperationGroup,
     #[command(subcommand)]
@@ -42,14 +115,13 @@ pub enum IpStackWrapper {
     Dual,
 }
 
-impl ToString for IpStackWrapper {
-    fn to_string(&self) -> String {
-        match self {
+impl std::fmt::Display for IpStackWrapper {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
             IpStackWrapper::V4 => "v4",
             IpStackWrapper::V6 => "v6",
             IpStackWrapper::Dual => "dual",
-        }
-        .to_string()
+        })
     }
 }
 
@@ -63,8 +135,110 @@ impl From<IpStackWrapper> for FirecrackerIpStack {
     }
 }
 
+fn parse_port_forward(value: &str) -> Result<FirecrackerPortForward, String> {
+    let (value, protocol) = match value.rsplit_once('/') {
+        Some((value, "tcp")) => (value, FirecrackerPortProtocol::Tcp),
+        Some((value, "udp")) => (value, FirecrackerPortProtocol::Udp),
+        Some((_, protocol)) => return Err(format!("Unknown protocol: {protocol}")),
+        None => (value, FirecrackerPortProtocol::Tcp),
+    };
+
+    let (value, guest_port) = value.rsplit_once(':').ok_or("Missing guest port")?;
+    let guest_port = guest_port
+        .parse::<u16>()
+        .map_err(|err| format!("Invalid guest port: {err}"))?;
+    let (host_ip, host_port) = match value.rsplit_once(':') {
+        Some((host_ip, host_port)) => (
+            Some(
+                IpAddr::from_str(host_ip.trim_start_matches('[').trim_end_matches(']'))
+                    .map_err(|err| format!("Invalid host IP: {err}"))?,
+            ),
+            host_port,
+        ),
+        None => (None, value),
+    };
+    let host_port = host_port.parse::<u16>().map_err(|err| format!("Invalid host port: {err}"))?;
+
+    Ok(FirecrackerPortForward {
+        host_ip,
+        host_port,
+        protocol,
+        guest_port,
+    })
+}
+
+#[derive(Args)]
+pub struct NftConfigArgs {
+    #[arg(help = "Name of the nftables table to use", long = "nft-table", default_value = "fcnet")]
+    pub table_name: String,
+    #[arg(
+        help = "Name of the postrouting chain",
+        long = "nft-postrouting-chain",
+        default_value = "postrouting"
+    )]
+    pub postrouting_chain_name: String,
+    #[arg(
+        help = "Priority of the postrouting chain",
+        long = "nft-postrouting-priority",
+        default_value_t = 100,
+        allow_negative_numbers = true
+    )]
+    pub postrouting_priority: i32,
+    #[arg(
+        help = "Name of the prerouting chain",
+        long = "nft-prerouting-chain",
+        default_value = "prerouting"
+    )]
+    pub prerouting_chain_name: String,
+    #[arg(
+        help = "Priority of the prerouting chain",
+        long = "nft-prerouting-priority",
+        default_value_t = -100,
+        allow_negative_numbers = true
+    )]
+    pub prerouting_priority: i32,
+    #[arg(help = "Name of the mangle chain", long = "nft-mangle-chain", default_value = "mangle")]
+    pub mangle_chain_name: String,
+    #[arg(
+        help = "Priority of the mangle chain",
+        long = "nft-mangle-priority",
+        default_value_t = -150,
+        allow_negative_numbers = true
+    )]
+    pub mangle_priority: i32,
+    #[arg(
+        help = "Name of the forward filter chain",
+        long = "nft-filter-chain",
+        default_value = "filter"
+    )]
+    pub filter_chain_name: String,
+    #[arg(
+        help = "Priority of the forward filter chain",
+        long = "nft-filter-priority",
+        default_value_t = 0,
+        allow_negative_numbers = true
+    )]
+    pub filter_priority: i32,
+}
+
+impl From<NftConfigArgs> for FirecrackerNftConfig {
+    fn from(value: NftConfigArgs) -> Self {
+        Self {
+            table_name: value.table_name,
+            postrouting_chain_name: value.postrouting_chain_name,
+            postrouting_priority: value.postrouting_priority,
+            prerouting_chain_name: value.prerouting_chain_name,
+            prerouting_priority: value.prerouting_priority,
+            mangle_chain_name: value.mangle_chain_name,
+            mangle_priority: value.mangle_priority,
+            filter_chain_name: value.filter_chain_name,
+            filter_priority: value.filter_priority,
+        }
+    }
+}
+
 #[derive(Args)]
-#[group(required = true, multiple = false)]
+#[group(multiple = false)]
 pub struct OperationGroup {
     #[arg(short = 'A', long = "add", help = "Add the given network")]
     pub add: bool,
@@ -72,6 +246,8 @@ pub struct OperationGroup {
     pub delete: bool,
     #[arg(short = 'C', long = "check", help = "Check the given network")]
     pub check: bool,
+    #[arg(short = 'F', long = "flush", help = "Flush the conntrack entries of the given network")]
+    pub flush: bool,
 }
 
 #[derive(Subcommand, Clone)]
@@ -103,5 +279,50 @@ pub enum Subcommands {
             long = "forwarded-guest-ip"
         )]
         forwarded_guest_ip: Option<IpAddr>,
+        #[arg(
+            help = "Optionally, the directory the netns is persisted in instead of /var/run/netns",
+            long = "netns-dir"
+        )]
+        netns_dir: Option<String>,
+        #[arg(
+            help = "Attach to an existing netns (e.g. --netns-dir /proc/<pid>/ns --netns net) without creating or deleting it or adding a default route inside it",
+            long = "attach-netns"
+        )]
+        attach_netns: bool,
+    },
+    #[command(about = "Use a routed configuration without NAT in the default netns")]
+    Routed {
+        #[arg(
+            help = "Make the guest reachable from the host interface's L2 segment via proxy ARP or NDP",
+            long = "proxy-neighbour"
+        )]
+        proxy_neighbour: bool,
+    },
+    #[command(about = "Use a configuration with the tap attached to a bridge shared with other microVMs")]
+    Bridge {
+        #[arg(help = "Name of the bridge", long = "bridge", default_value = "fcbr0")]
+        bridge_name: String,
+    },
+    #[command(about = "Use a macvtap on the host interface without NAT, printing its /dev/tapN device on add")]
+    Macvtap {
+        #[arg(help = "Use passthru mode instead of bridge mode for the macvtap", long = "passthru")]
+        passthru: bool,
     },
+    #[command(about = "Use an L3 ipvtap on the host interface without NAT")]
+    Ipvlan,
+    #[command(about = "Show the changes that converge the host to the networks in a config file, ignoring all other arguments")]
+    Plan(ReconcileArgs),
+    #[command(about = "Apply the changes that converge the host to the networks in a config file, ignoring all other arguments")]
+    Apply(ReconcileArgs),
+}
+
+#[derive(Args, Clone)]
+pub struct ReconcileArgs {
+    #[arg(help = "Path to the JSON config file with the list of desired networks", long = "config")]
+    pub config_path: String,
+    #[arg(
+        help = "Optional path to the JSON state file with the list of applied networks, which is updated on apply and needed to plan deletions",
+        long = "state"
+    )]
+    pub state_path: Option<String>,
 }
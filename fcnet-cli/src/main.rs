@@ -1,9 +1,14 @@
 use arguments::{Cli, Subcommands};
-use clap::Parser;
+use clap::{error::ErrorKind, CommandFactory, Parser};
 use fcnet::backend::TokioBackend;
-use fcnet_types::{FirecrackerNetwork, FirecrackerNetworkOperation, FirecrackerNetworkType};
+use fcnet_types::{
+    guest_mac_from_ip, FirecrackerMacvtapMode, FirecrackerNetwork, FirecrackerNetworkOperation, FirecrackerNetworkType,
+    FirecrackerPolicyRoute, FirecrackerRuleBackend,
+};
 
 mod arguments;
+mod reconcile;
+use reconcile::reconcile;
 
 fn main() {
     let cli = Cli::parse();
@@ -17,6 +22,8 @@ fn main() {
             veth1_ip,
             veth2_ip,
             forwarded_guest_ip,
+            netns_dir,
+            attach_netns,
         } => FirecrackerNetworkType::Namespaced {
             netns_name,
             veth1_name,
@@ -24,27 +31,106 @@ fn main() {
             veth1_ip,
             veth2_ip,
             forwarded_guest_ip,
+            netns_dir,
+            attach_netns,
         },
+        Subcommands::Routed { proxy_neighbour } => FirecrackerNetworkType::Routed { proxy_neighbour },
+        Subcommands::Bridge { bridge_name } => FirecrackerNetworkType::Bridge { bridge_name },
+        Subcommands::Macvtap { passthru } => FirecrackerNetworkType::Macvtap {
+            mode: match passthru {
+                true => FirecrackerMacvtapMode::Passthru,
+                false => FirecrackerMacvtapMode::Bridge,
+            },
+        },
+        Subcommands::Ipvlan => FirecrackerNetworkType::Ipvlan,
+        Subcommands::Plan(reconcile_args) => return reconcile(reconcile_args, false),
+        Subcommands::Apply(reconcile_args) => return reconcile(reconcile_args, true),
     };
 
     let network = FirecrackerNetwork {
         nft_path: cli.nft_path,
+        nft_config: cli.nft_config.into(),
+        rule_backend: match cli.iptables {
+            true => FirecrackerRuleBackend::Iptables {
+                iptables_path: cli.iptables_path,
+                ip6tables_path: cli.ip6tables_path,
+            },
+            false => FirecrackerRuleBackend::Nftables,
+        },
         ip_stack: cli.ip_stack.into(),
+        guest_mac: match cli.guest_mac.as_deref() {
+            Some("auto") => Some(guest_mac_from_ip(cli.guest_ip.address())),
+            _ => cli.guest_mac,
+        },
         guest_ip: cli.guest_ip,
         iface_name: cli.iface_name,
         tap_name: cli.tap_name,
         tap_ip: cli.tap_ip,
         network_type,
+        nat_ip: cli.nat_ip,
+        port_forwards: cli.port_forwards,
+        vlan_id: cli.vlan_id,
+        vlan_ip: cli.vlan_ip,
+        flowtable: cli.flowtable,
+        policy_route: match (cli.policy_fwmark, cli.policy_table_id) {
+            (Some(fwmark), Some(table_id)) => Some(FirecrackerPolicyRoute {
+                fwmark,
+                table_id,
+                gateway: cli.policy_gateway,
+            }),
+            _ => None,
+        },
     };
 
-    let future = {
-        if cli.operation_group.add {
-            fcnet::run::<TokioBackend>(&network, FirecrackerNetworkOperation::Add)
-        } else if cli.operation_group.delete {
-            fcnet::run::<TokioBackend>(&network, FirecrackerNetworkOperation::Delete)
-        } else {
-            fcnet::run::<TokioBackend>(&network, FirecrackerNetworkOperation::Check)
+    let operation = if cli.operation_group.add {
+        FirecrackerNetworkOperation::Add
+    } else if cli.operation_group.delete {
+        FirecrackerNetworkOperation::Delete
+    } else if cli.operation_group.flush {
+        FirecrackerNetworkOperation::Flush
+    } else if cli.operation_group.check {
+        FirecrackerNetworkOperation::Check
+    } else {
+        Cli::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                "one of --add, --del, --check or --flush is required for operating on a single network",
+            )
+            .exit();
+    };
+
+    let future = async {
+        if cli.print_rules {
+            let rendering = fcnet::render::<TokioBackend>(&network, operation).await?;
+            print!("{}", rendering.outer.script);
+
+            if let (Some(inner), FirecrackerNetworkType::Namespaced { netns_name, .. }) = (rendering.inner, &network.network_type)
+            {
+                println!("# inside netns {netns_name}");
+                print!("{}", inner.script);
+            }
+
+            return Ok::<_, fcnet::FirecrackerNetworkError>(());
+        }
+
+        // conflicting base chains don't fail the check, since they may be intended by the host's firewall
+        match operation {
+            FirecrackerNetworkOperation::Check => {
+                for conflict in fcnet::check_with_diagnostics::<TokioBackend>(&network).await? {
+                    eprintln!("Warning: {conflict}");
+                }
+            }
+            _ => fcnet::run::<TokioBackend>(&network, operation).await?,
         }
+
+        // the VMM can't be pointed to a macvtap or ipvlan by name, so report the device it should use instead
+        if let (FirecrackerNetworkOperation::Add, FirecrackerNetworkType::Macvtap { mode: _ } | FirecrackerNetworkType::Ipvlan) =
+            (operation, &network.network_type)
+        {
+            println!("{}", fcnet::get_vmm_device::<TokioBackend>(&network).await?);
+        }
+
+        Ok(())
     };
 
     let Ok(runtime) = tokio::runtime::Builder::new_current_thread().enable_all().build() else {
@@ -0,0 +1,89 @@
+use fcnet::{backend::TokioBackend, FirecrackerNetworkAction, FirecrackerNetworkChange};
+use fcnet_types::FirecrackerNetwork;
+
+use crate::arguments::ReconcileArgs;
+
+pub fn reconcile(reconcile_args: ReconcileArgs, apply: bool) {
+    let desired_networks = match read_networks(&reconcile_args.config_path) {
+        Ok(networks) => networks,
+        Err(err) => {
+            eprintln!("Could not read the config file: {err}");
+            return;
+        }
+    };
+
+    // a missing state file means that nothing was applied yet
+    let applied_networks = match reconcile_args.state_path {
+        Some(ref state_path) if std::fs::exists(state_path).unwrap_or(false) => match read_networks(state_path) {
+            Ok(networks) => networks,
+            Err(err) => {
+                eprintln!("Could not read the state file: {err}");
+                return;
+            }
+        },
+        _ => Vec::new(),
+    };
+
+    let Ok(runtime) = tokio::runtime::Builder::new_current_thread().enable_all().build() else {
+        eprintln!("Could not start a Tokio runtime");
+        return;
+    };
+
+    runtime.block_on(async {
+        let changes = fcnet::plan::<TokioBackend>(&desired_networks, &applied_networks).await;
+
+        if changes.is_empty() {
+            println!("No changes, the networks are up to date");
+        }
+
+        for change in &changes {
+            println!("{}", describe_change(change));
+        }
+
+        if !apply {
+            return;
+        }
+
+        let mut failed_changes = Vec::new();
+
+        for change in &changes {
+            if let Err(err) = fcnet::apply::<TokioBackend>(change).await {
+                eprintln!("{} failed: {err}", describe_change(change));
+                failed_changes.push(change);
+            }
+        }
+
+        let Some(state_path) = reconcile_args.state_path else {
+            return;
+        };
+
+        let state_networks = fcnet::applied_networks(desired_networks, &failed_changes);
+
+        if let Err(err) = write_networks(&state_path, &state_networks) {
+            eprintln!("Could not write the state file: {err}");
+        }
+    });
+}
+
+fn describe_change(change: &FirecrackerNetworkChange) -> String {
+    let symbol = match change.action {
+        FirecrackerNetworkAction::Delete => '-',
+        FirecrackerNetworkAction::Repair => '~',
+        FirecrackerNetworkAction::Create => '+',
+    };
+
+    match change.reason {
+        Some(ref reason) => format!("{symbol} {} ({reason})", change.network.tap_name),
+        None => format!("{symbol} {}", change.network.tap_name),
+    }
+}
+
+fn read_networks(path: &str) -> Result<Vec<FirecrackerNetwork>, String> {
+    let json = std::fs::read_to_string(path).map_err(|err| err.to_string())?;
+    serde_json::from_str(&json).map_err(|err| err.to_string())
+}
+
+fn write_networks(path: &str, networks: &[FirecrackerNetwork]) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(networks).map_err(|err| err.to_string())?;
+    std::fs::write(path, json).map_err(|err| err.to_string())
+}
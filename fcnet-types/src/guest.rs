@@ -0,0 +1,432 @@
+use std::net::IpAddr;
+
+use cidr::IpInet;
+
+use crate::FirecrackerNetwork;
+
+/// The network configuration of a guest's interface, generated from a [FirecrackerNetwork] via
+/// [FirecrackerNetwork::guest_config] and rendered into the formats understood by common guest distributions. A
+/// dual-stack guest is configured by adding an IPv6 network that shares the tap device with an IPv4 network (or vice
+/// versa) via [FirecrackerGuestConfig::add_network].
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FirecrackerGuestConfig {
+    /// The name of the interface inside the guest, e.g. "eth0".
+    pub iface_name: String,
+    /// The MAC address of the interface, which the interface is matched by instead of its name if set.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub mac: Option<String>,
+    /// The IPs of the guest.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub addresses: Vec<IpInet>,
+    /// The gateways of the default routes, one per address family.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub gateways: Vec<IpAddr>,
+    /// The DNS servers to resolve names with.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub dns_servers: Vec<IpAddr>,
+    /// The DNS search domains.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub search_domains: Vec<String>,
+    /// The MTU of the interface, which is left at the guest's default if not set.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub mtu: Option<u32>,
+    /// The hostname of the guest, which only the kernel boot argument can carry, since the other formats only
+    /// configure the interface.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub hostname: Option<String>,
+}
+
+impl FirecrackerNetwork {
+    /// Produce the [FirecrackerGuestConfig] of the guest's interface with the given name, with the guest IP as its
+    /// address and the tap IP as its gateway, but no DNS servers, search domains, MTU or hostname, which can be set on
+    /// the returned config.
+    pub fn guest_config(&self, guest_iface_name: impl Into<String>) -> FirecrackerGuestConfig {
+        let mut guest_config = FirecrackerGuestConfig {
+            iface_name: guest_iface_name.into(),
+            ..Default::default()
+        };
+        guest_config.add_network(self);
+        guest_config
+    }
+}
+
+impl FirecrackerGuestConfig {
+    /// Add the guest IP and tap IP of another [FirecrackerNetwork] using the same tap device to the interface, taking
+    /// over its guest MAC if none is set yet.
+    pub fn add_network(&mut self, network: &FirecrackerNetwork) {
+        self.addresses.push(network.guest_ip);
+        self.gateways.push(network.tap_ip.address());
+
+        if self.mac.is_none() {
+            self.mac = network.guest_mac.clone();
+        }
+    }
+
+    /// Render a systemd-networkd ".network" file.
+    pub fn to_networkd(&self) -> String {
+        let mut output = String::from("[Match]\n");
+
+        match self.mac {
+            Some(ref mac) => output.push_str(&format!("MACAddress={mac}\n")),
+            None => output.push_str(&format!("Name={}\n", self.iface_name)),
+        }
+
+        if let Some(mtu) = self.mtu {
+            output.push_str(&format!("\n[Link]\nMTUBytes={mtu}\n"));
+        }
+
+        output.push_str("\n[Network]\n");
+
+        for address in &self.addresses {
+            output.push_str(&format!("Address={address}\n"));
+        }
+
+        for gateway in &self.gateways {
+            output.push_str(&format!("Gateway={gateway}\n"));
+        }
+
+        for dns_server in &self.dns_servers {
+            output.push_str(&format!("DNS={dns_server}\n"));
+        }
+
+        if !self.search_domains.is_empty() {
+            output.push_str(&format!("Domains={}\n", self.search_domains.join(" ")));
+        }
+
+        // the addresses are static, so router advertisements mustn't add any others
+        if self.addresses.iter().any(|address| address.address().is_ipv6()) {
+            output.push_str("IPv6AcceptRA=no\n");
+        }
+
+        output
+    }
+
+    /// Render an ifupdown "/etc/network/interfaces" stanza, with one "iface" block per address family. The DNS
+    /// options are only read by resolvconf, if installed in the guest.
+    pub fn to_ifupdown(&self) -> String {
+        let mut output = format!("auto {}\n", self.iface_name);
+        let mut first_block = true;
+
+        for (family, is_ipv6) in [("inet", false), ("inet6", true)] {
+            let addresses = self
+                .addresses
+                .iter()
+                .filter(|address| address.address().is_ipv6() == is_ipv6)
+                .collect::<Vec<_>>();
+
+            if addresses.is_empty() {
+                continue;
+            }
+
+            output.push_str(&format!("\niface {} {family} static\n", self.iface_name));
+
+            for address in addresses {
+                output.push_str(&format!("    address {address}\n"));
+            }
+
+            if let Some(gateway) = self.gateways.iter().find(|gateway| gateway.is_ipv6() == is_ipv6) {
+                output.push_str(&format!("    gateway {gateway}\n"));
+            }
+
+            if let Some(mtu) = self.mtu {
+                output.push_str(&format!("    mtu {mtu}\n"));
+            }
+
+            if first_block {
+                if !self.dns_servers.is_empty() {
+                    output.push_str(&format!("    dns-nameservers {}\n", join(&self.dns_servers, " ")));
+                }
+
+                if !self.search_domains.is_empty() {
+                    output.push_str(&format!("    dns-search {}\n", self.search_domains.join(" ")));
+                }
+
+                first_block = false;
+            }
+        }
+
+        output
+    }
+
+    /// Render a cloud-init network config (version 2) as YAML.
+    #[cfg(feature = "serde")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "serde")))]
+    pub fn to_cloud_init_yaml(&self) -> Result<String, serde_yaml::Error> {
+        let yaml = serde_yaml::to_string(&self.cloud_init_config())?;
+
+        // serde_yaml emits MACs as plain scalars, which YAML 1.1 parsers read as sexagesimal numbers if all of their
+        // octets are decimal, so they're double-quoted, for which the escaping of JSON strings is valid
+        Ok(yaml
+            .lines()
+            .map(|line| match line.split_once("macaddress: ") {
+                Some((indent, macaddress)) if !macaddress.starts_with(['"', '\'']) => {
+                    format!("{indent}macaddress: {}\n", serde_json::Value::from(macaddress))
+                }
+                _ => format!("{line}\n"),
+            })
+            .collect())
+    }
+
+    /// Render a cloud-init network config (version 2) as JSON, for example to be served via MMDS.
+    #[cfg(feature = "serde")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "serde")))]
+    pub fn to_cloud_init_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string(&self.cloud_init_config())
+    }
+
+    /// Render a kernel "ip=" boot argument like [FirecrackerNetwork::guest_ip_boot_arg], additionally carrying the
+    /// hostname and up to 2 IPv4 DNS servers. The kernel only autoconfigures IPv4, so [None] is returned if the
+    /// interface has no IPv4 address.
+    pub fn to_boot_arg(&self) -> Option<String> {
+        let address = self.addresses.iter().find(|address| address.address().is_ipv4())?;
+        let gateway = self
+            .gateways
+            .iter()
+            .find(|gateway| gateway.is_ipv4())
+            .map(ToString::to_string)
+            .unwrap_or_default();
+        let mut output = format!(
+            "ip={}::{gateway}:{}:{}:{}:off",
+            address.address(),
+            address.mask(),
+            self.hostname.as_deref().unwrap_or_default(),
+            self.iface_name
+        );
+
+        for dns_server in self.dns_servers.iter().filter(|dns_server| dns_server.is_ipv4()).take(2) {
+            output.push_str(&format!(":{dns_server}"));
+        }
+
+        Some(output)
+    }
+
+    #[cfg(feature = "serde")]
+    fn cloud_init_config(&self) -> CloudInitConfig<'_> {
+        let ethernet = CloudInitEthernet {
+            r#match: self.mac.as_deref().map(|macaddress| CloudInitMatch { macaddress }),
+            set_name: self.mac.as_ref().map(|_| self.iface_name.as_str()),
+            addresses: &self.addresses,
+            routes: self
+                .gateways
+                .iter()
+                .map(|gateway| CloudInitRoute {
+                    to: match gateway.is_ipv6() {
+                        true => "::/0",
+                        false => "0.0.0.0/0",
+                    },
+                    via: *gateway,
+                })
+                .collect(),
+            mtu: self.mtu,
+            nameservers: match self.dns_servers.is_empty() && self.search_domains.is_empty() {
+                true => None,
+                false => Some(CloudInitNameservers {
+                    addresses: &self.dns_servers,
+                    search: &self.search_domains,
+                }),
+            },
+        };
+
+        CloudInitConfig {
+            network: CloudInitNetwork {
+                version: 2,
+                ethernets: [(self.iface_name.as_str(), ethernet)].into(),
+            },
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize)]
+struct CloudInitConfig<'a> {
+    network: CloudInitNetwork<'a>,
+}
+
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize)]
+struct CloudInitNetwork<'a> {
+    version: u32,
+    ethernets: std::collections::BTreeMap<&'a str, CloudInitEthernet<'a>>,
+}
+
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct CloudInitEthernet<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    r#match: Option<CloudInitMatch<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    set_name: Option<&'a str>,
+    addresses: &'a [IpInet],
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    routes: Vec<CloudInitRoute>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mtu: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nameservers: Option<CloudInitNameservers<'a>>,
+}
+
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize)]
+struct CloudInitMatch<'a> {
+    macaddress: &'a str,
+}
+
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize)]
+struct CloudInitRoute {
+    to: &'static str,
+    via: IpAddr,
+}
+
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize)]
+struct CloudInitNameservers<'a> {
+    addresses: &'a [IpAddr],
+    search: &'a [String],
+}
+
+fn join(addrs: &[IpAddr], separator: &str) -> String {
+    addrs.iter().map(ToString::to_string).collect::<Vec<_>>().join(separator)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FirecrackerGuestConfig;
+
+    fn dual_stack_config() -> FirecrackerGuestConfig {
+        FirecrackerGuestConfig {
+            iface_name: "eth0".to_string(),
+            mac: Some("06:00:ac:10:00:02".to_string()),
+            addresses: vec!["172.16.0.2/24".parse().unwrap(), "fd00::2/64".parse().unwrap()],
+            gateways: vec!["172.16.0.1".parse().unwrap(), "fd00::1".parse().unwrap()],
+            dns_servers: vec!["1.1.1.1".parse().unwrap(), "2606:4700:4700::1111".parse().unwrap()],
+            search_domains: vec!["example.com".to_string()],
+            mtu: Some(1420),
+            hostname: Some("vm".to_string()),
+        }
+    }
+
+    fn minimal_config() -> FirecrackerGuestConfig {
+        FirecrackerGuestConfig {
+            iface_name: "eth0".to_string(),
+            addresses: vec!["172.16.0.2/24".parse().unwrap()],
+            gateways: vec!["172.16.0.1".parse().unwrap()],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn networkd_output() {
+        assert_eq!(
+            dual_stack_config().to_networkd(),
+            "[Match]\nMACAddress=06:00:ac:10:00:02\n\n[Link]\nMTUBytes=1420\n\n[Network]\nAddress=172.16.0.2/24\n\
+             Address=fd00::2/64\nGateway=172.16.0.1\nGateway=fd00::1\nDNS=1.1.1.1\nDNS=2606:4700:4700::1111\n\
+             Domains=example.com\nIPv6AcceptRA=no\n"
+        );
+        assert_eq!(
+            minimal_config().to_networkd(),
+            "[Match]\nName=eth0\n\n[Network]\nAddress=172.16.0.2/24\nGateway=172.16.0.1\n"
+        );
+    }
+
+    #[test]
+    fn ifupdown_output() {
+        assert_eq!(
+            dual_stack_config().to_ifupdown(),
+            "auto eth0\n\niface eth0 inet static\n    address 172.16.0.2/24\n    gateway 172.16.0.1\n    mtu 1420\n    \
+             dns-nameservers 1.1.1.1 2606:4700:4700::1111\n    dns-search example.com\n\niface eth0 inet6 static\n    \
+             address fd00::2/64\n    gateway fd00::1\n    mtu 1420\n"
+        );
+        assert_eq!(
+            minimal_config().to_ifupdown(),
+            "auto eth0\n\niface eth0 inet static\n    address 172.16.0.2/24\n    gateway 172.16.0.1\n"
+        );
+    }
+
+    #[test]
+    fn cloud_init_yaml_output() {
+        assert_eq!(
+            dual_stack_config().to_cloud_init_yaml().unwrap(),
+            "network:
+  version: 2
+  ethernets:
+    eth0:
+      match:
+        macaddress: \"06:00:ac:10:00:02\"
+      set-name: eth0
+      addresses:
+      - 172.16.0.2/24
+      - fd00::2/64
+      routes:
+      - to: 0.0.0.0/0
+        via: 172.16.0.1
+      - to: ::/0
+        via: fd00::1
+      mtu: 1420
+      nameservers:
+        addresses:
+        - 1.1.1.1
+        - 2606:4700:4700::1111
+        search:
+        - example.com
+"
+        );
+        assert_eq!(
+            minimal_config().to_cloud_init_yaml().unwrap(),
+            "network:
+  version: 2
+  ethernets:
+    eth0:
+      addresses:
+      - 172.16.0.2/24
+      routes:
+      - to: 0.0.0.0/0
+        via: 172.16.0.1
+"
+        );
+    }
+
+    #[test]
+    fn cloud_init_yaml_quotes_decimal_mac() {
+        let config = FirecrackerGuestConfig {
+            mac: Some("12:34:56:12:34:56".to_string()),
+            ..minimal_config()
+        };
+
+        assert!(config
+            .to_cloud_init_yaml()
+            .unwrap()
+            .contains("        macaddress: \"12:34:56:12:34:56\"\n"));
+    }
+
+    #[test]
+    fn cloud_init_json_output() {
+        assert_eq!(
+            dual_stack_config().to_cloud_init_json().unwrap(),
+            r#"{"network":{"version":2,"ethernets":{"eth0":{"match":{"macaddress":"06:00:ac:10:00:02"},"set-name":"eth0","#
+                .to_string()
+                + r#""addresses":["172.16.0.2/24","fd00::2/64"],"routes":[{"to":"0.0.0.0/0","via":"172.16.0.1"},"#
+                + r#"{"to":"::/0","via":"fd00::1"}],"mtu":1420,"nameservers":{"addresses":["1.1.1.1","#
+                + r#""2606:4700:4700::1111"],"search":["example.com"]}}}}}"#
+        );
+        assert_eq!(
+            minimal_config().to_cloud_init_json().unwrap(),
+            r#"{"network":{"version":2,"ethernets":{"eth0":{"addresses":["172.16.0.2/24"],"#.to_string()
+                + r#""routes":[{"to":"0.0.0.0/0","via":"172.16.0.1"}]}}}}"#
+        );
+    }
+
+    #[test]
+    fn boot_arg_output() {
+        assert_eq!(
+            dual_stack_config().to_boot_arg().as_deref(),
+            Some("ip=172.16.0.2::172.16.0.1:255.255.255.0:vm:eth0:off:1.1.1.1")
+        );
+        assert_eq!(
+            minimal_config().to_boot_arg().as_deref(),
+            Some("ip=172.16.0.2::172.16.0.1:255.255.255.0::eth0:off")
+        );
+    }
+}
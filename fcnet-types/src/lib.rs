@@ -1,12 +1,26 @@
 #![cfg_attr(docsrs, feature(doc_cfg))]
 
-#[cfg(all(not(feature = "simple"), not(feature = "namespaced")))]
-compile_error!("Either \"simple\" or \"namespaced\" networking feature flags must be enabled");
+#[cfg(all(
+    not(feature = "simple"),
+    not(feature = "namespaced"),
+    not(feature = "routed"),
+    not(feature = "bridge"),
+    not(feature = "macvtap"),
+    not(feature = "ipvlan")
+))]
+compile_error!(
+    "At least one of the \"simple\", \"namespaced\", \"routed\", \"bridge\", \"macvtap\" or \"ipvlan\" networking feature flags must be enabled"
+);
 
 use std::net::IpAddr;
+#[cfg(feature = "namespaced")]
+use std::path::PathBuf;
 
 use cidr::IpInet;
 
+mod guest;
+pub use guest::FirecrackerGuestConfig;
+
 /// A configuration for a Firecracker microVM network.
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
@@ -14,19 +28,63 @@ pub struct FirecrackerNetwork {
     /// The optional explicit path to "nft" to use when invoking it.
     #[cfg_attr(feature = "serde", serde(default))]
     pub nft_path: Option<String>,
+    /// The names and hook priorities of the nftables table and chains to use, which allows separating the rules of
+    /// multiple fcnet consumers on one host and ordering them relative to other base chains.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub nft_config: FirecrackerNftConfig,
+    /// The backend that applies the NAT and forwarding rules of simple and namespaced networks. All other rules are
+    /// always applied via nftables, so routed and bridged networks reject the iptables backend.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub rule_backend: FirecrackerRuleBackend,
     /// The IP stack to use.
     #[cfg_attr(feature = "serde", serde(default))]
     pub ip_stack: FirecrackerIpStack,
     /// The name of the host network interface that handles real connectivity (i.e. via Ethernet or Wi-Fi).
-    pub iface_name: String,
+    /// If not set, it is resolved on every operation to the interface of the host's default route for the address
+    /// family of the guest IP.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub iface_name: Option<String>,
     /// The name of the tap device to direct Firecracker to use.
     pub tap_name: String,
     /// The IP of the tap device to direct Firecracker to use.
     pub tap_ip: IpInet,
     /// The IP of the guest.
     pub guest_ip: IpInet,
+    /// The optional MAC address of the guest's interface, which can be generated deterministically via
+    /// [guest_mac_from_ip] or [guest_mac_from_id]. If set, it's passed to Firecracker in the network interface config
+    /// and a permanent neighbour entry of the guest IP is added on the tap device of simple, namespaced and routed
+    /// networks, so that the guest's address is never resolved via ARP or NDP.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub guest_mac: Option<String>,
     /// The type of network to create, the available options depend on the feature flags enabled.
     pub network_type: FirecrackerNetworkType,
+    /// The optional dedicated IP owned by the host to use for 1:1 NAT of the guest: egress traffic is SNAT-ed to it
+    /// instead of being masqueraded behind the host interface's primary IP, and ingress traffic to it is DNAT-ed back
+    /// to the guest.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub nat_ip: Option<IpAddr>,
+    /// The ports of the guest to expose on the host interface.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub port_forwards: Vec<FirecrackerPortForward>,
+    /// The optional 802.1Q VLAN ID to tag egress traffic with. If set, the "<iface_name>.<vlan_id>" VLAN interface
+    /// is created on top of the host interface when missing, used in its place, and deleted once no network uses it.
+    /// Networks whose guest traffic is forwarded by the host also need a VLAN IP and a policy route, which routes the
+    /// traffic into the VLAN interface. Bridged networks don't support VLANs.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub vlan_id: Option<u16>,
+    /// The optional IP of the host on the VLAN, which is assigned to the VLAN interface when it's created and used as
+    /// the source of masqueraded traffic. All networks using the same VLAN interface must use the same VLAN IP.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub vlan_ip: Option<IpInet>,
+    /// The optional policy routing configuration to route the guest's egress traffic with, instead of the host's
+    /// main routing table. Is rejected with the iptables rule backend.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub policy_route: Option<FirecrackerPolicyRoute>,
+    /// Whether to offload the guest's established connections to the software fast path of a flowtable shared by
+    /// all networks, which bypasses the forward and NAT chains. Has no effect on macvtap and ipvlan networks,
+    /// and is rejected with the iptables rule backend.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub flowtable: bool,
 }
 
 /// The IP stack to use for networking.
@@ -42,6 +100,112 @@ pub enum FirecrackerIpStack {
     Dual,
 }
 
+/// A mapping of a port on the host interface to a port of the guest, resolved via DNAT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FirecrackerPortForward {
+    /// The optional IP of the host to restrict forwarding to. If not set, connections arriving to any IP
+    /// of the host interface are forwarded.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub host_ip: Option<IpAddr>,
+    /// The port on the host that connections are made to.
+    pub host_port: u16,
+    /// The transport protocol of the forwarded connections.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub protocol: FirecrackerPortProtocol,
+    /// The port on the guest that connections are forwarded to.
+    pub guest_port: u16,
+}
+
+/// The transport protocol of a [FirecrackerPortForward].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum FirecrackerPortProtocol {
+    /// TCP, translated to "tcp" payload matches in nftables.
+    #[default]
+    Tcp,
+    /// UDP, translated to "udp" payload matches in nftables.
+    Udp,
+}
+
+/// The names and hook priorities of the nftables objects that a [FirecrackerNetwork] places its rules into. The
+/// default uses the "fcnet" table, chains named after their hooks, and the standard priorities of their hook types.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct FirecrackerNftConfig {
+    /// The name of the table holding all chains.
+    pub table_name: String,
+    /// The name of the NAT chain on the postrouting hook, used for masquerading and SNAT.
+    pub postrouting_chain_name: String,
+    /// The priority of the postrouting chain.
+    pub postrouting_priority: i32,
+    /// The name of the NAT chain on the prerouting hook, used for DNAT.
+    pub prerouting_chain_name: String,
+    /// The priority of the prerouting chain.
+    pub prerouting_priority: i32,
+    /// The name of the filter chain on the prerouting hook, used for marking packets for policy routing.
+    pub mangle_chain_name: String,
+    /// The priority of the mangle chain.
+    pub mangle_priority: i32,
+    /// The name of the filter chain on the forward hook, used for accepting forwarded packets.
+    pub filter_chain_name: String,
+    /// The priority of the filter chain.
+    pub filter_priority: i32,
+}
+
+impl Default for FirecrackerNftConfig {
+    fn default() -> Self {
+        Self {
+            table_name: "fcnet".to_string(),
+            postrouting_chain_name: "postrouting".to_string(),
+            postrouting_priority: 100,
+            prerouting_chain_name: "prerouting".to_string(),
+            prerouting_priority: -100,
+            mangle_chain_name: "mangle".to_string(),
+            mangle_priority: -150,
+            filter_chain_name: "filter".to_string(),
+            filter_priority: 0,
+        }
+    }
+}
+
+/// The backend applying the NAT and forwarding rules of a [FirecrackerNetwork] to the host.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum FirecrackerRuleBackend {
+    /// nftables, invoked via its JSON API and placing rules into the table and chains of the [FirecrackerNftConfig].
+    #[default]
+    Nftables,
+    /// iptables, invoked once per rule and placing rules into dedicated "FCNET-POSTROUTING", "FCNET-PREROUTING" and
+    /// "FCNET-FORWARD" chains that are jumped to from the built-in chains. Suits hosts without nftables' JSON API,
+    /// where either iptables-nft or iptables-legacy may be installed.
+    Iptables {
+        /// The optional explicit path to "iptables" to use when invoking it for IPv4 rules.
+        #[cfg_attr(feature = "serde", serde(default))]
+        iptables_path: Option<String>,
+        /// The optional explicit path to "ip6tables" to use when invoking it for IPv6 rules.
+        #[cfg_attr(feature = "serde", serde(default))]
+        ip6tables_path: Option<String>,
+    },
+}
+
+/// A policy routing configuration that sends the egress traffic of a guest out of the host interface of its
+/// [FirecrackerNetwork] regardless of the host's main routing table, by marking it in nftables and looking it up in
+/// a dedicated routing table via a routing policy rule. Doesn't apply to macvtap and ipvlan networks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FirecrackerPolicyRoute {
+    /// The firewall mark set on the guest's egress packets and matched by the routing policy rule.
+    pub fwmark: u32,
+    /// The ID of the dedicated routing table holding the default route out of the host interface.
+    pub table_id: u32,
+    /// The optional gateway of the default route. If not set, the route points directly to the host interface,
+    /// which suits point-to-point tunnels.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub gateway: Option<IpAddr>,
+}
+
 /// The type of Firecracker network to work with.
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
@@ -65,7 +229,69 @@ pub enum FirecrackerNetworkType {
         veth2_ip: IpInet,
         #[cfg_attr(feature = "serde", serde(default))]
         forwarded_guest_ip: Option<IpAddr>,
+        /// The directory the netns is persisted in, for example inside a jailer's chroot. Defaults to "/var/run/netns".
+        #[cfg_attr(feature = "serde", serde(default))]
+        netns_dir: Option<String>,
+        /// Whether to attach to an existing netns that is neither created nor deleted by fcnet, for example a container
+        /// runtime's with "/proc/<pid>/ns" as the directory and "net" as the name. Deleting the network then deletes
+        /// the tap device, veth pair and rules inside the netns instead of the netns itself. No default route via veth1
+        /// is added inside an attached netns, so its existing routing is kept and decides whether the guest's traffic
+        /// leaves via the veth pair, for which a route via veth1 has to be added separately.
+        #[cfg_attr(feature = "serde", serde(default))]
+        attach_netns: bool,
+    },
+    /// A routed network configuration without any NAT, with a tap device and a host route to the guest IP, which
+    /// is expected to be routable, bound to the host interface via 1 set of forwarding rules.
+    /// The choice for deployments that assign routable addresses to guests. NAT IPs and port forwards are rejected.
+    /// There's no namespaced variant: the tap device and the route always live in the host's netns.
+    #[cfg(feature = "routed")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "routed")))]
+    Routed {
+        /// Whether to make the guest reachable from the L2 segment of the host interface by enabling proxy ARP
+        /// (IPv4) or adding a proxy NDP entry (IPv6) on it.
+        #[cfg_attr(feature = "serde", serde(default))]
+        proxy_neighbour: bool,
+    },
+    /// A bridged network configuration, with the tap device attached as a port to a Linux bridge that holds the tap
+    /// IP as the gateway of all microVMs attached to it, masqueraded and forwarded to any other interface via 1 set of
+    /// rules per bridge. The choice for several microVMs sharing one L2 segment. NAT IPs and port forwards are rejected.
+    #[cfg(feature = "bridge")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "bridge")))]
+    Bridge {
+        /// The name of the bridge, which is created if it doesn't exist and deleted once its last port is deleted. All
+        /// networks on the same bridge must use the same tap IP, which the bridge was created with.
+        bridge_name: String,
     },
+    /// A macvtap network configuration without any NAT, with a macvtap device named after the tap device stacked on
+    /// the host interface, placing the guest directly on the L2 segment of the host interface. The VMM is pointed
+    /// to the "/dev/tapN" character device of the macvtap. Tap IPs, NAT IPs and port forwards don't apply.
+    #[cfg(feature = "macvtap")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "macvtap")))]
+    Macvtap {
+        /// The mode of the macvtap device.
+        #[cfg_attr(feature = "serde", serde(default))]
+        mode: FirecrackerMacvtapMode,
+    },
+    /// An ipvlan network configuration without any NAT, with an L3 ipvtap interface named after the tap device
+    /// stacked on the host interface and registering the guest IP, sharing the MAC address of the host interface.
+    /// The VMM is pointed to the ipvtap's "/dev/tapN" character device, so the guest's traffic never reaches the
+    /// host's network stack. Tap IPs, NAT IPs and port forwards don't apply.
+    #[cfg(feature = "ipvlan")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "ipvlan")))]
+    Ipvlan,
+}
+
+/// The mode of the macvtap device of a [FirecrackerNetworkType::Macvtap] network.
+#[cfg(feature = "macvtap")]
+#[cfg_attr(docsrs, doc(cfg(feature = "macvtap")))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum FirecrackerMacvtapMode {
+    /// Bridge mode, allowing several macvtap devices on the same host interface to reach each other directly.
+    #[default]
+    Bridge,
+    /// Passthru mode, giving the only macvtap device on the host interface full control over it.
+    Passthru,
 }
 
 impl FirecrackerNetwork {
@@ -74,12 +300,120 @@ impl FirecrackerNetwork {
     pub fn guest_ip_boot_arg(&self, guest_iface_name: impl AsRef<str>) -> String {
         format!(
             "ip={}::{}:{}::{}:off",
-            self.guest_ip.address().to_string(),
-            self.tap_ip.address().to_string(),
-            self.guest_ip.mask().to_string(),
+            self.guest_ip.address(),
+            self.tap_ip.address(),
+            self.guest_ip.mask(),
             guest_iface_name.as_ref()
         )
     }
+
+    /// Produce the network interface config to pass to Firecracker for this network, with the tap device as the host
+    /// device and no rate limiters, which can be set on the returned config. The VMM can't open the "/dev/tapN"
+    /// character device of a macvtap or ipvlan network by name, so for these network types, callers must replace the
+    /// host device with the one resolved via "fcnet::get_vmm_device" once the network was added.
+    pub fn firecracker_interface_config(&self, iface_id: impl Into<String>) -> FirecrackerInterfaceConfig {
+        FirecrackerInterfaceConfig {
+            iface_id: iface_id.into(),
+            host_dev_name: self.tap_name.clone(),
+            guest_mac: self.guest_mac.clone(),
+            rx_rate_limiter: None,
+            tx_rate_limiter: None,
+        }
+    }
+
+    /// Get the path of the netns of a namespaced network, which is the value of the jailer's "--netns" argument, or
+    /// [None] for all other network types, which don't need the VMM to be launched in a netns.
+    #[cfg(feature = "namespaced")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "namespaced")))]
+    pub fn netns_path(&self) -> Option<PathBuf> {
+        match self.network_type {
+            FirecrackerNetworkType::Namespaced {
+                ref netns_name,
+                ref netns_dir,
+                ..
+            } => Some(PathBuf::from(netns_dir.as_deref().unwrap_or("/var/run/netns")).join(netns_name)),
+            #[allow(unreachable_patterns)]
+            _ => None,
+        }
+    }
+}
+
+/// Generate a locally administered unicast MAC address from the IP of a guest, with the "06:00" prefix followed by
+/// the 4 bytes of an IPv4 address or the last 4 bytes of an IPv6 address (e.g. "06:00:ac:10:00:02" for 172.16.0.2).
+/// Guests with distinct IPv4 addresses thus always get distinct MAC addresses.
+pub fn guest_mac_from_ip(guest_ip: IpAddr) -> String {
+    let suffix = match guest_ip {
+        IpAddr::V4(v4) => v4.octets(),
+        IpAddr::V6(v6) => {
+            let octets = v6.octets();
+            [octets[12], octets[13], octets[14], octets[15]]
+        }
+    };
+
+    format_mac([0x06, 0x00, suffix[0], suffix[1], suffix[2], suffix[3]])
+}
+
+/// Generate a locally administered unicast MAC address from an arbitrary network ID (e.g. a microVM's UUID), with
+/// the "02" prefix followed by 5 bytes of the ID's FNV-1a hash, which is stable across builds and platforms.
+pub fn guest_mac_from_id(id: impl AsRef<[u8]>) -> String {
+    let hash = id
+        .as_ref()
+        .iter()
+        .fold(0xcbf29ce484222325u64, |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+        })
+        .to_be_bytes();
+
+    format_mac([0x02, hash[0], hash[1], hash[2], hash[3], hash[4]])
+}
+
+fn format_mac(octets: [u8; 6]) -> String {
+    octets.map(|octet| format!("{octet:02x}")).join(":")
+}
+
+/// A network interface config of Firecracker, which is the body of its "PUT /network-interfaces/{iface_id}" API
+/// request and an element of the "network-interfaces" list of its JSON config file.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FirecrackerInterfaceConfig {
+    /// The ID of the interface inside Firecracker.
+    pub iface_id: String,
+    /// The name of the tap device on the host.
+    pub host_dev_name: String,
+    /// The MAC address of the interface inside the guest, which Firecracker picks itself if not set.
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub guest_mac: Option<String>,
+    /// The rate limiter of traffic received by the guest.
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub rx_rate_limiter: Option<FirecrackerRateLimiter>,
+    /// The rate limiter of traffic transmitted by the guest.
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub tx_rate_limiter: Option<FirecrackerRateLimiter>,
+}
+
+/// A rate limiter of a [FirecrackerInterfaceConfig], limiting bandwidth and operations independently.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FirecrackerRateLimiter {
+    /// The token bucket limiting bytes.
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub bandwidth: Option<FirecrackerTokenBucket>,
+    /// The token bucket limiting packets.
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub ops: Option<FirecrackerTokenBucket>,
+}
+
+/// A token bucket of a [FirecrackerRateLimiter].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FirecrackerTokenBucket {
+    /// The total number of tokens the bucket can hold.
+    pub size: u64,
+    /// The initial number of tokens that are available once on top of the size.
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub one_time_burst: Option<u64>,
+    /// The number of milliseconds it takes to refill the bucket from empty to full.
+    pub refill_time: u64,
 }
 
 /// An operation that can be made with a FirecrackerNetwork.
@@ -92,4 +426,85 @@ pub enum FirecrackerNetworkOperation {
     Check,
     /// Delete this network from the host.
     Delete,
+    /// Flush the conntrack entries of this network's guest on the host, without otherwise changing the network.
+    Flush,
+}
+
+/// The identifiers of the objects created by adding a [FirecrackerNetwork], which allow an implementation to target
+/// exactly these objects when checking or deleting the network later on.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FirecrackerNetworkHandle {
+    /// The link index of the tap device (or of the macvtap or ipvtap interface), inside the netns for namespaced
+    /// networks.
+    pub tap_index: u32,
+    /// The MAC address of the tap device (or of the macvtap or ipvtap interface), if it has one.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub tap_mac: Option<String>,
+    /// The inode of the netns of a namespaced network, which changes when the netns is recreated under the same name.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub netns_inode: Option<u64>,
+    /// The handles of the network's nftables rules in the host's netns, excluding rules applied via iptables and
+    /// rules inside the netns of a namespaced network.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub nft_rules: Vec<FirecrackerNftRuleHandle>,
+}
+
+/// The handle of an nftables rule within a chain of the configured table, as part of a [FirecrackerNetworkHandle].
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FirecrackerNftRuleHandle {
+    /// The name of the chain containing the rule.
+    pub chain: String,
+    /// The handle assigned to the rule by nftables.
+    pub handle: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    use super::{guest_mac_from_id, guest_mac_from_ip};
+
+    fn first_octet(mac: &str) -> u8 {
+        u8::from_str_radix(&mac[..2], 16).unwrap()
+    }
+
+    #[test]
+    fn guest_mac_from_ipv4_embeds_the_address() {
+        assert_eq!(
+            guest_mac_from_ip(IpAddr::V4(Ipv4Addr::new(172, 16, 0, 2))),
+            "06:00:ac:10:00:02"
+        );
+    }
+
+    #[test]
+    fn guest_mac_from_ipv6_embeds_the_last_four_bytes() {
+        assert_eq!(
+            guest_mac_from_ip(IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0xc0a8, 0x0102))),
+            "06:00:c0:a8:01:02"
+        );
+    }
+
+    #[test]
+    fn guest_mac_from_id_is_a_stable_fnv1a_hash() {
+        // the 64-bit FNV-1a hashes of "" and "abc" are 0xcbf29ce484222325 and 0xe71fa2190541574b
+        assert_eq!(guest_mac_from_id(""), "02:cb:f2:9c:e4:84");
+        assert_eq!(guest_mac_from_id("abc"), "02:e7:1f:a2:19:05");
+        assert_eq!(guest_mac_from_id(b"abc"), guest_mac_from_id(String::from("abc")));
+        assert_ne!(guest_mac_from_id("abc"), guest_mac_from_id("abd"));
+    }
+
+    #[test]
+    fn generated_guest_macs_are_locally_administered_unicast() {
+        for mac in [
+            guest_mac_from_ip(IpAddr::V4(Ipv4Addr::new(255, 255, 255, 255))),
+            guest_mac_from_ip(IpAddr::V6(Ipv6Addr::LOCALHOST)),
+            guest_mac_from_id("abc"),
+            guest_mac_from_id("f5d8a4c2-3b1e-4d6f-9a7c-0e2b8d4f6a1c"),
+        ] {
+            assert_eq!(first_octet(&mac) & 0b01, 0, "{mac} is multicast");
+            assert_eq!(first_octet(&mac) & 0b10, 0b10, "{mac} isn't locally administered");
+        }
+    }
 }
@@ -0,0 +1,226 @@
+use fcnet_types::FirecrackerNetwork;
+use futures_util::TryStreamExt;
+use nftables::{
+    expr::{Expression, Meta, MetaKey, NamedExpression},
+    stmt::{Match, Operator, Statement},
+};
+use rtnetlink::{packet_route::link::LinkAttribute, LinkBridge, LinkMessageBuilder, LinkUnspec};
+use tokio_tun::TunBuilder;
+
+use crate::{
+    backend::Backend,
+    conntrack,
+    rules::{ensure_nftables, NetworkRule, NftablesRuleBackend, RuleBackend, RuleChain},
+    util::{check_link_address, ensure_no_nat, find_link_index, get_link_index, FirecrackerNetworkExt},
+    FirecrackerNetworkError, FirecrackerNetworkObjectType, FirecrackerNetworkOperation,
+};
+
+pub async fn run<B: Backend>(
+    network: &FirecrackerNetwork,
+    netlink_handle: rtnetlink::Handle,
+    operation: FirecrackerNetworkOperation,
+    bridge_name: &str,
+) -> Result<(), FirecrackerNetworkError> {
+    ensure_nftables(&network.rule_backend, "bridged networks")?;
+    ensure_no_nat(network, "Bridged networks")?;
+
+    match operation {
+        FirecrackerNetworkOperation::Add => add::<B>(network, netlink_handle, bridge_name).await,
+        FirecrackerNetworkOperation::Check => check::<B>(network, netlink_handle, bridge_name).await,
+        FirecrackerNetworkOperation::Delete => delete::<B>(network, netlink_handle, bridge_name).await,
+        FirecrackerNetworkOperation::Flush => conntrack::flush::<B>(&[network.guest_ip.address()]).await,
+    }
+}
+
+async fn add<B: Backend>(
+    network: &FirecrackerNetwork,
+    netlink_handle: rtnetlink::Handle,
+    bridge_name: &str,
+) -> Result<(), FirecrackerNetworkError> {
+    // the gateway address lives on the bridge, so it's only assigned when the bridge is first created and all later
+    // ports must use the same one, since their guests otherwise have no gateway
+    let bridge_idx = match find_link_index(bridge_name, &netlink_handle).await? {
+        Some(bridge_idx) => {
+            check_link_address(bridge_idx, &network.tap_ip, &netlink_handle).await?;
+            bridge_idx
+        }
+        None => {
+            netlink_handle
+                .link()
+                .add(LinkMessageBuilder::<LinkBridge>::new(bridge_name).up().build())
+                .execute()
+                .await
+                .map_err(FirecrackerNetworkError::NetlinkOperationError)?;
+            let bridge_idx = get_link_index(bridge_name.to_string(), &netlink_handle).await?;
+            netlink_handle
+                .address()
+                .add(bridge_idx, network.tap_ip.address(), network.tap_ip.network_length())
+                .execute()
+                .await
+                .map_err(FirecrackerNetworkError::NetlinkOperationError)?;
+            bridge_idx
+        }
+    };
+
+    TunBuilder::new()
+        .name(&network.tap_name)
+        .tap()
+        .persist()
+        .up()
+        .build()
+        .map_err(FirecrackerNetworkError::TapDeviceError)?;
+    let tap_idx = get_link_index(network.tap_name.clone(), &netlink_handle).await?;
+    netlink_handle
+        .link()
+        .set(
+            LinkMessageBuilder::<LinkUnspec>::new()
+                .index(tap_idx)
+                .controller(bridge_idx)
+                .build(),
+        )
+        .execute()
+        .await
+        .map_err(FirecrackerNetworkError::NetlinkOperationError)?;
+
+    // the rules are shared by all ports of the bridge, so they're only added by the first one
+    NftablesRuleBackend::add_rules::<B>(&network.rule_context(), &network_rules(bridge_name)).await
+}
+
+async fn delete<B: Backend>(
+    network: &FirecrackerNetwork,
+    netlink_handle: rtnetlink::Handle,
+    bridge_name: &str,
+) -> Result<(), FirecrackerNetworkError> {
+    let tap_idx = get_link_index(network.tap_name.clone(), &netlink_handle).await?;
+    netlink_handle
+        .link()
+        .del(tap_idx)
+        .execute()
+        .await
+        .map_err(FirecrackerNetworkError::NetlinkOperationError)?;
+
+    // the bridge and its rules are kept for as long as other microVMs are still attached to it
+    let bridge_idx = get_link_index(bridge_name.to_string(), &netlink_handle).await?;
+    if get_bridge_port_count(bridge_idx, &netlink_handle).await? > 0 {
+        return Ok(());
+    }
+
+    netlink_handle
+        .link()
+        .del(bridge_idx)
+        .execute()
+        .await
+        .map_err(FirecrackerNetworkError::NetlinkOperationError)?;
+
+    NftablesRuleBackend::delete_rules::<B>(&network.rule_context(), &network_rules(bridge_name)).await
+}
+
+async fn check<B: Backend>(
+    network: &FirecrackerNetwork,
+    netlink_handle: rtnetlink::Handle,
+    bridge_name: &str,
+) -> Result<(), FirecrackerNetworkError> {
+    let bridge_idx = get_link_index(bridge_name.to_string(), &netlink_handle).await?;
+    check_link_address(bridge_idx, &network.tap_ip, &netlink_handle).await?;
+    let tap_idx = get_link_index(network.tap_name.clone(), &netlink_handle).await?;
+
+    let tap_controller_idx = netlink_handle
+        .link()
+        .get()
+        .match_index(tap_idx)
+        .execute()
+        .try_next()
+        .await
+        .map_err(FirecrackerNetworkError::NetlinkOperationError)?
+        .and_then(|link_message| {
+            link_message.attributes.into_iter().find_map(|attribute| match attribute {
+                LinkAttribute::Controller(controller_idx) => Some(controller_idx),
+                _ => None,
+            })
+        });
+
+    if tap_controller_idx != Some(bridge_idx) {
+        return Err(FirecrackerNetworkError::ObjectNotFound(
+            FirecrackerNetworkObjectType::BridgePort,
+        ));
+    }
+
+    NftablesRuleBackend::check_rules::<B>(&network.rule_context(), &network_rules(bridge_name)).await
+}
+
+/// Whether deleting the network deletes the rules shared by all ports of the bridge, which is only the case for the
+/// last remaining port.
+pub async fn deletes_rules(
+    network: &FirecrackerNetwork,
+    netlink_handle: &rtnetlink::Handle,
+    bridge_name: &str,
+) -> Result<bool, FirecrackerNetworkError> {
+    get_link_index(network.tap_name.clone(), netlink_handle).await?;
+    let bridge_idx = get_link_index(bridge_name.to_string(), netlink_handle).await?;
+    Ok(get_bridge_port_count(bridge_idx, netlink_handle).await? <= 1)
+}
+
+/// The rules of a bridge network, which are shared by all ports of the bridge and thus only derived from the bridge
+/// itself, matching all traffic that leaves it regardless of the host interface that the individual ports configure.
+pub fn network_rules(bridge_name: &str) -> Vec<NetworkRule> {
+    vec![
+        NetworkRule {
+            chain: RuleChain::Postrouting,
+            expr: masq_expr(bridge_name),
+            object_type: FirecrackerNetworkObjectType::NfMasqueradeRule,
+        },
+        NetworkRule {
+            chain: RuleChain::Filter,
+            expr: forward_expr(bridge_name),
+            object_type: FirecrackerNetworkObjectType::NfEgressForwardRule,
+        },
+    ]
+}
+
+async fn get_bridge_port_count(bridge_idx: u32, netlink_handle: &rtnetlink::Handle) -> Result<usize, FirecrackerNetworkError> {
+    let mut link_message_stream = netlink_handle.link().get().execute();
+    let mut port_count = 0;
+
+    while let Some(link_message) = link_message_stream
+        .try_next()
+        .await
+        .map_err(FirecrackerNetworkError::NetlinkOperationError)?
+    {
+        if link_message
+            .attributes
+            .iter()
+            .any(|attribute| matches!(attribute, LinkAttribute::Controller(controller_idx) if *controller_idx == bridge_idx))
+        {
+            port_count += 1;
+        }
+    }
+
+    Ok(port_count)
+}
+
+#[inline]
+fn masq_expr(bridge_name: &str) -> Vec<Statement<'static>> {
+    vec![
+        leaves_bridge_match(bridge_name, MetaKey::Iifname, Operator::EQ),
+        leaves_bridge_match(bridge_name, MetaKey::Oifname, Operator::NEQ),
+        Statement::Masquerade(None),
+    ]
+}
+
+#[inline]
+fn forward_expr(bridge_name: &str) -> Vec<Statement<'static>> {
+    vec![
+        leaves_bridge_match(bridge_name, MetaKey::Iifname, Operator::EQ),
+        leaves_bridge_match(bridge_name, MetaKey::Oifname, Operator::NEQ),
+        Statement::Accept(None),
+    ]
+}
+
+#[inline]
+fn leaves_bridge_match(bridge_name: &str, key: MetaKey, op: Operator) -> Statement<'static> {
+    Statement::Match(Match {
+        left: Expression::Named(NamedExpression::Meta(Meta { key })),
+        right: Expression::String(bridge_name.to_string().into()),
+        op,
+    })
+}
@@ -0,0 +1,326 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use rtnetlink::{
+    packet_core::{NetlinkBuffer, NLM_F_ACK, NLM_F_DUMP, NLM_F_REQUEST},
+    packet_utils::nla::{NlasIterator, NLA_F_NESTED},
+    sys::{constants::NETLINK_NETFILTER, AsyncSocket, AsyncSocketExt},
+};
+
+use crate::{backend::Backend, FirecrackerNetworkError};
+
+const NLMSG_HDRLEN: usize = 16;
+const NFGENMSG_LEN: usize = 4;
+const NLA_HDRLEN: usize = 4;
+const NETLINK_ALIGNTO: usize = 4;
+const NLMSG_ERROR: u16 = 2;
+const NLMSG_DONE: u16 = 3;
+const NFNL_SUBSYS_CTNETLINK: u16 = 1;
+const IPCTNL_MSG_CT_GET: u16 = 1;
+const IPCTNL_MSG_CT_DELETE: u16 = 2;
+const NFNETLINK_V0: u8 = 0;
+const AF_UNSPEC: u8 = 0;
+const CTA_TUPLE_ORIG: u16 = 1;
+const CTA_TUPLE_REPLY: u16 = 2;
+const CTA_ZONE: u16 = 18;
+const CTA_TUPLE_IP: u16 = 1;
+const CTA_IP_V4_SRC: u16 = 1;
+const CTA_IP_V4_DST: u16 = 2;
+const CTA_IP_V6_SRC: u16 = 3;
+const CTA_IP_V6_DST: u16 = 4;
+const ENOENT: i32 = 2;
+const EOPNOTSUPP: i32 = 95;
+
+/// Log a failed flush made as part of deleting a network instead of failing the delete, since the network's objects are
+/// deleted regardless and a retried delete would fail on them being gone, while stale entries expire on their own.
+pub fn log_failed_delete_flush(result: Result<(), FirecrackerNetworkError>) {
+    if let Err(err) = result {
+        tracing::warn!(%err, "Flushing the conntrack entries of the deleted network failed");
+    }
+}
+
+/// A conntrack entry that is to be deleted, identified by its family, original tuple and zone as they were dumped.
+struct ConntrackEntry {
+    family: u8,
+    orig_tuple: Vec<u8>,
+    zone: Option<Vec<u8>>,
+}
+
+/// Delete all conntrack entries of the current netns whose original or reply tuple has one of the given addresses
+/// as its source or destination, so that stale NAT mappings don't outlive the network they were created for.
+/// The entries are dumped and deleted one by one over ctnetlink, since filtered flushes aren't supported by all
+/// kernels, while entries that expire in between are skipped.
+pub async fn flush<B: Backend>(addresses: &[IpAddr]) -> Result<(), FirecrackerNetworkError> {
+    let mut socket = B::NetlinkSocket::new(NETLINK_NETFILTER).map_err(FirecrackerNetworkError::ConntrackError)?;
+    socket
+        .socket_mut()
+        .bind_auto()
+        .map_err(FirecrackerNetworkError::ConntrackError)?;
+
+    let mut entries = Vec::new();
+    let request = encode_message(IPCTNL_MSG_CT_GET, NLM_F_REQUEST | NLM_F_DUMP, 1, AF_UNSPEC, &[]);
+    socket.send(&request).await.map_err(FirecrackerNetworkError::ConntrackError)?;
+
+    let result = receive_messages(&mut socket, |payload| {
+        if let Some(entry) = decode_entry(payload, addresses)? {
+            entries.push(entry);
+        }
+
+        Ok(())
+    })
+    .await;
+
+    match result {
+        Ok(()) => {}
+        // nothing can be tracked if conntrack isn't loaded in the kernel
+        Err(err) if err.raw_os_error() == Some(EOPNOTSUPP) => return Ok(()),
+        Err(err) => return Err(FirecrackerNetworkError::ConntrackError(err)),
+    }
+
+    for (idx, entry) in entries.into_iter().enumerate() {
+        let mut attributes = entry.orig_tuple;
+        if let Some(zone) = entry.zone {
+            attributes.extend(zone);
+        }
+
+        let request = encode_message(
+            IPCTNL_MSG_CT_DELETE,
+            NLM_F_REQUEST | NLM_F_ACK,
+            idx as u32 + 2,
+            entry.family,
+            &attributes,
+        );
+        socket.send(&request).await.map_err(FirecrackerNetworkError::ConntrackError)?;
+
+        match receive_messages(&mut socket, |_| Ok(())).await {
+            Ok(()) => {}
+            Err(err) if err.raw_os_error() == Some(ENOENT) => {}
+            Err(err) => return Err(FirecrackerNetworkError::ConntrackError(err)),
+        }
+    }
+
+    Ok(())
+}
+
+fn encode_message(message_type: u16, flags: u16, sequence_number: u32, family: u8, attributes: &[u8]) -> Vec<u8> {
+    let length = NLMSG_HDRLEN + NFGENMSG_LEN + attributes.len();
+    let mut buffer = Vec::with_capacity(length);
+    buffer.extend_from_slice(&(length as u32).to_ne_bytes());
+    // the subsystem occupies the upper byte of an nfnetlink message type
+    buffer.extend_from_slice(&((NFNL_SUBSYS_CTNETLINK << 8) | message_type).to_ne_bytes());
+    buffer.extend_from_slice(&flags.to_ne_bytes());
+    buffer.extend_from_slice(&sequence_number.to_ne_bytes());
+    buffer.extend_from_slice(&0u32.to_ne_bytes());
+    buffer.extend_from_slice(&[family, NFNETLINK_V0, 0, 0]);
+    buffer.extend_from_slice(attributes);
+    buffer
+}
+
+fn encode_attribute(kind: u16, value: &[u8]) -> Vec<u8> {
+    let length = NLA_HDRLEN + value.len();
+    let mut buffer = Vec::with_capacity(length.next_multiple_of(NETLINK_ALIGNTO));
+    buffer.extend_from_slice(&(length as u16).to_ne_bytes());
+    buffer.extend_from_slice(&kind.to_ne_bytes());
+    buffer.extend_from_slice(value);
+    buffer.resize(buffer.capacity(), 0);
+    buffer
+}
+
+/// Receive messages until the reply to the last request ends, passing the payload of every non-control message to
+/// the given closure and surfacing a negative error code as an [io::Error].
+async fn receive_messages<S: AsyncSocket>(
+    socket: &mut S,
+    mut on_payload: impl FnMut(&[u8]) -> Result<(), io::Error>,
+) -> Result<(), io::Error> {
+    loop {
+        let (datagram, _) = socket.recv_from_full().await?;
+        let mut offset = 0;
+
+        while offset < datagram.len() {
+            let message = NetlinkBuffer::new_checked(&datagram[offset..]).map_err(invalid_data)?;
+            offset += (message.length() as usize).next_multiple_of(NETLINK_ALIGNTO);
+
+            match message.message_type() {
+                NLMSG_DONE => return Ok(()),
+                NLMSG_ERROR => {
+                    let code = message
+                        .payload()
+                        .get(..4)
+                        .map(|code| i32::from_ne_bytes(code.try_into().expect("Slice has a length of 4")))
+                        .ok_or_else(|| invalid_data("Truncated netlink error message"))?;
+
+                    return match code {
+                        0 => Ok(()),
+                        code => Err(io::Error::from_raw_os_error(-code)),
+                    };
+                }
+                _ => on_payload(message.payload())?,
+            }
+        }
+    }
+}
+
+fn decode_entry(payload: &[u8], addresses: &[IpAddr]) -> Result<Option<ConntrackEntry>, io::Error> {
+    let family = *payload.first().ok_or_else(|| invalid_data("Truncated nfnetlink message"))?;
+    let mut orig_tuple = None;
+    let mut zone = None;
+    let mut matches = false;
+
+    for attribute in NlasIterator::new(payload.get(NFGENMSG_LEN..).unwrap_or_default()) {
+        let attribute = attribute.map_err(invalid_data)?;
+
+        match attribute.kind() {
+            CTA_TUPLE_ORIG => {
+                matches |= tuple_matches(attribute.value(), addresses)?;
+                orig_tuple = Some(encode_attribute(CTA_TUPLE_ORIG | NLA_F_NESTED, attribute.value()));
+            }
+            CTA_TUPLE_REPLY => matches |= tuple_matches(attribute.value(), addresses)?,
+            CTA_ZONE => zone = Some(encode_attribute(CTA_ZONE, attribute.value())),
+            _ => continue,
+        }
+    }
+
+    Ok(match (matches, orig_tuple) {
+        (true, Some(orig_tuple)) => Some(ConntrackEntry {
+            family,
+            orig_tuple,
+            zone,
+        }),
+        _ => None,
+    })
+}
+
+fn tuple_matches(tuple: &[u8], addresses: &[IpAddr]) -> Result<bool, io::Error> {
+    for attribute in NlasIterator::new(tuple) {
+        let attribute = attribute.map_err(invalid_data)?;
+        if attribute.kind() != CTA_TUPLE_IP {
+            continue;
+        }
+
+        for attribute in NlasIterator::new(attribute.value()) {
+            let attribute = attribute.map_err(invalid_data)?;
+            let address = match (attribute.kind(), attribute.value()) {
+                (CTA_IP_V4_SRC | CTA_IP_V4_DST, &[a, b, c, d]) => IpAddr::V4(Ipv4Addr::new(a, b, c, d)),
+                (CTA_IP_V6_SRC | CTA_IP_V6_DST, value) if value.len() == 16 => {
+                    IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(value).expect("Slice has a length of 16")))
+                }
+                _ => continue,
+            };
+
+            if addresses.contains(&address) {
+                return Ok(true);
+            }
+        }
+    }
+
+    Ok(false)
+}
+
+#[inline]
+fn invalid_data(err: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    use rtnetlink::{
+        packet_core::{NetlinkBuffer, NLM_F_DUMP, NLM_F_REQUEST},
+        packet_utils::nla::{NlasIterator, NLA_F_NESTED},
+    };
+
+    use super::{
+        decode_entry, encode_attribute, encode_message, CTA_IP_V4_DST, CTA_IP_V4_SRC, CTA_IP_V6_SRC, CTA_TUPLE_IP,
+        CTA_TUPLE_ORIG, CTA_TUPLE_REPLY, CTA_ZONE, IPCTNL_MSG_CT_DELETE, NFGENMSG_LEN, NFNL_SUBSYS_CTNETLINK, NLMSG_HDRLEN,
+    };
+
+    const AF_INET: u8 = 2;
+    const AF_INET6: u8 = 10;
+
+    fn tuple(attributes: &[(u16, &[u8])]) -> Vec<u8> {
+        let ip = attributes
+            .iter()
+            .flat_map(|(kind, value)| encode_attribute(*kind, value))
+            .collect::<Vec<_>>();
+        encode_attribute(CTA_TUPLE_IP | NLA_F_NESTED, &ip)
+    }
+
+    fn entry_payload(family: u8, orig: &[u8], reply: &[u8], zone: Option<u16>) -> Vec<u8> {
+        let mut payload = vec![family, 0, 0, 0];
+        payload.extend(encode_attribute(CTA_TUPLE_ORIG | NLA_F_NESTED, orig));
+        payload.extend(encode_attribute(CTA_TUPLE_REPLY | NLA_F_NESTED, reply));
+        if let Some(zone) = zone {
+            payload.extend(encode_attribute(CTA_ZONE, &zone.to_be_bytes()));
+        }
+        payload
+    }
+
+    #[test]
+    fn attributes_are_padded_to_4_bytes() {
+        let attribute = encode_attribute(CTA_ZONE, &[1, 2, 3, 4, 5]);
+
+        assert_eq!(attribute.len(), 12);
+        assert_eq!(u16::from_ne_bytes([attribute[0], attribute[1]]), 9);
+        assert_eq!(u16::from_ne_bytes([attribute[2], attribute[3]]), CTA_ZONE);
+        assert_eq!(&attribute[4..], &[1, 2, 3, 4, 5, 0, 0, 0]);
+    }
+
+    #[test]
+    fn messages_round_trip_through_netlink_buffer() {
+        let attributes = encode_attribute(CTA_ZONE, &7u16.to_be_bytes());
+        let message = encode_message(IPCTNL_MSG_CT_DELETE, NLM_F_REQUEST | NLM_F_DUMP, 42, AF_INET, &attributes);
+        let buffer = NetlinkBuffer::new_checked(&message).unwrap();
+
+        assert_eq!(buffer.length() as usize, NLMSG_HDRLEN + NFGENMSG_LEN + attributes.len());
+        assert_eq!(buffer.message_type(), (NFNL_SUBSYS_CTNETLINK << 8) | IPCTNL_MSG_CT_DELETE);
+        assert_eq!(buffer.flags(), NLM_F_REQUEST | NLM_F_DUMP);
+        assert_eq!(buffer.sequence_number(), 42);
+        assert_eq!(buffer.port_number(), 0);
+        assert_eq!(&buffer.payload()[..NFGENMSG_LEN], &[AF_INET, 0, 0, 0]);
+
+        let decoded = NlasIterator::new(&buffer.payload()[NFGENMSG_LEN..])
+            .map(|attribute| attribute.unwrap())
+            .map(|attribute| (attribute.kind(), attribute.value().to_vec()))
+            .collect::<Vec<_>>();
+        assert_eq!(decoded, [(CTA_ZONE, 7u16.to_be_bytes().to_vec())]);
+    }
+
+    #[test]
+    fn matching_entries_are_decoded_for_deletion() {
+        let orig = tuple(&[(CTA_IP_V4_SRC, &[172, 16, 0, 2]), (CTA_IP_V4_DST, &[1, 1, 1, 1])]);
+        let reply = tuple(&[(CTA_IP_V4_SRC, &[1, 1, 1, 1]), (CTA_IP_V4_DST, &[10, 0, 0, 5])]);
+        let payload = entry_payload(AF_INET, &orig, &reply, Some(3));
+
+        let entry = decode_entry(&payload, &[IpAddr::V4(Ipv4Addr::new(172, 16, 0, 2))])
+            .unwrap()
+            .unwrap();
+        assert_eq!(entry.family, AF_INET);
+        assert_eq!(entry.orig_tuple, encode_attribute(CTA_TUPLE_ORIG | NLA_F_NESTED, &orig));
+        assert_eq!(entry.zone, Some(encode_attribute(CTA_ZONE, &3u16.to_be_bytes())));
+
+        // an address only found in the reply tuple matches as well, e.g. the NAT IP of an SNAT mapping
+        let entry = decode_entry(&payload, &[IpAddr::V4(Ipv4Addr::new(10, 0, 0, 5))]).unwrap();
+        assert!(entry.is_some_and(|entry| entry.zone.is_some()));
+    }
+
+    #[test]
+    fn other_entries_are_skipped() {
+        let guest_ip = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2);
+        let other_ip = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 3);
+        let orig = tuple(&[(CTA_IP_V6_SRC, &other_ip.octets())]);
+        let payload = entry_payload(AF_INET6, &orig, &orig, None);
+
+        assert!(decode_entry(&payload, &[IpAddr::V6(guest_ip)]).unwrap().is_none());
+        assert!(decode_entry(&payload, &[IpAddr::V6(other_ip)])
+            .unwrap()
+            .is_some_and(|entry| entry.family == AF_INET6 && entry.zone.is_none()));
+    }
+
+    #[test]
+    fn truncated_messages_are_rejected() {
+        assert!(decode_entry(&[], &[]).is_err());
+    }
+}
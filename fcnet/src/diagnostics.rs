@@ -0,0 +1,240 @@
+use std::ffi::OsStr;
+
+use fcnet_types::{FirecrackerNetwork, FirecrackerNetworkOperation, FirecrackerRuleBackend};
+use nftables::{
+    schema::{NfListObject, NfObject},
+    stmt::Statement,
+    types::{NfChainPolicy, NfFamily, NfHook},
+};
+use nftables_async::{driver::Driver, helper::Helper};
+
+use crate::{
+    backend::Backend,
+    run,
+    util::{matches_interface, FirecrackerNetworkExt, NO_NFT_ARGS},
+    FirecrackerNetworkError,
+};
+
+/// A base chain outside of a [FirecrackerNetwork]'s own table that can drop the network's forwarded traffic
+/// regardless of fcnet's rules accepting it, as found by [diagnose].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FirecrackerNetworkConflict {
+    /// The family of the conflicting chain's table.
+    pub family: NfFamily,
+    /// The name of the conflicting chain's table.
+    pub table: String,
+    /// The name of the conflicting chain.
+    pub chain: String,
+    /// The hook that the conflicting chain is attached to.
+    pub hook: NfHook,
+    /// Why the conflicting chain can drop the network's traffic.
+    pub reason: FirecrackerNetworkConflictReason,
+}
+
+/// The reason that a [FirecrackerNetworkConflict] can drop a network's traffic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FirecrackerNetworkConflictReason {
+    /// The chain's policy is to drop, as is the case with Docker's FORWARD chain.
+    DropPolicy,
+    /// The chain ends in an unconditional drop or reject rule, as is the case with firewalld's chains.
+    DropRule,
+    /// The chain belongs to an iptables-legacy table, which is invisible to nftables, and its policy is to drop.
+    LegacyIptables,
+}
+
+impl std::fmt::Display for FirecrackerNetworkConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let reason = match self.reason {
+            FirecrackerNetworkConflictReason::DropPolicy => "has a drop policy",
+            FirecrackerNetworkConflictReason::DropRule => "has an unconditional drop or reject rule",
+            FirecrackerNetworkConflictReason::LegacyIptables => "belongs to iptables-legacy and has a drop policy",
+        };
+
+        write!(
+            f,
+            "chain \"{}\" of table \"{}\" ({:?}) on the {:?} hook {reason}",
+            self.chain, self.table, self.family, self.hook
+        )
+    }
+}
+
+/// Diagnose whether the forwarded traffic of a [FirecrackerNetwork] can be dropped by base chains outside of its own
+/// table via the given [Backend], by inspecting the full nftables ruleset for forward and postrouting base chains of
+/// overlapping families that drop by policy or by an unconditional rule, as well as for iptables-legacy FORWARD chains
+/// with a drop policy. Chains with rules that match the network's interface are assumed to have been set up for the
+/// network and aren't reported. Networks whose traffic isn't forwarded by the host never conflict, and networks using
+/// the iptables rule backend aren't diagnosed, since the host may lack nftables.
+pub async fn diagnose<B: Backend>(
+    network: &FirecrackerNetwork,
+) -> Result<Vec<FirecrackerNetworkConflict>, FirecrackerNetworkError> {
+    let (Some(forward_iface_name), FirecrackerRuleBackend::Nftables) = (network.forward_iface_name(), &network.rule_backend)
+    else {
+        return Ok(Vec::new());
+    };
+
+    let current_ruleset = B::NftablesDriver::get_current_ruleset_with_args(network.nft_program(), NO_NFT_ARGS)
+        .await
+        .map_err(FirecrackerNetworkError::NftablesError)?;
+    let mut conflicts = Vec::new();
+
+    for object in current_ruleset.objects.iter() {
+        let NfObject::ListObject(NfListObject::Chain(chain)) = object else {
+            continue;
+        };
+
+        let Some(hook @ (NfHook::Forward | NfHook::Postrouting)) = chain.hook else {
+            continue;
+        };
+
+        if !families_overlap(chain.family, network.nf_family())
+            || (chain.family == network.nf_family() && chain.table == network.nft_config.table_name)
+        {
+            continue;
+        }
+
+        let mut matches_forward_iface = false;
+        let mut has_drop_rule = false;
+
+        for object in current_ruleset.objects.iter() {
+            match object {
+                NfObject::ListObject(NfListObject::Rule(rule))
+                    if rule.family == chain.family && rule.table == chain.table && rule.chain == chain.name =>
+                {
+                    if rule
+                        .expr
+                        .iter()
+                        .any(|statement| matches_interface(statement, forward_iface_name))
+                    {
+                        matches_forward_iface = true;
+                    } else if !rule.expr.iter().any(|statement| matches!(statement, Statement::Match(_)))
+                        && matches!(rule.expr.last(), Some(Statement::Drop(_) | Statement::Reject(_)))
+                    {
+                        has_drop_rule = true;
+                    }
+                }
+                _ => continue,
+            }
+        }
+
+        let reason = match (matches_forward_iface, chain.policy, has_drop_rule) {
+            (true, _, _) => continue,
+            (false, Some(NfChainPolicy::Drop), _) => FirecrackerNetworkConflictReason::DropPolicy,
+            (false, _, true) => FirecrackerNetworkConflictReason::DropRule,
+            _ => continue,
+        };
+
+        conflicts.push(FirecrackerNetworkConflict {
+            family: chain.family,
+            table: chain.table.to_string(),
+            chain: chain.name.to_string(),
+            hook,
+            reason,
+        });
+    }
+
+    for (family, tables_path, program) in [
+        (NfFamily::IP, "/proc/net/ip_tables_names", "iptables-legacy"),
+        (NfFamily::IP6, "/proc/net/ip6_tables_names", "ip6tables-legacy"),
+    ] {
+        if !families_overlap(family, network.nf_family()) {
+            continue;
+        }
+
+        // the file is missing when the iptables-legacy module isn't loaded at all
+        let Ok(table_names) = std::fs::read_to_string(tables_path) else {
+            continue;
+        };
+
+        if table_names.lines().any(|table_name| table_name == "filter")
+            && legacy_forward_chain_drops::<B>(program, forward_iface_name).await
+        {
+            conflicts.push(FirecrackerNetworkConflict {
+                family,
+                table: "filter".to_string(),
+                chain: "FORWARD".to_string(),
+                hook: NfHook::Forward,
+                reason: FirecrackerNetworkConflictReason::LegacyIptables,
+            });
+        }
+    }
+
+    Ok(conflicts)
+}
+
+/// Check a [FirecrackerNetwork] via the given [Backend] like [run], and once the check passes, [diagnose] it for
+/// conflicting base chains. Conflicts don't fail the check, since they may be intended by the host's firewall and
+/// re-adding the network can't resolve them, so they're returned as warnings alongside the passed check instead.
+pub async fn check_with_diagnostics<B: Backend>(
+    network: &FirecrackerNetwork,
+) -> Result<Vec<FirecrackerNetworkConflict>, FirecrackerNetworkError> {
+    run::<B>(network, FirecrackerNetworkOperation::Check).await?;
+    diagnose::<B>(network).await
+}
+
+/// Whether the FORWARD chain of the iptables-legacy filter table has a drop policy without any rule matching the
+/// network's interface, as listed by the given iptables-legacy binary. A loaded module alone isn't a conflict, and
+/// neither is a chain that can't be listed since the binary isn't installed.
+async fn legacy_forward_chain_drops<B: Backend>(program: &str, forward_iface_name: &str) -> bool {
+    let args = ["-w", "-t", "filter", "-S", "FORWARD"].map(OsStr::new);
+    let Ok(output) = B::NftablesDriver::run_process(OsStr::new(program), &args, None).await else {
+        return false;
+    };
+
+    if !output.status.success() {
+        return false;
+    }
+
+    legacy_listing_drops(&String::from_utf8_lossy(&output.stdout), forward_iface_name)
+}
+
+fn legacy_listing_drops(listing: &str, forward_iface_name: &str) -> bool {
+    let mut has_drop_policy = false;
+    let mut matches_forward_iface = false;
+
+    for line in listing.lines() {
+        let args = line.split_whitespace().collect::<Vec<_>>();
+
+        match args.as_slice() {
+            ["-P", "FORWARD", "DROP"] => has_drop_policy = true,
+            args => {
+                matches_forward_iface |= args
+                    .windows(2)
+                    .any(|pair| matches!(pair, ["-i" | "-o", iface_name] if *iface_name == forward_iface_name));
+            }
+        }
+    }
+
+    has_drop_policy && !matches_forward_iface
+}
+
+#[inline]
+fn families_overlap(family: NfFamily, other_family: NfFamily) -> bool {
+    match (family, other_family) {
+        (NfFamily::INet, NfFamily::IP | NfFamily::IP6 | NfFamily::INet) => true,
+        (NfFamily::IP | NfFamily::IP6, NfFamily::INet) => true,
+        (family, other_family) => family == other_family,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::legacy_listing_drops;
+
+    #[test]
+    fn legacy_drop_policy_is_a_conflict() {
+        let listing = "-P FORWARD DROP\n-A FORWARD -j DOCKER-USER\n-A FORWARD -o docker0 -j ACCEPT\n";
+        assert!(legacy_listing_drops(listing, "tap0"));
+    }
+
+    #[test]
+    fn legacy_accept_policy_isnt_a_conflict() {
+        assert!(!legacy_listing_drops("-P FORWARD ACCEPT\n", "tap0"));
+    }
+
+    #[test]
+    fn legacy_rules_for_the_interface_resolve_the_conflict() {
+        let listing = "-P FORWARD DROP\n-A FORWARD -i tap0 -j ACCEPT\n-A FORWARD -o tap0 -j ACCEPT\n";
+        assert!(!legacy_listing_drops(listing, "tap0"));
+        assert!(legacy_listing_drops(listing, "tap01"));
+    }
+}
@@ -0,0 +1,372 @@
+use std::borrow::Cow;
+
+use fcnet_types::FirecrackerNetwork;
+use nftables::{
+    batch::Batch,
+    expr::{Expression, Meta, MetaKey, NamedExpression, CT},
+    schema::{FlowTable, NfCmd, NfListObject, NfObject, Nftables, Rule},
+    stmt::{Flow, Match, Operator, SetOp, Statement},
+    types::NfHook,
+};
+use nftables_async::helper::Helper;
+
+use crate::{
+    backend::Backend,
+    rules::{ensure_nftables, NftRuleDeletion},
+    util::{FirecrackerNetworkExt, NO_NFT_ARGS},
+    FirecrackerNetworkError, FirecrackerNetworkObjectType, NFT_FLOWTABLE,
+};
+
+/// Ensure that the flowtable isn't combined with the iptables rule backend, since the flowtable and its flow rule
+/// can only live in the nftables table and filter chain that this backend doesn't create.
+pub fn validate(network: &FirecrackerNetwork) -> Result<(), FirecrackerNetworkError> {
+    match network.flowtable {
+        true => ensure_nftables(&network.rule_backend, "flowtables"),
+        false => Ok(()),
+    }
+}
+
+pub async fn add_if_needed<B: Backend>(network: &FirecrackerNetwork) -> Result<(), FirecrackerNetworkError> {
+    if !network.flowtable || network.forward_iface_name().is_none() {
+        return Ok(());
+    }
+
+    let current_ruleset = B::NftablesDriver::get_current_ruleset_with_args(network.nft_program(), NO_NFT_ARGS)
+        .await
+        .map_err(FirecrackerNetworkError::NftablesError)?;
+    let mut batch = Batch::new();
+    add_to_batch_if_needed(network, &current_ruleset, &mut batch)?;
+
+    B::NftablesDriver::apply_ruleset_with_args(&batch.to_nftables(), network.nft_program(), NO_NFT_ARGS)
+        .await
+        .map_err(FirecrackerNetworkError::NftablesError)
+}
+
+/// Whether the network's flow rule is shared with other networks, which is the case for all ports of a bridge, so that it
+/// isn't owned by any one of them.
+#[inline]
+pub fn is_shared(network: &FirecrackerNetwork) -> bool {
+    match network.network_type {
+        #[cfg(feature = "bridge")]
+        fcnet_types::FirecrackerNetworkType::Bridge { .. } => true,
+        #[allow(unreachable_patterns)]
+        _ => false,
+    }
+}
+
+/// Whether deleting the network deletes its flow rule, judging by whether the flow rule is looked up for the deletion
+/// or was already deleted by its handle. A flow rule shared by the ports of a bridge is never part of a handle and is
+/// only deleted along with the last port, like the other rules of the bridge.
+#[allow(unused_variables)]
+pub async fn deletes_flow_rule(
+    network: &FirecrackerNetwork,
+    netlink_handle: &rtnetlink::Handle,
+    deletion: NftRuleDeletion,
+) -> Result<bool, FirecrackerNetworkError> {
+    if !network.flowtable || network.forward_iface_name().is_none() {
+        return Ok(false);
+    }
+
+    match network.network_type {
+        #[cfg(feature = "bridge")]
+        fcnet_types::FirecrackerNetworkType::Bridge { ref bridge_name } => {
+            crate::bridge::deletes_rules(network, netlink_handle, bridge_name).await
+        }
+        #[allow(unreachable_patterns)]
+        _ => Ok(deletion == NftRuleDeletion::Lookup),
+    }
+}
+
+pub async fn delete_if_needed<B: Backend>(network: &FirecrackerNetwork) -> Result<(), FirecrackerNetworkError> {
+    if !network.flowtable || network.forward_iface_name().is_none() {
+        return Ok(());
+    }
+
+    let current_ruleset = B::NftablesDriver::get_current_ruleset_with_args(network.nft_program(), NO_NFT_ARGS)
+        .await
+        .map_err(FirecrackerNetworkError::NftablesError)?;
+    let mut batch = Batch::new();
+    delete_to_batch_if_needed(network, &current_ruleset, &mut batch)?;
+
+    B::NftablesDriver::apply_ruleset_with_args(&batch.to_nftables(), network.nft_program(), NO_NFT_ARGS)
+        .await
+        .map_err(FirecrackerNetworkError::NftablesError)
+}
+
+/// Add the flowtable, merging the network's devices into it, and the network's flow rule if it's missing in the
+/// given ruleset to the batch.
+pub fn add_to_batch_if_needed(
+    network: &FirecrackerNetwork,
+    current_ruleset: &Nftables,
+    batch: &mut Batch<'static>,
+) -> Result<(), FirecrackerNetworkError> {
+    validate(network)?;
+    let (true, Some(forward_iface_name)) = (network.flowtable, network.forward_iface_name()) else {
+        return Ok(());
+    };
+
+    let mut flow_rule_exists = false;
+
+    for object in current_ruleset.objects.iter() {
+        match object {
+            NfObject::ListObject(NfListObject::Rule(rule))
+                if rule.table == network.nft_config.table_name
+                    && rule.chain == network.nft_config.filter_chain_name
+                    && rule.expr == flow_expr(forward_iface_name) =>
+            {
+                flow_rule_exists = true;
+            }
+            _ => continue,
+        }
+    }
+
+    // adding an existing flowtable merges the given devices into its device set
+    batch.add(NfListObject::FlowTable(flowtable(
+        network,
+        None,
+        Some(vec![forward_iface_name.to_string().into(), network.uplink_name()?.into()]),
+    )));
+
+    // the flow rule is inserted at the start of the chain, so that it's evaluated before the accepting rules
+    if !flow_rule_exists {
+        batch.add_cmd(NfCmd::Insert(NfListObject::Rule(Rule {
+            family: network.nf_family(),
+            table: network.nft_config.table_name.clone().into(),
+            chain: network.nft_config.filter_chain_name.clone().into(),
+            expr: flow_expr(forward_iface_name).into(),
+            handle: None,
+            index: None,
+            comment: None,
+        })));
+    }
+
+    Ok(())
+}
+
+/// Find the handle of the network's flow rule in the given ruleset, which is only present if the network's traffic is
+/// offloaded to the flowtable.
+pub fn find_flow_rule_handle(
+    network: &FirecrackerNetwork,
+    current_ruleset: &Nftables,
+) -> Result<Option<u32>, FirecrackerNetworkError> {
+    let (true, Some(forward_iface_name)) = (network.flowtable, network.forward_iface_name()) else {
+        return Ok(None);
+    };
+
+    current_ruleset
+        .objects
+        .iter()
+        .find_map(|object| match object {
+            NfObject::ListObject(NfListObject::Rule(rule))
+                if rule.table == network.nft_config.table_name
+                    && rule.family == network.nf_family()
+                    && rule.chain == network.nft_config.filter_chain_name
+                    && rule.expr == flow_expr(forward_iface_name) =>
+            {
+                rule.handle
+            }
+            _ => None,
+        })
+        .map(Some)
+        .ok_or(FirecrackerNetworkError::ObjectNotFound(
+            FirecrackerNetworkObjectType::NfFlowOffloadRule,
+        ))
+}
+
+/// Add the deletion of the network's flow rule, as well as of the flowtable if no other flow rules remain, by their
+/// handles in the given ruleset to the batch.
+pub fn delete_to_batch_if_needed(
+    network: &FirecrackerNetwork,
+    current_ruleset: &Nftables,
+    batch: &mut Batch<'static>,
+) -> Result<(), FirecrackerNetworkError> {
+    let (true, Some(forward_iface_name)) = (network.flowtable, network.forward_iface_name()) else {
+        return Ok(());
+    };
+
+    let mut flow_rule_handle = None;
+    let mut flowtable_handle = None;
+    let mut other_flow_rules_exist = false;
+
+    for object in current_ruleset.objects.iter() {
+        match object {
+            NfObject::ListObject(NfListObject::FlowTable(flowtable))
+                if flowtable.table == network.nft_config.table_name
+                    && flowtable.family == network.nf_family()
+                    && flowtable.name == NFT_FLOWTABLE =>
+            {
+                flowtable_handle = flowtable.handle;
+            }
+            NfObject::ListObject(NfListObject::Rule(rule))
+                if rule.table == network.nft_config.table_name
+                    && rule.family == network.nf_family()
+                    && rule.chain == network.nft_config.filter_chain_name =>
+            {
+                if rule.expr == flow_expr(forward_iface_name) {
+                    flow_rule_handle = rule.handle;
+                } else if rule.expr.iter().any(|statement| matches!(statement, Statement::Flow(_))) {
+                    other_flow_rules_exist = true;
+                }
+            }
+            _ => continue,
+        }
+    }
+
+    if flow_rule_handle.is_none() {
+        return Err(FirecrackerNetworkError::ObjectNotFound(
+            FirecrackerNetworkObjectType::NfFlowOffloadRule,
+        ));
+    }
+
+    if flowtable_handle.is_none() {
+        return Err(FirecrackerNetworkError::ObjectNotFound(
+            FirecrackerNetworkObjectType::NfFlowtable,
+        ));
+    }
+
+    batch.delete(NfListObject::Rule(Rule {
+        family: network.nf_family(),
+        table: network.nft_config.table_name.clone().into(),
+        chain: network.nft_config.filter_chain_name.clone().into(),
+        expr: flow_expr(forward_iface_name).into(),
+        handle: flow_rule_handle,
+        index: None,
+        comment: None,
+    }));
+
+    // deleted devices leave the flowtable on their own, so it only needs to be deleted along with the last flow rule
+    // in order to release the uplink
+    if !other_flow_rules_exist {
+        batch.delete(NfListObject::FlowTable(flowtable(network, flowtable_handle, None)));
+    }
+
+    Ok(())
+}
+
+/// Add the deletion of the flowtable to the batch if no flow rules remain in the given ruleset other than those with the
+/// given handles, which are deleted along with it.
+pub fn delete_unused_to_batch(
+    network: &FirecrackerNetwork,
+    current_ruleset: &Nftables,
+    deleted_handles: &[u32],
+    batch: &mut Batch<'static>,
+) {
+    if !network.flowtable || network.forward_iface_name().is_none() {
+        return;
+    }
+
+    let mut flowtable_handle = None;
+    let mut other_flow_rules_exist = false;
+
+    for object in current_ruleset.objects.iter() {
+        match object {
+            NfObject::ListObject(NfListObject::FlowTable(flowtable))
+                if flowtable.table == network.nft_config.table_name
+                    && flowtable.family == network.nf_family()
+                    && flowtable.name == NFT_FLOWTABLE =>
+            {
+                flowtable_handle = flowtable.handle;
+            }
+            NfObject::ListObject(NfListObject::Rule(rule))
+                if rule.table == network.nft_config.table_name
+                    && rule.family == network.nf_family()
+                    && rule.expr.iter().any(|statement| matches!(statement, Statement::Flow(_)))
+                    && !rule.handle.is_some_and(|handle| deleted_handles.contains(&handle)) =>
+            {
+                other_flow_rules_exist = true;
+            }
+            _ => continue,
+        }
+    }
+
+    if flowtable_handle.is_some() && !other_flow_rules_exist {
+        batch.delete(NfListObject::FlowTable(flowtable(network, flowtable_handle, None)));
+    }
+}
+
+pub async fn check_if_needed<B: Backend>(network: &FirecrackerNetwork) -> Result<(), FirecrackerNetworkError> {
+    let (true, Some(forward_iface_name)) = (network.flowtable, network.forward_iface_name()) else {
+        return Ok(());
+    };
+
+    let current_ruleset = B::NftablesDriver::get_current_ruleset_with_args(network.nft_program(), NO_NFT_ARGS)
+        .await
+        .map_err(FirecrackerNetworkError::NftablesError)?;
+    let uplink_name = network.uplink_name()?;
+    let mut flowtable_has_devices = false;
+    let mut flow_rule_exists = false;
+
+    for object in current_ruleset.objects.iter() {
+        match object {
+            NfObject::ListObject(NfListObject::FlowTable(flowtable))
+                if flowtable.table == network.nft_config.table_name
+                    && flowtable.family == network.nf_family()
+                    && flowtable.name == NFT_FLOWTABLE =>
+            {
+                if let Some(ref devices) = flowtable.dev {
+                    flowtable_has_devices = devices.iter().any(|device| device == forward_iface_name)
+                        && devices.iter().any(|device| *device == uplink_name);
+                }
+            }
+            NfObject::ListObject(NfListObject::Rule(rule))
+                if rule.table == network.nft_config.table_name
+                    && rule.chain == network.nft_config.filter_chain_name
+                    && rule.expr == flow_expr(forward_iface_name) =>
+            {
+                flow_rule_exists = true;
+            }
+            _ => continue,
+        }
+    }
+
+    if !flowtable_has_devices {
+        return Err(FirecrackerNetworkError::ObjectNotFound(
+            FirecrackerNetworkObjectType::NfFlowtable,
+        ));
+    }
+
+    if !flow_rule_exists {
+        return Err(FirecrackerNetworkError::ObjectNotFound(
+            FirecrackerNetworkObjectType::NfFlowOffloadRule,
+        ));
+    }
+
+    Ok(())
+}
+
+#[inline]
+fn flowtable(network: &FirecrackerNetwork, handle: Option<u32>, devices: Option<Vec<Cow<'static, str>>>) -> FlowTable<'static> {
+    FlowTable {
+        family: network.nf_family(),
+        table: network.nft_config.table_name.clone().into(),
+        name: NFT_FLOWTABLE.into(),
+        handle,
+        hook: devices.as_ref().map(|_| NfHook::Ingress),
+        prio: devices.as_ref().map(|_| 0),
+        dev: devices.map(Into::into),
+    }
+}
+
+#[inline]
+fn flow_expr(forward_iface_name: &str) -> Vec<Statement<'static>> {
+    vec![
+        Statement::Match(Match {
+            left: Expression::Named(NamedExpression::Meta(Meta { key: MetaKey::Iifname })),
+            right: Expression::String(forward_iface_name.to_string().into()),
+            op: Operator::EQ,
+        }),
+        Statement::Match(Match {
+            left: Expression::Named(NamedExpression::CT(CT {
+                key: "state".into(),
+                family: None,
+                dir: None,
+            })),
+            right: Expression::String("established".into()),
+            op: Operator::IN,
+        }),
+        Statement::Flow(Flow {
+            op: SetOp::Add,
+            flowtable: format!("@{NFT_FLOWTABLE}").into(),
+        }),
+    ]
+}
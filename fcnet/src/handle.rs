@@ -0,0 +1,264 @@
+use std::ffi::OsStr;
+
+#[cfg(any(feature = "namespaced", feature = "bridge"))]
+use fcnet_types::FirecrackerNetworkType;
+use fcnet_types::{FirecrackerNetwork, FirecrackerNetworkHandle, FirecrackerNetworkOperation, FirecrackerNftRuleHandle};
+use nftables::{
+    batch::Batch,
+    helper::NftablesError,
+    schema::{NfListObject, NfObject, Nftables, Rule},
+};
+use nftables_async::{driver::Driver, helper::Helper};
+
+use crate::{
+    backend::Backend,
+    flowtable, policy,
+    render::nft_network_rules,
+    rules::{find_nft_rule_handle, nft_chain_name, unique_rules, NftRuleDeletion},
+    run,
+    util::{get_link_identity, nf_family_name, resolve_iface_name, FirecrackerNetworkExt, NO_NFT_ARGS},
+    FirecrackerNetworkError, FirecrackerNetworkObjectType,
+};
+
+/// Add a [FirecrackerNetwork] via the given [Backend] like [run], returning a [FirecrackerNetworkHandle] of the created
+/// objects that can be passed to [run_with_handle] later on.
+pub async fn add<B: Backend>(network: &FirecrackerNetwork) -> Result<FirecrackerNetworkHandle, FirecrackerNetworkError> {
+    run::<B>(network, FirecrackerNetworkOperation::Add).await?;
+    get_handle::<B>(network).await
+}
+
+/// Get the [FirecrackerNetworkHandle] of an already added [FirecrackerNetwork] via the given [Backend].
+pub async fn get_handle<B: Backend>(network: &FirecrackerNetwork) -> Result<FirecrackerNetworkHandle, FirecrackerNetworkError> {
+    let (connection, netlink_handle, _) =
+        rtnetlink::new_connection_with_socket::<B::NetlinkSocket>().map_err(FirecrackerNetworkError::IoError)?;
+    B::spawn_connection(connection);
+
+    let network = resolve_iface_name(network, &netlink_handle).await?;
+    let network = network.as_ref();
+
+    let (tap_index, tap_mac) = get_tap_identity::<B>(network, &netlink_handle, None).await?;
+    let netns_inode = get_netns_inode_if_needed(network)?;
+
+    let context = network.rule_context();
+    // the rules of a bridge are shared by all of its ports, so they aren't owned by the handle of any one of them
+    let mut rules = match network.network_type {
+        #[cfg(feature = "bridge")]
+        FirecrackerNetworkType::Bridge { .. } => Vec::new(),
+        #[allow(unreachable_patterns)]
+        _ => nft_network_rules(network, &netlink_handle, FirecrackerNetworkOperation::Add).await?,
+    };
+    rules.extend(
+        network
+            .policy_route
+            .map(|policy_route| policy::mark_rule(network, &policy_route)),
+    );
+
+    let mut nft_rules = Vec::with_capacity(rules.len() + 1);
+
+    // networks applying all of their rules via iptables may run on hosts without nft, so the ruleset isn't listed
+    if rules.is_empty() && (!network.flowtable || flowtable::is_shared(network)) {
+        return Ok(FirecrackerNetworkHandle {
+            tap_index,
+            tap_mac,
+            netns_inode,
+            nft_rules,
+        });
+    }
+
+    let current_ruleset = B::NftablesDriver::get_current_ruleset_with_args(network.nft_program(), NO_NFT_ARGS)
+        .await
+        .map_err(FirecrackerNetworkError::NftablesError)?;
+
+    for rule in unique_rules(&rules) {
+        let handle = find_nft_rule_handle(&context, &current_ruleset, rule)
+            .ok_or(FirecrackerNetworkError::ObjectNotFound(rule.object_type))?;
+        nft_rules.push(FirecrackerNftRuleHandle {
+            chain: nft_chain_name(&context, rule.chain).to_string(),
+            handle,
+        });
+    }
+
+    // like the other rules of a bridge, its flow rule is shared by all of its ports
+    if !flowtable::is_shared(network) {
+        if let Some(handle) = flowtable::find_flow_rule_handle(network, &current_ruleset)? {
+            nft_rules.push(FirecrackerNftRuleHandle {
+                chain: network.nft_config.filter_chain_name.clone(),
+                handle,
+            });
+        }
+    }
+
+    Ok(FirecrackerNetworkHandle {
+        tap_index,
+        tap_mac,
+        netns_inode,
+        nft_rules,
+    })
+}
+
+/// Run a [FirecrackerNetworkOperation] on a [FirecrackerNetwork] via the given [Backend], targeting the objects
+/// identified by its [FirecrackerNetworkHandle]. A check only verifies that the tap device and netns are still the
+/// ones that were added and that all rule handles still exist in the configured table, which is much cheaper than a
+/// full check. A delete is refused if the tap device or netns were replaced in the meantime, so that objects recreated
+/// by others under the same names aren't deleted, and otherwise deletes the nftables rules by their handles in a
+/// single batch before deleting the network's other objects. Adds and flushes are run like via [run].
+pub async fn run_with_handle<B: Backend>(
+    network: &FirecrackerNetwork,
+    operation: FirecrackerNetworkOperation,
+    handle: &FirecrackerNetworkHandle,
+) -> Result<(), FirecrackerNetworkError> {
+    match operation {
+        FirecrackerNetworkOperation::Check => {
+            check_identity::<B>(network, handle).await?;
+            check_nft_rules::<B>(network, handle).await
+        }
+        FirecrackerNetworkOperation::Delete => {
+            check_identity::<B>(network, handle).await?;
+            delete_nft_rules::<B>(network, handle).await?;
+
+            let (connection, netlink_handle, _) =
+                rtnetlink::new_connection_with_socket::<B::NetlinkSocket>().map_err(FirecrackerNetworkError::IoError)?;
+            B::spawn_connection(connection);
+            let network = resolve_iface_name(network, &netlink_handle).await?;
+            crate::delete::<B>(network.as_ref(), &netlink_handle, NftRuleDeletion::Skip).await
+        }
+        FirecrackerNetworkOperation::Add | FirecrackerNetworkOperation::Flush => run::<B>(network, operation).await,
+    }
+}
+
+async fn check_identity<B: Backend>(
+    network: &FirecrackerNetwork,
+    handle: &FirecrackerNetworkHandle,
+) -> Result<(), FirecrackerNetworkError> {
+    let (connection, netlink_handle, _) =
+        rtnetlink::new_connection_with_socket::<B::NetlinkSocket>().map_err(FirecrackerNetworkError::IoError)?;
+    B::spawn_connection(connection);
+
+    // the netns is checked first, since the tap device is looked up inside it
+    #[cfg(feature = "namespaced")]
+    if get_netns_inode_if_needed(network)? != handle.netns_inode {
+        return Err(FirecrackerNetworkError::ObjectNotFound(FirecrackerNetworkObjectType::Netns));
+    }
+
+    let (_, tap_mac) = get_tap_identity::<B>(network, &netlink_handle, Some(handle.tap_index)).await?;
+    match tap_mac == handle.tap_mac {
+        true => Ok(()),
+        false => Err(FirecrackerNetworkError::ObjectNotFound(FirecrackerNetworkObjectType::IpLink)),
+    }
+}
+
+async fn check_nft_rules<B: Backend>(
+    network: &FirecrackerNetwork,
+    handle: &FirecrackerNetworkHandle,
+) -> Result<(), FirecrackerNetworkError> {
+    if handle.nft_rules.is_empty() {
+        return Ok(());
+    }
+
+    let table = list_table::<B>(network).await?;
+
+    for rule_handle in &handle.nft_rules {
+        let exists = table.objects.iter().any(|object| match object {
+            NfObject::ListObject(NfListObject::Rule(rule)) => {
+                rule.chain == rule_handle.chain && rule.handle == Some(rule_handle.handle)
+            }
+            _ => false,
+        });
+
+        if !exists {
+            return Err(FirecrackerNetworkError::ObjectNotFound(
+                FirecrackerNetworkObjectType::NfRuleHandle,
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+async fn delete_nft_rules<B: Backend>(
+    network: &FirecrackerNetwork,
+    handle: &FirecrackerNetworkHandle,
+) -> Result<(), FirecrackerNetworkError> {
+    if handle.nft_rules.is_empty() {
+        return Ok(());
+    }
+
+    let mut batch = Batch::new();
+
+    for rule_handle in &handle.nft_rules {
+        batch.delete(NfListObject::Rule(Rule {
+            family: network.nf_family(),
+            table: network.nft_config.table_name.clone().into(),
+            chain: rule_handle.chain.clone().into(),
+            expr: Vec::new().into(),
+            handle: Some(rule_handle.handle),
+            index: None,
+            comment: None,
+        }));
+    }
+
+    // the table is only listed to find out whether the flowtable is still used by other networks' flow rules
+    if network.flowtable {
+        let table = list_table::<B>(network).await?;
+        let deleted_handles = handle
+            .nft_rules
+            .iter()
+            .map(|rule_handle| rule_handle.handle)
+            .collect::<Vec<_>>();
+        flowtable::delete_unused_to_batch(network, &table, &deleted_handles, &mut batch);
+    }
+
+    B::NftablesDriver::apply_ruleset_with_args(&batch.to_nftables(), network.nft_program(), NO_NFT_ARGS)
+        .await
+        .map_err(FirecrackerNetworkError::NftablesError)
+}
+
+async fn list_table<B: Backend>(network: &FirecrackerNetwork) -> Result<Nftables<'static>, FirecrackerNetworkError> {
+    // only the configured table is listed instead of the entire ruleset
+    let program = OsStr::new(network.nft_program().unwrap_or("nft"));
+    let args = [
+        "-j",
+        "list",
+        "table",
+        nf_family_name(network.nf_family()),
+        &network.nft_config.table_name,
+    ]
+    .map(OsStr::new);
+    let output = B::NftablesDriver::run_process(program, &args, None).await.map_err(|err| {
+        FirecrackerNetworkError::NftablesError(NftablesError::NftExecution {
+            program: program.into(),
+            inner: err,
+        })
+    })?;
+
+    if !output.status.success() {
+        return Err(FirecrackerNetworkError::ObjectNotFound(FirecrackerNetworkObjectType::NfTable));
+    }
+
+    serde_json::from_slice::<Nftables>(&output.stdout)
+        .map_err(|err| FirecrackerNetworkError::NftablesError(NftablesError::NftInvalidJson(err)))
+}
+
+#[cfg_attr(not(feature = "namespaced"), allow(clippy::extra_unused_type_parameters))]
+async fn get_tap_identity<B: Backend>(
+    network: &FirecrackerNetwork,
+    netlink_handle: &rtnetlink::Handle,
+    tap_index: Option<u32>,
+) -> Result<(u32, Option<String>), FirecrackerNetworkError> {
+    #[cfg(feature = "namespaced")]
+    if let FirecrackerNetworkType::Namespaced { .. } = network.network_type {
+        return crate::namespaced::get_inner_tap_identity::<B>(network, tap_index).await;
+    }
+
+    get_link_identity(&network.tap_name, tap_index, netlink_handle).await
+}
+
+#[allow(unused_variables)]
+fn get_netns_inode_if_needed(network: &FirecrackerNetwork) -> Result<Option<u64>, FirecrackerNetworkError> {
+    #[cfg(feature = "namespaced")]
+    if let FirecrackerNetworkType::Namespaced { .. } = network.network_type {
+        return crate::namespaced::get_netns_inode(network).map(Some);
+    }
+
+    Ok(None)
+}
@@ -0,0 +1,97 @@
+use std::future::Future;
+
+use fcnet_types::{FirecrackerNetwork, FirecrackerNetworkHandle, FirecrackerNetworkOperation};
+
+use crate::{backend::Backend, run, run_with_handle, FirecrackerNetworkError};
+
+/// Site-specific steps that [run_with_hooks] invokes around adding, checking and deleting a [FirecrackerNetwork],
+/// for example in order to register the guest in DNS or to notify an inventory.
+pub trait FirecrackerNetworkHooks: Sync {
+    /// Invoked before the operation, which isn't run if this step fails.
+    fn pre_operation(
+        &self,
+        network: &FirecrackerNetwork,
+        operation: FirecrackerNetworkOperation,
+    ) -> impl Future<Output = Result<(), String>> + Send;
+
+    /// Invoked after the operation with its result, rolling a successful add or delete back if this step fails.
+    fn post_operation(
+        &self,
+        network: &FirecrackerNetwork,
+        operation: FirecrackerNetworkOperation,
+        result: &Result<(), FirecrackerNetworkError>,
+    ) -> impl Future<Output = Result<(), String>> + Send;
+}
+
+/// Run a [FirecrackerNetworkOperation] on a [FirecrackerNetwork] via the given [Backend] like [run], surrounded by the
+/// given [FirecrackerNetworkHooks]. A failing pre-hook aborts the operation, while a failing post-hook of a successful
+/// operation rolls it back by deleting an added network or re-adding a deleted one, both failing with
+/// [FirecrackerNetworkError::HookError]. Flushes only affect conntrack entries and aren't surrounded by hooks.
+pub async fn run_with_hooks<B: Backend, H: FirecrackerNetworkHooks>(
+    network: &FirecrackerNetwork,
+    operation: FirecrackerNetworkOperation,
+    hooks: &H,
+) -> Result<(), FirecrackerNetworkError> {
+    run_hooked::<B, H>(network, operation, None, hooks).await
+}
+
+/// Run a [FirecrackerNetworkOperation] on a [FirecrackerNetwork] via the given [Backend] like [run_with_handle],
+/// surrounded by the given [FirecrackerNetworkHooks] like via [run_with_hooks].
+pub async fn run_with_handle_and_hooks<B: Backend, H: FirecrackerNetworkHooks>(
+    network: &FirecrackerNetwork,
+    operation: FirecrackerNetworkOperation,
+    handle: &FirecrackerNetworkHandle,
+    hooks: &H,
+) -> Result<(), FirecrackerNetworkError> {
+    run_hooked::<B, H>(network, operation, Some(handle), hooks).await
+}
+
+async fn run_hooked<B: Backend, H: FirecrackerNetworkHooks>(
+    network: &FirecrackerNetwork,
+    operation: FirecrackerNetworkOperation,
+    handle: Option<&FirecrackerNetworkHandle>,
+    hooks: &H,
+) -> Result<(), FirecrackerNetworkError> {
+    if operation == FirecrackerNetworkOperation::Flush {
+        return run::<B>(network, operation).await;
+    }
+
+    hooks
+        .pre_operation(network, operation)
+        .await
+        .map_err(FirecrackerNetworkError::HookError)?;
+
+    let result = run_maybe_with_handle::<B>(network, operation, handle).await;
+    let hook_result = hooks.post_operation(network, operation, &result).await;
+    result?;
+
+    let Err(err) = hook_result else {
+        return Ok(());
+    };
+
+    let rollback_operation = match operation {
+        FirecrackerNetworkOperation::Add => FirecrackerNetworkOperation::Delete,
+        FirecrackerNetworkOperation::Delete => FirecrackerNetworkOperation::Add,
+        FirecrackerNetworkOperation::Check | FirecrackerNetworkOperation::Flush => {
+            return Err(FirecrackerNetworkError::HookError(err))
+        }
+    };
+
+    match run_maybe_with_handle::<B>(network, rollback_operation, handle).await {
+        Ok(_) => Err(FirecrackerNetworkError::HookError(err)),
+        Err(rollback_err) => Err(FirecrackerNetworkError::HookError(format!(
+            "{err}, and rolling the operation back failed: {rollback_err}"
+        ))),
+    }
+}
+
+async fn run_maybe_with_handle<B: Backend>(
+    network: &FirecrackerNetwork,
+    operation: FirecrackerNetworkOperation,
+    handle: Option<&FirecrackerNetworkHandle>,
+) -> Result<(), FirecrackerNetworkError> {
+    match handle {
+        Some(handle) => run_with_handle::<B>(network, operation, handle).await,
+        None => run::<B>(network, operation).await,
+    }
+}
@@ -0,0 +1,69 @@
+use fcnet_types::FirecrackerNetwork;
+use rtnetlink::{
+    packet_route::link::{InfoData, InfoIpVtap, InfoKind, IpVtapMode},
+    LinkMessageBuilder, LinkUnspec,
+};
+
+use crate::{
+    backend::Backend,
+    conntrack,
+    util::{check_link_address, check_stacked_link, get_link_index, FirecrackerNetworkExt},
+    FirecrackerNetworkError, FirecrackerNetworkOperation,
+};
+
+pub async fn run<B: Backend>(
+    network: &FirecrackerNetwork,
+    netlink_handle: rtnetlink::Handle,
+    operation: FirecrackerNetworkOperation,
+) -> Result<(), FirecrackerNetworkError> {
+    match operation {
+        FirecrackerNetworkOperation::Add => add(network, netlink_handle).await,
+        FirecrackerNetworkOperation::Check => check(network, netlink_handle).await,
+        FirecrackerNetworkOperation::Delete => delete(network, netlink_handle).await,
+        FirecrackerNetworkOperation::Flush => conntrack::flush::<B>(&[network.guest_ip.address()]).await,
+    }
+}
+
+async fn add(network: &FirecrackerNetwork, netlink_handle: rtnetlink::Handle) -> Result<(), FirecrackerNetworkError> {
+    let iface_idx = get_link_index(network.uplink_name()?, &netlink_handle).await?;
+    netlink_handle
+        .link()
+        .add(
+            LinkMessageBuilder::<LinkUnspec>::new_with_info_kind(InfoKind::IpVtap)
+                .name(network.tap_name.clone())
+                .link(iface_idx)
+                .set_info_data(InfoData::IpVtap(vec![InfoIpVtap::Mode(IpVtapMode::L3)]))
+                .up()
+                .build(),
+        )
+        .execute()
+        .await
+        .map_err(FirecrackerNetworkError::NetlinkOperationError)?;
+
+    // in L3 mode, the host interface demultiplexes ingress traffic to its ipvtap interfaces by their addresses, so the
+    // guest IP is registered on the ipvtap, which also lets the host interface answer ARP for it. Frames demultiplexed
+    // to the ipvtap are queued to its character device instead of reaching the host's network stack.
+    let ipvlan_idx = get_link_index(network.tap_name.clone(), &netlink_handle).await?;
+    netlink_handle
+        .address()
+        .add(ipvlan_idx, network.guest_ip.address(), network.guest_ip.network_length())
+        .execute()
+        .await
+        .map_err(FirecrackerNetworkError::NetlinkOperationError)
+}
+
+async fn check(network: &FirecrackerNetwork, netlink_handle: rtnetlink::Handle) -> Result<(), FirecrackerNetworkError> {
+    check_stacked_link(network, &netlink_handle).await?;
+    let ipvlan_idx = get_link_index(network.tap_name.clone(), &netlink_handle).await?;
+    check_link_address(ipvlan_idx, &network.guest_ip, &netlink_handle).await
+}
+
+async fn delete(network: &FirecrackerNetwork, netlink_handle: rtnetlink::Handle) -> Result<(), FirecrackerNetworkError> {
+    let ipvlan_idx = get_link_index(network.tap_name.clone(), &netlink_handle).await?;
+    netlink_handle
+        .link()
+        .del(ipvlan_idx)
+        .execute()
+        .await
+        .map_err(FirecrackerNetworkError::NetlinkOperationError)
+}
@@ -3,25 +3,53 @@
 use backend::Backend;
 use fcnet_types::{FirecrackerNetwork, FirecrackerNetworkOperation, FirecrackerNetworkType};
 use nftables::helper::NftablesError;
+use rules::NftRuleDeletion;
 
+#[cfg(feature = "bridge")]
+mod bridge;
+#[cfg(feature = "ipvlan")]
+mod ipvlan;
+#[cfg(feature = "macvtap")]
+mod macvtap;
 #[cfg(feature = "namespaced")]
 mod namespaced;
 #[cfg(feature = "namespaced")]
-mod netns;
+#[cfg_attr(docsrs, doc(cfg(feature = "namespaced")))]
+pub mod netns;
+#[cfg(feature = "namespaced")]
+#[cfg_attr(docsrs, doc(cfg(feature = "namespaced")))]
+pub use namespaced::run_in_netns;
 #[cfg(feature = "namespaced")]
 #[cfg_attr(docsrs, doc(cfg(feature = "namespaced")))]
 pub use netns::NetNsError;
+#[cfg(feature = "routed")]
+mod routed;
 #[cfg(feature = "simple")]
 mod simple;
 
 pub mod backend;
+mod conntrack;
+mod diagnostics;
+pub use diagnostics::{check_with_diagnostics, diagnose, FirecrackerNetworkConflict, FirecrackerNetworkConflictReason};
+mod flowtable;
+mod handle;
+pub use handle::{add, get_handle, run_with_handle};
+mod hooks;
+pub use hooks::{run_with_handle_and_hooks, run_with_hooks, FirecrackerNetworkHooks};
+mod policy;
+mod reconcile;
+pub use reconcile::{applied_networks, apply, apply_with_hooks, plan, FirecrackerNetworkAction, FirecrackerNetworkChange};
+mod render;
+pub use render::{render, FirecrackerNetworkRendering, FirecrackerNftChanges};
+mod rules;
+mod uplink;
+pub use uplink::{switch_uplink, watch_uplink, UplinkChange};
 pub(crate) mod util;
+mod vlan;
+mod watch;
+pub use watch::{watch, FirecrackerNetworkDrift};
 
-const NFT_TABLE: &str = "fcnet";
-const NFT_POSTROUTING_CHAIN: &str = "postrouting";
-#[cfg(feature = "namespaced")]
-const NFT_PREROUTING_CHAIN: &str = "prerouting";
-const NFT_FILTER_CHAIN: &str = "filter";
+const NFT_FLOWTABLE: &str = "flowtable";
 
 /// An error that can be emitted by embedded fcnet.
 #[derive(Debug)]
@@ -32,12 +60,22 @@ pub enum FirecrackerNetworkError {
     #[cfg_attr(docsrs, doc(cfg(feature = "namespaced")))]
     NetnsError(NetNsError),
     IoError(std::io::Error),
+    ConntrackError(std::io::Error),
     #[cfg(feature = "namespaced")]
     #[cfg_attr(docsrs, doc(cfg(feature = "namespaced")))]
     ChannelCancelError(futures_channel::oneshot::Canceled),
     NftablesError(NftablesError),
+    IptablesError(String),
+    RenderError(String),
+    HookError(String),
+    InvalidGuestMac(String),
+    InvalidVlan(&'static str),
+    UnresolvedIfaceName,
+    UnsupportedRuleBackend(&'static str),
+    UnsupportedNat(&'static str),
     ObjectNotFound(FirecrackerNetworkObjectType),
     ForbiddenDualStackInRoute,
+    ForbiddenDualStackInNat,
 }
 
 impl std::fmt::Display for FirecrackerNetworkError {
@@ -50,11 +88,31 @@ impl std::fmt::Display for FirecrackerNetworkError {
                 write!(f, "Interacting wtih a network namespace failed: {err}")
             }
             FirecrackerNetworkError::IoError(err) => write!(f, "A generic I/O error occurred: {err}"),
+            FirecrackerNetworkError::ConntrackError(err) => {
+                write!(f, "A ctnetlink operation on the conntrack table failed: {err}")
+            }
             #[cfg(feature = "namespaced")]
             FirecrackerNetworkError::ChannelCancelError(err) => {
                 write!(f, "Receiving from a supporting oneshot channel failed: {err}")
             }
             FirecrackerNetworkError::NftablesError(err) => write!(f, "Invoking nftables failed: {err}"),
+            FirecrackerNetworkError::IptablesError(err) => write!(f, "Invoking iptables failed: {err}"),
+            FirecrackerNetworkError::RenderError(err) => write!(f, "Rendering the nftables changes failed: {err}"),
+            FirecrackerNetworkError::HookError(err) => write!(f, "A hook around the operation failed: {err}"),
+            FirecrackerNetworkError::InvalidGuestMac(mac) => write!(f, "The guest MAC address is invalid: {mac}"),
+            FirecrackerNetworkError::InvalidVlan(reason) => write!(f, "The VLAN configuration is invalid: {reason}"),
+            FirecrackerNetworkError::UnresolvedIfaceName => {
+                write!(
+                    f,
+                    "The host interface name wasn't resolved before the network's objects were derived"
+                )
+            }
+            FirecrackerNetworkError::UnsupportedRuleBackend(feature) => {
+                write!(f, "The network's rule backend doesn't support {feature}")
+            }
+            FirecrackerNetworkError::UnsupportedNat(network_type) => {
+                write!(f, "{network_type} support neither a NAT IP nor port forwards")
+            }
             FirecrackerNetworkError::ObjectNotFound(object_type) => {
                 write!(f, "An nftables object was not found in the current ruleset: {object_type:?}")
             }
@@ -62,20 +120,32 @@ impl std::fmt::Display for FirecrackerNetworkError {
                 f,
                 "In a netlink route, both an IPv4 and an IPv6 support are being used (address, gateway)"
             ),
+            FirecrackerNetworkError::ForbiddenDualStackInNat => write!(
+                f,
+                "In a 1:1 NAT or port forward, both an IPv4 and an IPv6 address are being used (NAT IP or host IP, guest IP)"
+            ),
         }
     }
 }
 
 /// An object created by the integrated Firecracker networking backend.
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum FirecrackerNetworkObjectType {
     IpLink,
+    IpAddress,
     IpRoute,
+    IpRule,
+    IpNeighbour,
+    #[cfg(feature = "bridge")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "bridge")))]
+    BridgePort,
+    #[cfg(feature = "routed")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "routed")))]
+    Sysctl,
     NfTable,
     NfPostroutingChain,
-    #[cfg(feature = "namespaced")]
-    #[cfg_attr(docsrs, doc(cfg(feature = "namespaced")))]
     NfPreroutingChain,
+    NfMangleChain,
     NfFilterChain,
     NfMasqueradeRule,
     NfEgressForwardRule,
@@ -83,9 +153,14 @@ pub enum FirecrackerNetworkObjectType {
     #[cfg(feature = "namespaced")]
     #[cfg_attr(docsrs, doc(cfg(feature = "namespaced")))]
     NfEgressSnatRule,
+    NfIngressDnatRule,
+    NfMarkRule,
+    NfFlowtable,
+    NfFlowOffloadRule,
+    NfRuleHandle,
     #[cfg(feature = "namespaced")]
     #[cfg_attr(docsrs, doc(cfg(feature = "namespaced")))]
-    NfIngressDnatRule,
+    Netns,
 }
 
 /// Run a [FirecrackerNetworkOperation] on a [FirecrackerNetwork] via the given [Backend].
@@ -97,9 +172,73 @@ pub async fn run<B: Backend>(
         rtnetlink::new_connection_with_socket::<B::NetlinkSocket>().map_err(FirecrackerNetworkError::IoError)?;
     B::spawn_connection(connection);
 
+    let network = util::resolve_iface_name(network, &netlink_handle).await?;
+    let network = network.as_ref();
+
+    match operation {
+        FirecrackerNetworkOperation::Add => {
+            util::validate_guest_mac(network)?;
+            util::validate_nat_ip(network)?;
+            flowtable::validate(network)?;
+            vlan::validate(network)?;
+            policy::validate(network)?;
+            let created_vlan = vlan::add_if_needed(network, &netlink_handle).await?;
+            let result = async {
+                run_network_type::<B>(network, netlink_handle.clone(), operation, NftRuleDeletion::Lookup).await?;
+                flowtable::add_if_needed::<B>(network).await?;
+                policy::add_if_needed::<B>(network, &netlink_handle).await
+            }
+            .await;
+
+            // a VLAN interface created for this network would otherwise be left behind without any network using it
+            if result.is_err() && created_vlan {
+                let _ = vlan::delete_created(network, &netlink_handle).await;
+            }
+
+            result
+        }
+        FirecrackerNetworkOperation::Check => {
+            vlan::check_if_needed(network, &netlink_handle).await?;
+            run_network_type::<B>(network, netlink_handle.clone(), operation, NftRuleDeletion::Lookup).await?;
+            flowtable::check_if_needed::<B>(network).await?;
+            policy::check_if_needed::<B>(network, &netlink_handle).await
+        }
+        FirecrackerNetworkOperation::Delete => delete::<B>(network, &netlink_handle, NftRuleDeletion::Lookup).await,
+        FirecrackerNetworkOperation::Flush => {
+            run_network_type::<B>(network, netlink_handle, operation, NftRuleDeletion::Lookup).await
+        }
+    }
+}
+
+/// Delete a [FirecrackerNetwork] with a resolved host interface, with its nftables rules in the current netns either
+/// being looked up or having already been deleted by their handles.
+pub(crate) async fn delete<B: Backend>(
+    network: &FirecrackerNetwork,
+    netlink_handle: &rtnetlink::Handle,
+    deletion: NftRuleDeletion,
+) -> Result<(), FirecrackerNetworkError> {
+    // whether a flow rule shared by the ports of a bridge goes away depends on the ports left before deleting this one
+    let deletes_flow_rule = flowtable::deletes_flow_rule(network, netlink_handle, deletion).await?;
+    policy::delete_if_needed::<B>(network, netlink_handle, deletion).await?;
+    run_network_type::<B>(network, netlink_handle.clone(), FirecrackerNetworkOperation::Delete, deletion).await?;
+
+    if deletes_flow_rule {
+        flowtable::delete_if_needed::<B>(network).await?;
+    }
+
+    vlan::delete_if_unused::<B>(network, netlink_handle).await
+}
+
+#[allow(unused_variables)]
+async fn run_network_type<B: Backend>(
+    network: &FirecrackerNetwork,
+    netlink_handle: rtnetlink::Handle,
+    operation: FirecrackerNetworkOperation,
+    deletion: NftRuleDeletion,
+) -> Result<(), FirecrackerNetworkError> {
     match &network.network_type {
         #[cfg(feature = "simple")]
-        FirecrackerNetworkType::Simple => simple::run::<B>(network, netlink_handle, operation).await,
+        FirecrackerNetworkType::Simple => simple::run::<B>(network, netlink_handle, operation, deletion).await,
         #[cfg(feature = "namespaced")]
         FirecrackerNetworkType::Namespaced {
             netns_name: _,
@@ -108,6 +247,32 @@ pub async fn run<B: Backend>(
             veth1_ip: _,
             veth2_ip: _,
             forwarded_guest_ip: _,
-        } => namespaced::run::<B>(operation, network, netlink_handle).await,
+            netns_dir: _,
+            attach_netns: _,
+        } => namespaced::run::<B>(operation, network, netlink_handle, deletion).await,
+        #[cfg(feature = "routed")]
+        FirecrackerNetworkType::Routed { proxy_neighbour } => {
+            routed::run::<B>(network, netlink_handle, operation, *proxy_neighbour, deletion).await
+        }
+        #[cfg(feature = "bridge")]
+        FirecrackerNetworkType::Bridge { bridge_name } => bridge::run::<B>(network, netlink_handle, operation, bridge_name).await,
+        #[cfg(feature = "macvtap")]
+        FirecrackerNetworkType::Macvtap { mode } => macvtap::run(network, netlink_handle, operation, *mode).await,
+        #[cfg(feature = "ipvlan")]
+        FirecrackerNetworkType::Ipvlan => ipvlan::run::<B>(network, netlink_handle, operation).await,
     }
 }
+
+/// Resolve the device that the VMM should be directed to use for an added [FirecrackerNetwork] via the given [Backend]:
+/// the "/dev/tapN" character device for macvtap and ipvlan networks, or the name of the tap device otherwise.
+pub async fn get_vmm_device<B: Backend>(network: &FirecrackerNetwork) -> Result<String, FirecrackerNetworkError> {
+    #[cfg(any(feature = "macvtap", feature = "ipvlan"))]
+    if util::has_tap_char_device(network) {
+        let (connection, netlink_handle, _) =
+            rtnetlink::new_connection_with_socket::<B::NetlinkSocket>().map_err(FirecrackerNetworkError::IoError)?;
+        B::spawn_connection(connection);
+        return util::get_tap_char_device_path(network, &netlink_handle).await;
+    }
+
+    Ok(network.tap_name.clone())
+}
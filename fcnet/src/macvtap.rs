@@ -0,0 +1,57 @@
+use fcnet_types::{FirecrackerMacvtapMode, FirecrackerNetwork};
+use rtnetlink::{packet_route::link::MacVtapMode, LinkMacVtap};
+
+use crate::{
+    util::{check_stacked_link, get_link_index, parse_mac, FirecrackerNetworkExt},
+    FirecrackerNetworkError, FirecrackerNetworkOperation,
+};
+
+pub async fn run(
+    network: &FirecrackerNetwork,
+    netlink_handle: rtnetlink::Handle,
+    operation: FirecrackerNetworkOperation,
+    mode: FirecrackerMacvtapMode,
+) -> Result<(), FirecrackerNetworkError> {
+    match operation {
+        FirecrackerNetworkOperation::Add => add(network, netlink_handle, mode).await,
+        FirecrackerNetworkOperation::Check => check_stacked_link(network, &netlink_handle).await,
+        FirecrackerNetworkOperation::Delete => delete(network, netlink_handle).await,
+        // the guest's traffic bypasses the host's network stack, so there are no conntrack entries to flush
+        FirecrackerNetworkOperation::Flush => Ok(()),
+    }
+}
+
+async fn add(
+    network: &FirecrackerNetwork,
+    netlink_handle: rtnetlink::Handle,
+    mode: FirecrackerMacvtapMode,
+) -> Result<(), FirecrackerNetworkError> {
+    let iface_idx = get_link_index(network.uplink_name()?, &netlink_handle).await?;
+    let mode = match mode {
+        FirecrackerMacvtapMode::Bridge => MacVtapMode::Bridge,
+        FirecrackerMacvtapMode::Passthru => MacVtapMode::Passthrough,
+    };
+
+    // the guest uses the macvtap's own MAC, since frames to any other address aren't delivered to its character device
+    let mut link = LinkMacVtap::new(&network.tap_name, iface_idx, mode).up();
+    if let Some(guest_mac) = &network.guest_mac {
+        link = link.address(parse_mac(guest_mac)?.to_vec());
+    }
+
+    netlink_handle
+        .link()
+        .add(link.build())
+        .execute()
+        .await
+        .map_err(FirecrackerNetworkError::NetlinkOperationError)
+}
+
+async fn delete(network: &FirecrackerNetwork, netlink_handle: rtnetlink::Handle) -> Result<(), FirecrackerNetworkError> {
+    let macvtap_idx = get_link_index(network.tap_name.clone(), &netlink_handle).await?;
+    netlink_handle
+        .link()
+        .del(macvtap_idx)
+        .execute()
+        .await
+        .map_err(FirecrackerNetworkError::NetlinkOperationError)
+}
@@ -1,104 +1,62 @@
-use nftables::{
-    batch::Batch,
-    schema::{NfListObject, NfObject, Rule},
-};
-use nftables_async::helper::Helper;
-
 use crate::{
     backend::Backend,
-    netns::NetNs,
-    util::{FirecrackerNetworkExt, NO_NFT_ARGS},
-    FirecrackerNetwork, FirecrackerNetworkError, FirecrackerNetworkObjectType, NFT_FILTER_CHAIN, NFT_POSTROUTING_CHAIN,
-    NFT_TABLE,
+    conntrack,
+    rules::{self, NftRuleDeletion},
+    util::{get_link_index, FirecrackerNetworkExt},
+    FirecrackerNetwork, FirecrackerNetworkError,
 };
 
-use super::{outer_egress_forward_expr, outer_ingress_forward_expr, outer_masq_expr, NamespacedData};
+use super::{
+    flush::{flush_inner, flush_outer},
+    inner_rules, outer_rules, use_netns_in_thread, InnerNfData, NamespacedData,
+};
 
 pub(super) async fn delete<B: Backend>(
     namespaced_data: NamespacedData<'_>,
     network: &FirecrackerNetwork,
+    deletion: NftRuleDeletion,
 ) -> Result<(), FirecrackerNetworkError> {
-    NetNs::get(&namespaced_data.netns_name)
-        .map_err(FirecrackerNetworkError::NetnsError)?
-        .remove()
-        .map_err(FirecrackerNetworkError::NetnsError)?;
-
-    let current_ruleset = B::NftablesDriver::get_current_ruleset_with_args(network.nft_program(), NO_NFT_ARGS)
-        .await
-        .map_err(FirecrackerNetworkError::NftablesError)?;
-
-    let mut outer_masq_rule_handle = None;
-    let mut outer_ingress_forward_rule_handle = None;
-    let mut outer_egress_forward_rule_handle = None;
-
-    for object in current_ruleset.objects.iter() {
-        match object {
-            NfObject::ListObject(object) => match object {
-                NfListObject::Rule(rule) if rule.table == NFT_TABLE.to_string() => {
-                    if rule.chain == NFT_POSTROUTING_CHAIN && rule.expr == outer_masq_expr(network, &namespaced_data) {
-                        outer_masq_rule_handle = rule.handle;
-                    } else if rule.chain == NFT_FILTER_CHAIN {
-                        if rule.expr == outer_ingress_forward_expr(network, &namespaced_data) {
-                            outer_ingress_forward_rule_handle = rule.handle;
-                        } else if rule.expr == outer_egress_forward_expr(network, &namespaced_data) {
-                            outer_egress_forward_rule_handle = rule.handle;
-                        }
-                    }
-                }
-                _ => continue,
-            },
-            _ => continue,
-        }
+    conntrack::log_failed_delete_flush(flush_inner::<B>(&namespaced_data, network).await);
+
+    match namespaced_data.attach_netns {
+        true => delete_inner::<B>(&namespaced_data, network).await?,
+        // the netns outlives its removal for as long as the VMM is still running inside it
+        false => namespaced_data
+            .get_netns()?
+            .remove()
+            .map_err(FirecrackerNetworkError::NetnsError)?,
     }
 
-    if outer_masq_rule_handle.is_none() {
-        return Err(FirecrackerNetworkError::ObjectNotFound(
-            FirecrackerNetworkObjectType::NfMasqueradeRule,
-        ));
-    }
+    rules::delete_rules_unless_skipped::<B>(&network.rule_context(), &outer_rules(network, &namespaced_data)?, deletion).await?;
 
-    if outer_ingress_forward_rule_handle.is_none() {
-        return Err(FirecrackerNetworkError::ObjectNotFound(
-            FirecrackerNetworkObjectType::NfIngressForwardRule,
-        ));
-    }
-
-    if outer_egress_forward_rule_handle.is_none() {
-        return Err(FirecrackerNetworkError::ObjectNotFound(
-            FirecrackerNetworkObjectType::NfEgressForwardRule,
-        ));
-    }
+    conntrack::log_failed_delete_flush(flush_outer::<B>(&namespaced_data).await);
+    Ok(())
+}
 
-    let mut batch = Batch::new();
-    batch.delete(NfListObject::Rule(Rule {
-        family: network.nf_family(),
-        table: NFT_TABLE.into(),
-        chain: NFT_POSTROUTING_CHAIN.into(),
-        expr: outer_masq_expr(network, &namespaced_data).into(),
-        handle: outer_masq_rule_handle,
-        index: None,
-        comment: None,
-    }));
-    batch.delete(NfListObject::Rule(Rule {
-        family: network.nf_family(),
-        table: NFT_TABLE.into(),
-        chain: NFT_FILTER_CHAIN.into(),
-        expr: outer_ingress_forward_expr(network, &namespaced_data).into(),
-        handle: outer_ingress_forward_rule_handle,
-        index: None,
-        comment: None,
-    }));
-    batch.delete(NfListObject::Rule(Rule {
-        family: network.nf_family(),
-        table: NFT_TABLE.into(),
-        chain: NFT_FILTER_CHAIN.into(),
-        expr: outer_egress_forward_expr(network, &namespaced_data).into(),
-        handle: outer_egress_forward_rule_handle,
-        index: None,
-        comment: None,
-    }));
+/// Delete everything that was set up inside an attached netns, which isn't owned and thus can't simply be removed.
+/// Deleting the veth2 end of the pair also deletes veth1, while the netns's own routes are left untouched.
+async fn delete_inner<B: Backend>(
+    namespaced_data: &NamespacedData<'_>,
+    network: &FirecrackerNetwork,
+) -> Result<(), FirecrackerNetworkError> {
+    let tap_name = network.tap_name.clone();
+    let inner_nf_data = InnerNfData::new(network, namespaced_data);
+    use_netns_in_thread::<B, _, _>(namespaced_data.get_netns()?, async move {
+        let (connection, inner_handle, _) =
+            rtnetlink::new_connection_with_socket::<B::NetlinkSocket>().map_err(FirecrackerNetworkError::IoError)?;
+        B::spawn_connection(connection);
+
+        for link_name in [tap_name, inner_nf_data.veth2_name.clone()] {
+            let link_idx = get_link_index(link_name, &inner_handle).await?;
+            inner_handle
+                .link()
+                .del(link_idx)
+                .execute()
+                .await
+                .map_err(FirecrackerNetworkError::NetlinkOperationError)?;
+        }
 
-    B::NftablesDriver::apply_ruleset_with_args(&batch.to_nftables(), network.nft_program(), NO_NFT_ARGS)
-        .await
-        .map_err(FirecrackerNetworkError::NftablesError)
+        rules::delete_rules::<B>(&inner_nf_data.rule_context(), &inner_rules(&inner_nf_data)).await
+    })
+    .await
 }
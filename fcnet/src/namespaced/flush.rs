@@ -0,0 +1,28 @@
+use crate::{backend::Backend, conntrack, FirecrackerNetwork, FirecrackerNetworkError};
+
+use super::{use_netns_in_thread, NamespacedData};
+
+pub(super) async fn flush<B: Backend>(
+    namespaced_data: &NamespacedData<'_>,
+    network: &FirecrackerNetwork,
+) -> Result<(), FirecrackerNetworkError> {
+    flush_inner::<B>(namespaced_data, network).await?;
+    flush_outer::<B>(namespaced_data).await
+}
+
+pub(super) async fn flush_inner<B: Backend>(
+    namespaced_data: &NamespacedData<'_>,
+    network: &FirecrackerNetwork,
+) -> Result<(), FirecrackerNetworkError> {
+    let addresses = [network.guest_ip.address(), namespaced_data.veth2_ip.address()];
+    use_netns_in_thread::<B, _, _>(namespaced_data.get_netns()?, async move {
+        conntrack::flush::<B>(&addresses).await
+    })
+    .await
+}
+
+pub(super) async fn flush_outer<B: Backend>(namespaced_data: &NamespacedData<'_>) -> Result<(), FirecrackerNetworkError> {
+    let mut addresses = vec![namespaced_data.veth2_ip.address()];
+    addresses.extend(*namespaced_data.forwarded_guest_ip);
+    conntrack::flush::<B>(&addresses).await
+}
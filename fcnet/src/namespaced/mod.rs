@@ -1,14 +1,24 @@
-use std::net::IpAddr;
+use std::{borrow::Cow, net::IpAddr, os::unix::fs::MetadataExt, path::PathBuf};
 
 use cidr::IpInet;
+use fcnet_types::{FirecrackerNftConfig, FirecrackerPortForward, FirecrackerRuleBackend};
 use nftables::{
+    batch::Batch,
     expr::{Expression, Meta, MetaKey, NamedExpression, Payload, PayloadField},
-    stmt::{Match, NATFamily, Operator, Statement, NAT},
+    schema::Nftables,
+    stmt::{Match, Operator, Statement, NAT},
     types::NfFamily,
 };
 
 use crate::{
-    backend::Backend, util::nat_proto_from_addr, FirecrackerNetwork, FirecrackerNetworkError, FirecrackerNetworkOperation,
+    backend::Backend,
+    netns::{CustomNetNsEnvironment, NetNs, NetNsEnvironment, DEFAULT_PERSIST_DIR},
+    rules::{add_nft_rules_to_batch, nft_rules_batch, NetworkRule, NftRuleDeletion, RuleChain, RuleContext},
+    util::{
+        egress_nat_statement, get_link_identity, nat_family_from_addr, nat_ip_dnat_expr, nat_proto_from_addr,
+        port_forward_dnat_expr, transport_proto_from_port_forward, FirecrackerNetworkExt,
+    },
+    FirecrackerNetwork, FirecrackerNetworkError, FirecrackerNetworkObjectType, FirecrackerNetworkOperation,
     FirecrackerNetworkType,
 };
 use std::future::Future;
@@ -19,6 +29,8 @@ mod check;
 use check::check;
 mod delete;
 use delete::delete;
+mod flush;
+use flush::flush;
 
 struct NamespacedData<'a> {
     netns_name: &'a str,
@@ -27,48 +39,206 @@ struct NamespacedData<'a> {
     veth1_ip: &'a IpInet,
     veth2_ip: &'a IpInet,
     forwarded_guest_ip: &'a Option<IpAddr>,
+    netns_dir: Option<&'a str>,
+    attach_netns: bool,
+}
+
+impl<'a> NamespacedData<'a> {
+    fn new(network: &'a FirecrackerNetwork) -> Self {
+        match network.network_type {
+            #[cfg(feature = "simple")]
+            FirecrackerNetworkType::Simple => unreachable!(),
+            #[cfg(feature = "routed")]
+            FirecrackerNetworkType::Routed { .. } => unreachable!(),
+            #[cfg(feature = "bridge")]
+            FirecrackerNetworkType::Bridge { .. } => unreachable!(),
+            #[cfg(feature = "macvtap")]
+            FirecrackerNetworkType::Macvtap { .. } => unreachable!(),
+            #[cfg(feature = "ipvlan")]
+            FirecrackerNetworkType::Ipvlan => unreachable!(),
+            FirecrackerNetworkType::Namespaced {
+                ref netns_name,
+                ref veth1_name,
+                ref veth2_name,
+                ref veth1_ip,
+                ref veth2_ip,
+                ref forwarded_guest_ip,
+                ref netns_dir,
+                attach_netns,
+            } => NamespacedData {
+                netns_name,
+                veth1_name,
+                veth2_name,
+                veth1_ip,
+                veth2_ip,
+                forwarded_guest_ip,
+                netns_dir: netns_dir.as_deref(),
+                attach_netns,
+            },
+        }
+    }
+
+    fn netns_env(&self) -> CustomNetNsEnvironment {
+        CustomNetNsEnvironment(PathBuf::from(self.netns_dir.unwrap_or(DEFAULT_PERSIST_DIR)))
+    }
+
+    /// Open the existing netns, which is either persisted in the netns directory or attached to.
+    fn get_netns(&self) -> Result<NetNs<CustomNetNsEnvironment>, FirecrackerNetworkError> {
+        NetNs::get_from_env(self.netns_name, self.netns_env()).map_err(FirecrackerNetworkError::NetnsError)
+    }
+}
+
+/// An owned copy of the data needed to operate on the rules inside the netns, which is moved into the separate
+/// thread that enters the netns.
+struct InnerNfData {
+    nf_family: NfFamily,
+    nft_path: Option<String>,
+    nft_config: FirecrackerNftConfig,
+    rule_backend: FirecrackerRuleBackend,
+    veth2_name: String,
+    veth2_ip: IpInet,
+    guest_ip: IpInet,
+    forwarded_guest_ip: Option<IpAddr>,
+    nat_ip: Option<IpAddr>,
+    port_forwards: Vec<FirecrackerPortForward>,
+}
+
+impl InnerNfData {
+    fn new(network: &FirecrackerNetwork, namespaced_data: &NamespacedData) -> Self {
+        Self {
+            nf_family: network.nf_family(),
+            nft_path: network.nft_path.clone(),
+            nft_config: network.nft_config.clone(),
+            rule_backend: network.rule_backend.clone(),
+            veth2_name: namespaced_data.veth2_name.to_string(),
+            veth2_ip: *namespaced_data.veth2_ip,
+            guest_ip: network.guest_ip,
+            forwarded_guest_ip: *namespaced_data.forwarded_guest_ip,
+            nat_ip: network.nat_ip,
+            port_forwards: network.port_forwards.clone(),
+        }
+    }
+
+    #[inline]
+    fn rule_context(&self) -> RuleContext<'_> {
+        // nothing is forwarded inside the netns, where the prerouting chain is only needed for any kind of forwarding
+        RuleContext {
+            nf_family: self.nf_family,
+            nft_path: self.nft_path.as_deref(),
+            nft_config: &self.nft_config,
+            rule_backend: &self.rule_backend,
+            needs_prerouting_chain: self.forwarded_guest_ip.is_some() || self.nat_ip.is_some() || !self.port_forwards.is_empty(),
+            needs_mangle_chain: false,
+            needs_filter_chain: false,
+        }
+    }
 }
 
 pub async fn run<B: Backend>(
     operation: FirecrackerNetworkOperation,
     network: &FirecrackerNetwork,
     netlink_handle: rtnetlink::Handle,
+    deletion: NftRuleDeletion,
 ) -> Result<(), FirecrackerNetworkError> {
-    let namespaced_data = match network.network_type {
-        #[cfg(feature = "simple")]
-        FirecrackerNetworkType::Simple => unreachable!(),
-        FirecrackerNetworkType::Namespaced {
-            ref netns_name,
-            ref veth1_name,
-            ref veth2_name,
-            ref veth1_ip,
-            ref veth2_ip,
-            ref forwarded_guest_ip,
-        } => NamespacedData {
-            netns_name,
-            veth1_name,
-            veth2_name,
-            veth1_ip,
-            veth2_ip,
-            forwarded_guest_ip,
-        },
-    };
+    let namespaced_data = NamespacedData::new(network);
 
     match operation {
         FirecrackerNetworkOperation::Add => add::<B>(namespaced_data, network, netlink_handle).await,
         FirecrackerNetworkOperation::Check => check::<B>(namespaced_data, network, netlink_handle).await,
-        FirecrackerNetworkOperation::Delete => delete::<B>(namespaced_data, network).await,
+        FirecrackerNetworkOperation::Delete => delete::<B>(namespaced_data, network, deletion).await,
+        FirecrackerNetworkOperation::Flush => flush::<B>(&namespaced_data, network).await,
     }
 }
 
-#[cfg(feature = "namespaced")]
-async fn use_netns_in_thread<B: Backend>(
-    netns_name: String,
-    future: impl 'static + Send + Future<Output = Result<(), FirecrackerNetworkError>>,
-) -> Result<(), FirecrackerNetworkError> {
-    use crate::netns::NetNs;
+/// The rules outside the netns, which are applied along with the network's other rules.
+pub fn network_rules(network: &FirecrackerNetwork) -> Result<Vec<NetworkRule>, FirecrackerNetworkError> {
+    outer_rules(network, &NamespacedData::new(network))
+}
+
+/// Add the base chains and rules inside the netns to the batch, judging by an empty ruleset since adding the network
+/// creates the netns along with them.
+fn add_inner_rules_to_batch(network: &FirecrackerNetwork, batch: &mut Batch<'static>) -> Result<(), FirecrackerNetworkError> {
+    let inner_nf_data = InnerNfData::new(network, &NamespacedData::new(network));
+    add_nft_rules_to_batch(
+        &inner_nf_data.rule_context(),
+        &Nftables {
+            objects: Cow::Borrowed(&[]),
+        },
+        &inner_rules(&inner_nf_data),
+        batch,
+    )
+}
 
-    let netns = NetNs::get(netns_name).map_err(FirecrackerNetworkError::NetnsError)?;
+/// The nftables batch that the operation makes inside the netns with the nftables rule backend, or [None] if it
+/// makes no changes there. A created netns is judged by an empty ruleset, while the rules inside an attached netns are
+/// looked up there just like when running the operation. Deleting a created netns removes it along with its rules.
+pub async fn inner_nft_changes<B: Backend>(
+    network: &FirecrackerNetwork,
+    operation: FirecrackerNetworkOperation,
+) -> Result<Option<Nftables<'static>>, FirecrackerNetworkError> {
+    let namespaced_data = NamespacedData::new(network);
+
+    if let FirecrackerRuleBackend::Iptables { .. } = network.rule_backend {
+        return Ok(None);
+    }
+
+    match (operation, namespaced_data.attach_netns) {
+        (FirecrackerNetworkOperation::Add, false) => {
+            let mut batch = Batch::new();
+            add_inner_rules_to_batch(network, &mut batch)?;
+            Ok(Some(batch.to_nftables()))
+        }
+        (FirecrackerNetworkOperation::Add | FirecrackerNetworkOperation::Delete, true) => {
+            let inner_nf_data = InnerNfData::new(network, &namespaced_data);
+            use_netns_in_thread::<B, _, _>(namespaced_data.get_netns()?, async move {
+                let batch = nft_rules_batch::<B>(&inner_nf_data.rule_context(), &inner_rules(&inner_nf_data), operation).await?;
+                Ok(Some(batch.to_nftables()))
+            })
+            .await
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Get the index and MAC address of the tap device inside the netns, by its index if known.
+pub async fn get_inner_tap_identity<B: Backend>(
+    network: &FirecrackerNetwork,
+    tap_index: Option<u32>,
+) -> Result<(u32, Option<String>), FirecrackerNetworkError> {
+    let tap_name = network.tap_name.clone();
+    use_netns_in_thread::<B, _, _>(NamespacedData::new(network).get_netns()?, async move {
+        let (connection, netlink_handle, _) =
+            rtnetlink::new_connection_with_socket::<B::NetlinkSocket>().map_err(FirecrackerNetworkError::IoError)?;
+        B::spawn_connection(connection);
+        get_link_identity(&tap_name, tap_index, &netlink_handle).await
+    })
+    .await
+}
+
+/// Get the inode of the netns, which identifies it across being recreated under the same name.
+pub fn get_netns_inode(network: &FirecrackerNetwork) -> Result<u64, FirecrackerNetworkError> {
+    NamespacedData::new(network)
+        .get_netns()?
+        .file()
+        .metadata()
+        .map(|metadata| metadata.ino())
+        .map_err(FirecrackerNetworkError::IoError)
+}
+
+/// Run a future inside the given [NetNs] via the given [Backend], on a separate thread that enters the netns and
+/// drives the future to completion, so that netlink connections or sockets opened by the future operate on the netns
+/// while the calling thread stays in its own.
+pub async fn run_in_netns<B: Backend, E: 'static + NetNsEnvironment + Send, T: 'static + Send>(
+    netns: NetNs<E>,
+    future: impl 'static + Send + Future<Output = Result<T, FirecrackerNetworkError>>,
+) -> Result<T, FirecrackerNetworkError> {
+    use_netns_in_thread::<B, E, T>(netns, future).await
+}
+
+async fn use_netns_in_thread<B: Backend, E: 'static + NetNsEnvironment + Send, T: 'static + Send>(
+    netns: NetNs<E>,
+    future: impl 'static + Send + Future<Output = Result<T, FirecrackerNetworkError>>,
+) -> Result<T, FirecrackerNetworkError> {
     let (sender, receiver) = futures_channel::oneshot::channel();
 
     std::thread::spawn(move || {
@@ -86,9 +256,118 @@ async fn use_netns_in_thread<B: Backend>(
     }
 }
 
+fn outer_rules(
+    network: &FirecrackerNetwork,
+    namespaced_data: &NamespacedData,
+) -> Result<Vec<NetworkRule>, FirecrackerNetworkError> {
+    let mut rules = vec![
+        // masquerade veth packets as host iface packets
+        NetworkRule {
+            chain: RuleChain::Postrouting,
+            expr: outer_masq_expr(network, namespaced_data)?,
+            object_type: FirecrackerNetworkObjectType::NfMasqueradeRule,
+        },
+        // forward ingress packets from host iface to veth
+        NetworkRule {
+            chain: RuleChain::Filter,
+            expr: outer_ingress_forward_expr(network, namespaced_data)?,
+            object_type: FirecrackerNetworkObjectType::NfIngressForwardRule,
+        },
+        // forward egress packets from veth to host iface
+        NetworkRule {
+            chain: RuleChain::Filter,
+            expr: outer_egress_forward_expr(network, namespaced_data)?,
+            object_type: FirecrackerNetworkObjectType::NfEgressForwardRule,
+        },
+    ];
+
+    // DNAT packets arriving to the forwarded host ports to the veth2 ip, from where they are then resolved via
+    // DNAT to the guest ip inside the netns. The existing ingress forward rule already accepts them
+    for port_forward in &network.port_forwards {
+        rules.push(NetworkRule {
+            chain: RuleChain::Prerouting,
+            expr: outer_dnat_expr(network, namespaced_data, port_forward)?,
+            object_type: FirecrackerNetworkObjectType::NfIngressDnatRule,
+        });
+    }
+
+    // DNAT packets arriving to the dedicated NAT ip to the veth2 ip, from where they are then resolved via DNAT
+    // to the guest ip inside the netns. This comes after the port forwards, which would otherwise be shadowed if
+    // forwarding from the NAT ip
+    if let Some(nat_ip) = network.nat_ip {
+        rules.push(NetworkRule {
+            chain: RuleChain::Prerouting,
+            expr: outer_nat_dnat_expr(network, namespaced_data, nat_ip)?,
+            object_type: FirecrackerNetworkObjectType::NfIngressDnatRule,
+        });
+    }
+
+    Ok(rules)
+}
+
+fn inner_rules(inner_nf_data: &InnerNfData) -> Vec<NetworkRule> {
+    let InnerNfData {
+        nf_family,
+        ref veth2_name,
+        veth2_ip,
+        guest_ip,
+        forwarded_guest_ip,
+        nat_ip,
+        ref port_forwards,
+        ..
+    } = *inner_nf_data;
+
+    // SNAT packets coming from the guest ip to the veth2 ip so that outer netns forwards them not from the
+    // guest ip local to the inner netns, but from the known veth2 ip
+    let mut rules = vec![NetworkRule {
+        chain: RuleChain::Postrouting,
+        expr: inner_snat_expr(veth2_name.clone(), guest_ip, veth2_ip, nf_family),
+        object_type: FirecrackerNetworkObjectType::NfEgressSnatRule,
+    }];
+
+    // DNAT packets coming to the forwarded guest ip via a route in the outer netns to the actual guest
+    // ip local to the inner netns
+    if let Some(forwarded_guest_ip) = forwarded_guest_ip {
+        rules.push(NetworkRule {
+            chain: RuleChain::Prerouting,
+            expr: inner_dnat_expr(veth2_name.clone(), forwarded_guest_ip, guest_ip, nf_family, None),
+            object_type: FirecrackerNetworkObjectType::NfIngressDnatRule,
+        });
+    }
+
+    // DNAT packets coming to the forwarded ports of the veth2 ip via the outer DNAT to the actual guest ip
+    for port_forward in port_forwards {
+        rules.push(NetworkRule {
+            chain: RuleChain::Prerouting,
+            expr: inner_dnat_expr(
+                veth2_name.clone(),
+                veth2_ip.address(),
+                guest_ip,
+                nf_family,
+                Some(port_forward),
+            ),
+            object_type: FirecrackerNetworkObjectType::NfIngressDnatRule,
+        });
+    }
+
+    // DNAT all packets coming to the veth2 ip via the outer 1:1 NAT to the actual guest ip
+    if nat_ip.is_some() {
+        rules.push(NetworkRule {
+            chain: RuleChain::Prerouting,
+            expr: inner_dnat_expr(veth2_name.clone(), veth2_ip.address(), guest_ip, nf_family, None),
+            object_type: FirecrackerNetworkObjectType::NfIngressDnatRule,
+        });
+    }
+
+    rules
+}
+
 #[inline]
-fn outer_masq_expr(network: &FirecrackerNetwork, namespaced_data: &NamespacedData) -> Vec<Statement<'static>> {
-    vec![
+fn outer_masq_expr(
+    network: &FirecrackerNetwork,
+    namespaced_data: &NamespacedData,
+) -> Result<Vec<Statement<'static>>, FirecrackerNetworkError> {
+    Ok(vec![
         Statement::Match(Match {
             left: Expression::Named(NamedExpression::Payload(Payload::PayloadField(PayloadField {
                 protocol: nat_proto_from_addr(namespaced_data.veth2_ip.address()),
@@ -99,19 +378,36 @@ fn outer_masq_expr(network: &FirecrackerNetwork, namespaced_data: &NamespacedDat
         }),
         Statement::Match(Match {
             left: Expression::Named(NamedExpression::Meta(Meta { key: MetaKey::Oifname })),
-            right: Expression::String(network.iface_name.clone().into()),
+            right: Expression::String(network.uplink_name()?.into()),
             op: Operator::EQ,
         }),
-        Statement::Masquerade(None),
-    ]
+        egress_nat_statement(network),
+    ])
 }
 
 #[inline]
-fn outer_ingress_forward_expr(network: &FirecrackerNetwork, namespaced_data: &NamespacedData) -> Vec<Statement<'static>> {
-    vec![
+fn outer_nat_dnat_expr(
+    network: &FirecrackerNetwork,
+    namespaced_data: &NamespacedData,
+    nat_ip: IpAddr,
+) -> Result<Vec<Statement<'static>>, FirecrackerNetworkError> {
+    Ok(nat_ip_dnat_expr(
+        &network.uplink_name()?,
+        nat_ip,
+        namespaced_data.veth2_ip.address(),
+        network.nf_family(),
+    ))
+}
+
+#[inline]
+fn outer_ingress_forward_expr(
+    network: &FirecrackerNetwork,
+    namespaced_data: &NamespacedData,
+) -> Result<Vec<Statement<'static>>, FirecrackerNetworkError> {
+    Ok(vec![
         Statement::Match(Match {
             left: Expression::Named(NamedExpression::Meta(Meta { key: MetaKey::Iifname })),
-            right: Expression::String(network.iface_name.clone().into()),
+            right: Expression::String(network.uplink_name()?.into()),
             op: Operator::EQ,
         }),
         Statement::Match(Match {
@@ -120,15 +416,18 @@ fn outer_ingress_forward_expr(network: &FirecrackerNetwork, namespaced_data: &Na
             op: Operator::EQ,
         }),
         Statement::Accept(None),
-    ]
+    ])
 }
 
 #[inline]
-fn outer_egress_forward_expr(network: &FirecrackerNetwork, namespaced_data: &NamespacedData) -> Vec<Statement<'static>> {
-    vec![
+fn outer_egress_forward_expr(
+    network: &FirecrackerNetwork,
+    namespaced_data: &NamespacedData,
+) -> Result<Vec<Statement<'static>>, FirecrackerNetworkError> {
+    Ok(vec![
         Statement::Match(Match {
             left: Expression::Named(NamedExpression::Meta(Meta { key: MetaKey::Oifname })),
-            right: Expression::String(network.iface_name.clone().into()),
+            right: Expression::String(network.uplink_name()?.into()),
             op: Operator::EQ,
         }),
         Statement::Match(Match {
@@ -137,7 +436,7 @@ fn outer_egress_forward_expr(network: &FirecrackerNetwork, namespaced_data: &Nam
             op: Operator::EQ,
         }),
         Statement::Accept(None),
-    ]
+    ])
 }
 
 #[inline]
@@ -159,7 +458,7 @@ fn inner_snat_expr(veth2_name: String, guest_ip: IpInet, veth2_ip: IpInet, nf_fa
         Statement::SNAT(Some(NAT {
             addr: Some(Expression::String(veth2_ip.address().to_string().into())),
             family: match nf_family {
-                NfFamily::INet => Some(nat_family_from_inet(&veth2_ip)),
+                NfFamily::INet => Some(nat_family_from_addr(veth2_ip.address())),
                 _ => None,
             },
             port: None,
@@ -168,14 +467,29 @@ fn inner_snat_expr(veth2_name: String, guest_ip: IpInet, veth2_ip: IpInet, nf_fa
     ]
 }
 
+#[inline]
+fn outer_dnat_expr(
+    network: &FirecrackerNetwork,
+    namespaced_data: &NamespacedData,
+    port_forward: &FirecrackerPortForward,
+) -> Result<Vec<Statement<'static>>, FirecrackerNetworkError> {
+    Ok(port_forward_dnat_expr(
+        &network.uplink_name()?,
+        port_forward,
+        namespaced_data.veth2_ip.address(),
+        network.nf_family(),
+    ))
+}
+
 #[inline]
 fn inner_dnat_expr(
     veth2_name: String,
     forwarded_guest_ip: IpAddr,
     guest_ip: IpInet,
     nf_family: NfFamily,
+    port_forward: Option<&FirecrackerPortForward>,
 ) -> Vec<Statement<'static>> {
-    vec![
+    let mut expr = vec![
         Statement::Match(Match {
             left: Expression::Named(NamedExpression::Meta(Meta { key: MetaKey::Iifname })),
             right: Expression::String(veth2_name.into()),
@@ -189,22 +503,28 @@ fn inner_dnat_expr(
             right: Expression::String(forwarded_guest_ip.to_string().into()),
             op: Operator::EQ,
         }),
-        Statement::DNAT(Some(NAT {
-            addr: Some(Expression::String(guest_ip.address().to_string().into())),
-            family: match nf_family {
-                NfFamily::INet => Some(nat_family_from_inet(&guest_ip)),
-                _ => None,
-            },
-            port: None,
-            flags: None,
-        })),
-    ]
-}
+    ];
 
-#[inline]
-fn nat_family_from_inet(inet: &IpInet) -> NATFamily {
-    match inet {
-        IpInet::V4(_) => NATFamily::IP,
-        IpInet::V6(_) => NATFamily::IP6,
+    // port forwards arrive already translated to the guest port by the outer DNAT, so only the address is rewritten
+    if let Some(port_forward) = port_forward {
+        expr.push(Statement::Match(Match {
+            left: Expression::Named(NamedExpression::Payload(Payload::PayloadField(PayloadField {
+                protocol: transport_proto_from_port_forward(port_forward),
+                field: "dport".into(),
+            }))),
+            right: Expression::Number(port_forward.guest_port.into()),
+            op: Operator::EQ,
+        }));
     }
+
+    expr.push(Statement::DNAT(Some(NAT {
+        addr: Some(Expression::String(guest_ip.address().to_string().into())),
+        family: match nf_family {
+            NfFamily::INet => Some(nat_family_from_addr(guest_ip.address())),
+            _ => None,
+        },
+        port: None,
+        flags: None,
+    })));
+    expr
 }
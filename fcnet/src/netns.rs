@@ -1,14 +1,20 @@
 /// Code originating from https://github.com/openanolis/netns-rs, was vendored in
 /// to update nix crate and remove unnecessary functionality in the scope of this library.
 use std::fs::File;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd};
 use std::os::unix::io::IntoRawFd;
+use std::os::unix::process::CommandExt;
 use std::path::{Path, PathBuf};
+use std::process::Command;
 use std::thread::{self, JoinHandle};
 
 use nix::mount::{mount, umount2, MntFlags, MsFlags};
 use nix::sched::{setns, unshare, CloneFlags};
 use nix::unistd::gettid;
 
+/// The directory network namespaces are persisted in by default.
+pub const DEFAULT_PERSIST_DIR: &str = "/var/run/netns";
+
 /// An error that can occur in the network namespace backend.
 #[derive(Debug)]
 pub enum NetNsError {
@@ -21,6 +27,7 @@ pub enum NetNsError {
     UnshareError(nix::Error),
     JoinThreadError(String),
     SetnsError(nix::Error),
+    DuplicateFdError(std::io::Error),
 }
 
 impl std::error::Error for NetNsError {}
@@ -37,10 +44,12 @@ impl std::fmt::Display for NetNsError {
             NetNsError::UnshareError(err) => write!(f, "Failed to unshare: {err}"),
             NetNsError::JoinThreadError(detail) => write!(f, "Failed to join thread: {detail}"),
             NetNsError::SetnsError(err) => write!(f, "Cannot setns: {err}"),
+            NetNsError::DuplicateFdError(err) => write!(f, "Cannot duplicate netns file descriptor: {err}"),
         }
     }
 }
 
+/// The environment determining where network namespaces are persisted.
 pub trait NetNsEnvironment {
     fn persist_dir(&self) -> PathBuf;
 
@@ -87,15 +96,27 @@ pub trait NetNsEnvironment {
     }
 }
 
+/// The [NetNsEnvironment] persisting network namespaces in "/var/run/netns", like iproute2 does.
 #[derive(Copy, Clone, Default, Debug)]
 pub struct DefaultNetNsEnvironment;
 
 impl NetNsEnvironment for DefaultNetNsEnvironment {
     fn persist_dir(&self) -> PathBuf {
-        PathBuf::from("/var/run/netns")
+        PathBuf::from(DEFAULT_PERSIST_DIR)
+    }
+}
+
+/// A [NetNsEnvironment] persisting network namespaces in a custom directory, for example inside a jailer's chroot.
+#[derive(Clone, Debug)]
+pub struct CustomNetNsEnvironment(pub PathBuf);
+
+impl NetNsEnvironment for CustomNetNsEnvironment {
+    fn persist_dir(&self) -> PathBuf {
+        self.0.clone()
     }
 }
 
+/// An open network namespace persisted in a [NetNsEnvironment].
 #[derive(Debug)]
 pub struct NetNs<E: NetNsEnvironment = DefaultNetNsEnvironment> {
     file: File,
@@ -110,11 +131,10 @@ impl<E: NetNsEnvironment> NetNs<E> {
         // create an empty file at the mount point
         let ns_path = env.persist_dir().join(ns_name.as_ref());
         let _ = File::create(&ns_path).map_err(NetNsError::CreateNsError)?;
-        Self::persistent(&ns_path, true).map_err(|e| {
+        Self::persistent(&ns_path, true).inspect_err(|_| {
             // Ensure the mount point is cleaned up on errors; if the namespace was successfully
             // mounted this will have no effect because the file is in-use
             std::fs::remove_file(&ns_path).ok();
-            e
         })?;
         Self::get_from_env(ns_name, env)
     }
@@ -124,11 +144,7 @@ impl<E: NetNsEnvironment> NetNs<E> {
             let ns_path_clone = ns_path.as_ref().to_path_buf();
             let new_thread: JoinHandle<Result<(), NetNsError>> = thread::spawn(move || Self::persistent(&ns_path_clone, false));
             match new_thread.join() {
-                Ok(t) => {
-                    if let Err(e) = t {
-                        return Err(e);
-                    }
-                }
+                Ok(t) => t?,
                 Err(e) => {
                     return Err(NetNsError::JoinThreadError(format!("{:?}", e)));
                 }
@@ -156,10 +172,31 @@ impl<E: NetNsEnvironment> NetNs<E> {
         &self.file
     }
 
+    /// The path the netns is persisted at, which can be passed to e.g. the jailer's "--netns" argument.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Move the current thread into the netns.
     pub fn enter(&self) -> Result<(), NetNsError> {
         setns(&self.file, CloneFlags::CLONE_NEWNET).map_err(NetNsError::SetnsError)
     }
 
+    /// Make the given [Command] spawn its process inside the netns, for example in order to launch Firecracker or the
+    /// jailer next to the tap device of a namespaced network. The netns is entered in the forked child right before
+    /// the executable is run, so the calling thread stays in its own netns.
+    pub fn apply_to_command(&self, command: &mut Command) -> Result<(), NetNsError> {
+        let fd = self.file.try_clone().map_err(NetNsError::DuplicateFdError)?;
+
+        // SAFETY: setns is a plain syscall that doesn't allocate or take locks, so it's safe to call between fork and
+        // exec, and the duplicated file descriptor is close-on-exec and lives as long as the command
+        unsafe {
+            command.pre_exec(move || setns(&fd, CloneFlags::CLONE_NEWNET).map_err(std::io::Error::from));
+        }
+
+        Ok(())
+    }
+
     pub fn get_from_env<S: AsRef<str>>(ns_name: S, env: E) -> Result<Self, NetNsError> {
         let ns_path = env.persist_dir().join(ns_name.as_ref());
         let file = File::open(&ns_path).map_err(|e| NetNsError::OpenNsError(ns_path.clone(), e))?;
@@ -192,11 +229,25 @@ impl<E: NetNsEnvironment> NetNs<E> {
     }
 }
 
+impl<E: NetNsEnvironment> AsFd for NetNs<E> {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.file.as_fd()
+    }
+}
+
+impl<E: NetNsEnvironment> AsRawFd for NetNs<E> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
 impl NetNs {
+    /// Create a netns with the given name in the [DefaultNetNsEnvironment].
     pub fn new<S: AsRef<str>>(ns_name: S) -> Result<Self, NetNsError> {
         Self::new_with_env(ns_name, DefaultNetNsEnvironment)
     }
 
+    /// Open an existing netns with the given name in the [DefaultNetNsEnvironment].
     pub fn get<S: AsRef<str>>(ns_name: S) -> Result<Self, NetNsError> {
         Self::get_from_env(ns_name, DefaultNetNsEnvironment)
     }
@@ -0,0 +1,361 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use fcnet_types::{FirecrackerNetwork, FirecrackerPolicyRoute};
+use futures_util::TryStreamExt;
+use nftables::{
+    expr::{Expression, Meta, MetaKey, NamedExpression, Payload, PayloadField},
+    schema::{NfListObject, NfObject},
+    stmt::{Mangle, Match, Operator, Statement},
+};
+use nftables_async::helper::Helper;
+use rtnetlink::{
+    packet_route::{
+        route::{RouteAttribute, RouteMessage},
+        rule::{RuleAction, RuleAttribute, RuleMessage},
+    },
+    IpVersion, RouteMessageBuilder,
+};
+
+use crate::{
+    backend::Backend,
+    rules::{ensure_nftables, NetworkRule, NftRuleDeletion, NftablesRuleBackend, RuleBackend, RuleChain},
+    util::{get_link_index, nat_proto_from_addr, FirecrackerNetworkExt, NO_NFT_ARGS},
+    FirecrackerNetworkError, FirecrackerNetworkObjectType,
+};
+
+/// Ensure that policy routing isn't combined with the iptables rule backend, since its mark rule can only live in the
+/// nftables table and mangle chain that this backend doesn't create.
+pub fn validate(network: &FirecrackerNetwork) -> Result<(), FirecrackerNetworkError> {
+    match network.policy_route {
+        Some(_) => ensure_nftables(&network.rule_backend, "policy routing"),
+        None => Ok(()),
+    }
+}
+
+/// Add the policy routing of the network. The default route of the routing table and the ip rule directing the fwmark
+/// to it are shared by all networks using the same table and fwmark, so they're only added if they're missing.
+pub async fn add_if_needed<B: Backend>(
+    network: &FirecrackerNetwork,
+    netlink_handle: &rtnetlink::Handle,
+) -> Result<(), FirecrackerNetworkError> {
+    let Some(policy_route) = network.policy_route else {
+        return Ok(());
+    };
+
+    if get_default_route(network, &policy_route, netlink_handle).await?.is_none() {
+        let uplink_idx = get_link_index(network.uplink_name()?, netlink_handle).await?;
+        netlink_handle
+            .route()
+            .add(default_route_message(network, &policy_route, uplink_idx)?)
+            .execute()
+            .await
+            .map_err(FirecrackerNetworkError::NetlinkOperationError)?;
+    }
+
+    if get_policy_rule(network, &policy_route, netlink_handle).await?.is_none() {
+        let rule_request = netlink_handle
+            .rule()
+            .add()
+            .fw_mark(policy_route.fwmark)
+            .table_id(policy_route.table_id)
+            .action(RuleAction::ToTable);
+        match egress_source_addr(network) {
+            IpAddr::V4(_) => rule_request.v4().execute().await,
+            IpAddr::V6(_) => rule_request.v6().execute().await,
+        }
+        .map_err(FirecrackerNetworkError::NetlinkOperationError)?;
+    }
+
+    NftablesRuleBackend::add_rules::<B>(&network.rule_context(), &[mark_rule(network, &policy_route)]).await
+}
+
+/// Delete the policy routing of the network. The shared ip rule is only deleted once no other mark rule sets its
+/// fwmark, and the shared default route only once no other ip rule directs packets to its table.
+pub async fn delete_if_needed<B: Backend>(
+    network: &FirecrackerNetwork,
+    netlink_handle: &rtnetlink::Handle,
+    deletion: NftRuleDeletion,
+) -> Result<(), FirecrackerNetworkError> {
+    let Some(policy_route) = network.policy_route else {
+        return Ok(());
+    };
+
+    if deletion == NftRuleDeletion::Lookup {
+        NftablesRuleBackend::delete_rules::<B>(&network.rule_context(), &[mark_rule(network, &policy_route)]).await?;
+    }
+
+    if fwmark_is_set_by_other_rules::<B>(network, &policy_route).await? {
+        return Ok(());
+    }
+
+    let rule_message = get_policy_rule(network, &policy_route, netlink_handle)
+        .await?
+        .ok_or(FirecrackerNetworkError::ObjectNotFound(FirecrackerNetworkObjectType::IpRule))?;
+    netlink_handle
+        .rule()
+        .del(rule_message)
+        .execute()
+        .await
+        .map_err(FirecrackerNetworkError::NetlinkOperationError)?;
+
+    if table_is_used_by_other_rules(network, &policy_route, netlink_handle).await? {
+        return Ok(());
+    }
+
+    let route_message = get_default_route(network, &policy_route, netlink_handle)
+        .await?
+        .ok_or(FirecrackerNetworkError::ObjectNotFound(FirecrackerNetworkObjectType::IpRoute))?;
+    netlink_handle
+        .route()
+        .del(route_message)
+        .execute()
+        .await
+        .map_err(FirecrackerNetworkError::NetlinkOperationError)
+}
+
+pub async fn check_if_needed<B: Backend>(
+    network: &FirecrackerNetwork,
+    netlink_handle: &rtnetlink::Handle,
+) -> Result<(), FirecrackerNetworkError> {
+    let Some(policy_route) = network.policy_route else {
+        return Ok(());
+    };
+
+    if get_policy_rule(network, &policy_route, netlink_handle).await?.is_none() {
+        return Err(FirecrackerNetworkError::ObjectNotFound(FirecrackerNetworkObjectType::IpRule));
+    }
+
+    if get_default_route(network, &policy_route, netlink_handle).await?.is_none() {
+        return Err(FirecrackerNetworkError::ObjectNotFound(FirecrackerNetworkObjectType::IpRoute));
+    }
+
+    NftablesRuleBackend::check_rules::<B>(&network.rule_context(), &[mark_rule(network, &policy_route)]).await
+}
+
+/// The rule marking the guest's egress packets in the mangle chain, so that they're routed via the dedicated table.
+pub fn mark_rule(network: &FirecrackerNetwork, policy_route: &FirecrackerPolicyRoute) -> NetworkRule {
+    NetworkRule {
+        chain: RuleChain::Mangle,
+        expr: mark_expr(network, policy_route),
+        object_type: FirecrackerNetworkObjectType::NfMarkRule,
+    }
+}
+
+pub async fn switch_default_route_if_needed(
+    from_network: &FirecrackerNetwork,
+    to_network: &FirecrackerNetwork,
+    netlink_handle: &rtnetlink::Handle,
+) -> Result<(), FirecrackerNetworkError> {
+    let Some(policy_route) = to_network.policy_route else {
+        return Ok(());
+    };
+
+    let route_message = get_default_route(from_network, &policy_route, netlink_handle)
+        .await?
+        .ok_or(FirecrackerNetworkError::ObjectNotFound(FirecrackerNetworkObjectType::IpRoute))?;
+    netlink_handle
+        .route()
+        .del(route_message)
+        .execute()
+        .await
+        .map_err(FirecrackerNetworkError::NetlinkOperationError)?;
+
+    let uplink_idx = get_link_index(to_network.uplink_name()?, netlink_handle).await?;
+    netlink_handle
+        .route()
+        .add(default_route_message(to_network, &policy_route, uplink_idx)?)
+        .execute()
+        .await
+        .map_err(FirecrackerNetworkError::NetlinkOperationError)
+}
+
+/// Whether any nftables rule in the ruleset sets the fwmark of the policy route, e.g. the mark rule of another network.
+async fn fwmark_is_set_by_other_rules<B: Backend>(
+    network: &FirecrackerNetwork,
+    policy_route: &FirecrackerPolicyRoute,
+) -> Result<bool, FirecrackerNetworkError> {
+    let current_ruleset = B::NftablesDriver::get_current_ruleset_with_args(network.nft_program(), NO_NFT_ARGS)
+        .await
+        .map_err(FirecrackerNetworkError::NftablesError)?;
+
+    Ok(current_ruleset.objects.iter().any(|object| match object {
+        NfObject::ListObject(NfListObject::Rule(rule)) => rule.expr.iter().any(|statement| {
+            matches!(
+                statement,
+                Statement::Mangle(Mangle {
+                    key: Expression::Named(NamedExpression::Meta(Meta { key: MetaKey::Mark })),
+                    value: Expression::Number(fwmark),
+                }) if *fwmark == policy_route.fwmark
+            )
+        }),
+        _ => false,
+    }))
+}
+
+/// Whether any ip rule of the network's IP version still directs packets to the table of the policy route.
+async fn table_is_used_by_other_rules(
+    network: &FirecrackerNetwork,
+    policy_route: &FirecrackerPolicyRoute,
+    netlink_handle: &rtnetlink::Handle,
+) -> Result<bool, FirecrackerNetworkError> {
+    let mut rule_message_stream = netlink_handle.rule().get(ip_version(network)).execute();
+
+    while let Some(rule_message) = rule_message_stream
+        .try_next()
+        .await
+        .map_err(FirecrackerNetworkError::NetlinkOperationError)?
+    {
+        if rule_table_id(&rule_message) == policy_route.table_id {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+async fn get_policy_rule(
+    network: &FirecrackerNetwork,
+    policy_route: &FirecrackerPolicyRoute,
+    netlink_handle: &rtnetlink::Handle,
+) -> Result<Option<RuleMessage>, FirecrackerNetworkError> {
+    let mut rule_message_stream = netlink_handle.rule().get(ip_version(network)).execute();
+
+    while let Some(rule_message) = rule_message_stream
+        .try_next()
+        .await
+        .map_err(FirecrackerNetworkError::NetlinkOperationError)?
+    {
+        let fwmark_matches = rule_message
+            .attributes
+            .iter()
+            .any(|attribute| matches!(attribute, RuleAttribute::FwMark(fwmark) if *fwmark == policy_route.fwmark));
+
+        if fwmark_matches && rule_table_id(&rule_message) == policy_route.table_id {
+            return Ok(Some(rule_message));
+        }
+    }
+
+    Ok(None)
+}
+
+#[inline]
+fn rule_table_id(rule_message: &RuleMessage) -> u32 {
+    // tables above 255 are only carried by the attribute
+    rule_message
+        .attributes
+        .iter()
+        .find_map(|attribute| match attribute {
+            RuleAttribute::Table(table) => Some(*table),
+            _ => None,
+        })
+        .unwrap_or(rule_message.header.table as u32)
+}
+
+#[inline]
+fn ip_version(network: &FirecrackerNetwork) -> IpVersion {
+    match egress_source_addr(network) {
+        IpAddr::V4(_) => IpVersion::V4,
+        IpAddr::V6(_) => IpVersion::V6,
+    }
+}
+
+async fn get_default_route(
+    network: &FirecrackerNetwork,
+    policy_route: &FirecrackerPolicyRoute,
+    netlink_handle: &rtnetlink::Handle,
+) -> Result<Option<RouteMessage>, FirecrackerNetworkError> {
+    let uplink_idx = get_link_index(network.uplink_name()?, netlink_handle).await?;
+    let route_filter = match egress_source_addr(network) {
+        IpAddr::V4(_) => RouteMessageBuilder::<Ipv4Addr>::new().table_id(policy_route.table_id).build(),
+        IpAddr::V6(_) => RouteMessageBuilder::<Ipv6Addr>::new().table_id(policy_route.table_id).build(),
+    };
+    let mut route_message_stream = netlink_handle.route().get(route_filter).execute();
+
+    while let Some(route_message) = route_message_stream
+        .try_next()
+        .await
+        .map_err(FirecrackerNetworkError::NetlinkOperationError)?
+    {
+        if route_message.header.destination_prefix_length != 0 {
+            continue;
+        }
+
+        let mut oif_matches = false;
+        let mut table_id = route_message.header.table as u32;
+
+        for attribute in &route_message.attributes {
+            match attribute {
+                RouteAttribute::Oif(oif) => oif_matches = *oif == uplink_idx,
+                RouteAttribute::Table(table) => table_id = *table,
+                _ => continue,
+            }
+        }
+
+        if oif_matches && table_id == policy_route.table_id {
+            return Ok(Some(route_message));
+        }
+    }
+
+    Ok(None)
+}
+
+/// The default route of the policy route's table, which sends the guest's egress traffic out of its uplink.
+#[inline]
+pub fn default_route_message(
+    network: &FirecrackerNetwork,
+    policy_route: &FirecrackerPolicyRoute,
+    uplink_idx: u32,
+) -> Result<RouteMessage, FirecrackerNetworkError> {
+    match (egress_source_addr(network), policy_route.gateway) {
+        (IpAddr::V4(_), None) => Ok(RouteMessageBuilder::<Ipv4Addr>::new()
+            .output_interface(uplink_idx)
+            .table_id(policy_route.table_id)
+            .build()),
+        (IpAddr::V4(_), Some(IpAddr::V4(gateway))) => Ok(RouteMessageBuilder::<Ipv4Addr>::new()
+            .output_interface(uplink_idx)
+            .gateway(gateway)
+            .table_id(policy_route.table_id)
+            .build()),
+        (IpAddr::V6(_), None) => Ok(RouteMessageBuilder::<Ipv6Addr>::new()
+            .output_interface(uplink_idx)
+            .table_id(policy_route.table_id)
+            .build()),
+        (IpAddr::V6(_), Some(IpAddr::V6(gateway))) => Ok(RouteMessageBuilder::<Ipv6Addr>::new()
+            .output_interface(uplink_idx)
+            .gateway(gateway)
+            .table_id(policy_route.table_id)
+            .build()),
+        _ => Err(FirecrackerNetworkError::ForbiddenDualStackInRoute),
+    }
+}
+
+#[inline]
+fn egress_source_addr(network: &FirecrackerNetwork) -> IpAddr {
+    // namespaced guests leave the netns already SNAT-ed to the second end of the veth pair
+    match network.network_type {
+        #[cfg(feature = "namespaced")]
+        fcnet_types::FirecrackerNetworkType::Namespaced { ref veth2_ip, .. } => veth2_ip.address(),
+        #[allow(unreachable_patterns)]
+        _ => network.guest_ip.address(),
+    }
+}
+
+#[inline]
+fn mark_expr(network: &FirecrackerNetwork, policy_route: &FirecrackerPolicyRoute) -> Vec<Statement<'static>> {
+    let source_addr = egress_source_addr(network);
+
+    vec![
+        Statement::Match(Match {
+            left: Expression::Named(NamedExpression::Payload(Payload::PayloadField(PayloadField {
+                protocol: nat_proto_from_addr(source_addr),
+                field: "saddr".into(),
+            }))),
+            right: Expression::String(source_addr.to_string().into()),
+            op: Operator::EQ,
+        }),
+        Statement::Mangle(Mangle {
+            key: Expression::Named(NamedExpression::Meta(Meta { key: MetaKey::Mark })),
+            value: Expression::Number(policy_route.fwmark),
+        }),
+    ]
+}
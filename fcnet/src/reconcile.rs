@@ -0,0 +1,147 @@
+use fcnet_types::{FirecrackerNetwork, FirecrackerNetworkOperation};
+
+use crate::{backend::Backend, run, run_with_hooks, FirecrackerNetworkError, FirecrackerNetworkHooks};
+
+/// What [apply] does to a [FirecrackerNetwork] in order to converge the host to the desired state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum FirecrackerNetworkAction {
+    /// Delete a previously applied network that is no longer desired.
+    Delete,
+    /// Delete and re-add a desired network that was applied before, but no longer passes a check.
+    Repair,
+    /// Add a desired network that wasn't applied before and either doesn't pass a check or replaces a deleted network.
+    Create,
+}
+
+/// A single step of the plan produced by [plan].
+#[derive(Debug)]
+pub struct FirecrackerNetworkChange {
+    /// The action to take.
+    pub action: FirecrackerNetworkAction,
+    /// The network to take the action on.
+    pub network: FirecrackerNetwork,
+    /// The error of the failed check that made a creation or a repair necessary, which is absent for deletions and for
+    /// creations that replace a deleted network.
+    pub reason: Option<FirecrackerNetworkError>,
+}
+
+/// Plan the changes that converge the host from the previously applied [FirecrackerNetwork]s to the desired ones via
+/// the given [Backend], by checking the live state of every desired network. Networks are identified by equality, so
+/// a network whose configuration changed is deleted and created anew. Deletions are planned first, so that names
+/// freed by them can be taken by creations, and desired networks that pass a check are adopted without changes.
+/// A desired network that replaces a deleted one with the same tap name isn't checked, since the check would observe
+/// the objects of the deleted network, and is always created.
+pub async fn plan<B: Backend>(
+    desired_networks: &[FirecrackerNetwork],
+    applied_networks: &[FirecrackerNetwork],
+) -> Vec<FirecrackerNetworkChange> {
+    let mut changes = applied_networks
+        .iter()
+        .filter(|network| !desired_networks.contains(network))
+        .map(|network| FirecrackerNetworkChange {
+            action: FirecrackerNetworkAction::Delete,
+            network: network.clone(),
+            reason: None,
+        })
+        .collect::<Vec<_>>();
+
+    for network in desired_networks {
+        let replaces_deleted_network = changes
+            .iter()
+            .any(|change| change.action == FirecrackerNetworkAction::Delete && change.network.tap_name == network.tap_name);
+
+        if replaces_deleted_network {
+            changes.push(FirecrackerNetworkChange {
+                action: FirecrackerNetworkAction::Create,
+                network: network.clone(),
+                reason: None,
+            });
+            continue;
+        }
+
+        let reason = match run::<B>(network, FirecrackerNetworkOperation::Check).await {
+            Ok(_) => continue,
+            Err(err) => err,
+        };
+
+        changes.push(FirecrackerNetworkChange {
+            action: match applied_networks.contains(network) {
+                true => FirecrackerNetworkAction::Repair,
+                false => FirecrackerNetworkAction::Create,
+            },
+            network: network.clone(),
+            reason: Some(reason),
+        });
+    }
+
+    changes.sort_by_key(|change| change.action);
+    changes
+}
+
+/// Apply a single [FirecrackerNetworkChange] of a plan via the given [Backend]. A repair attempts to delete the
+/// remains of the network first, tolerating the deletion failing on objects that are already missing, and then adds
+/// the network again.
+pub async fn apply<B: Backend>(change: &FirecrackerNetworkChange) -> Result<(), FirecrackerNetworkError> {
+    apply_with_hooks::<B, _>(change, &NoHooks).await
+}
+
+/// Apply a single [FirecrackerNetworkChange] of a plan via the given [Backend] like [apply], with every operation
+/// surrounded by the given [FirecrackerNetworkHooks] like via [run_with_hooks].
+pub async fn apply_with_hooks<B: Backend, H: FirecrackerNetworkHooks>(
+    change: &FirecrackerNetworkChange,
+    hooks: &H,
+) -> Result<(), FirecrackerNetworkError> {
+    match change.action {
+        FirecrackerNetworkAction::Delete => {
+            run_with_hooks::<B, H>(&change.network, FirecrackerNetworkOperation::Delete, hooks).await
+        }
+        FirecrackerNetworkAction::Repair => {
+            let _ = run_with_hooks::<B, H>(&change.network, FirecrackerNetworkOperation::Delete, hooks).await;
+            run_with_hooks::<B, H>(&change.network, FirecrackerNetworkOperation::Add, hooks).await
+        }
+        FirecrackerNetworkAction::Create => {
+            run_with_hooks::<B, H>(&change.network, FirecrackerNetworkOperation::Add, hooks).await
+        }
+    }
+}
+
+/// The networks that count as applied after applying a plan towards the desired networks, given the changes whose
+/// application failed. Failed creations weren't applied and failed deletions are retried on the next application,
+/// while failed repairs stay applied so that they're planned as repairs again.
+pub fn applied_networks(
+    desired_networks: Vec<FirecrackerNetwork>,
+    failed_changes: &[&FirecrackerNetworkChange],
+) -> Vec<FirecrackerNetwork> {
+    let mut applied_networks = desired_networks
+        .into_iter()
+        .filter(|network| {
+            !failed_changes
+                .iter()
+                .any(|change| change.action == FirecrackerNetworkAction::Create && change.network == *network)
+        })
+        .collect::<Vec<_>>();
+    applied_networks.extend(
+        failed_changes
+            .iter()
+            .filter(|change| change.action == FirecrackerNetworkAction::Delete)
+            .map(|change| change.network.clone()),
+    );
+    applied_networks
+}
+
+struct NoHooks;
+
+impl FirecrackerNetworkHooks for NoHooks {
+    async fn pre_operation(&self, _network: &FirecrackerNetwork, _operation: FirecrackerNetworkOperation) -> Result<(), String> {
+        Ok(())
+    }
+
+    async fn post_operation(
+        &self,
+        _network: &FirecrackerNetwork,
+        _operation: FirecrackerNetworkOperation,
+        _result: &Result<(), FirecrackerNetworkError>,
+    ) -> Result<(), String> {
+        Ok(())
+    }
+}
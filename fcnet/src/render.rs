@@ -0,0 +1,532 @@
+use std::fmt::Write;
+
+#[cfg(any(feature = "simple", feature = "namespaced"))]
+use fcnet_types::FirecrackerRuleBackend;
+use fcnet_types::{FirecrackerNetwork, FirecrackerNetworkOperation, FirecrackerNetworkType};
+use nftables::{
+    batch::Batch,
+    expr::{Expression, Meta, MetaKey, NamedExpression, Payload, PayloadField, Prefix, CT},
+    schema::{Chain, FlowTable, NfCmd, NfListObject, NfObject, Nftables, Rule, Table},
+    stmt::{Flow, Mangle, Match, NATFamily, Operator, SetOp, Statement, NAT},
+    types::{NfChainPolicy, NfChainType, NfHook},
+};
+use nftables_async::helper::Helper;
+
+use crate::{
+    backend::Backend,
+    flowtable, policy,
+    rules::{add_nft_rules_to_batch, delete_nft_rules_to_batch, NetworkRule, NftRuleDeletion},
+    util::{self, nf_family_name, FirecrackerNetworkExt, NO_NFT_ARGS},
+    FirecrackerNetworkError,
+};
+
+/// The nftables changes that an operation makes inside one netns, both as the JSON batch that fcnet passes to nftables
+/// and as an equivalent script that can be applied by hand via "nft -f".
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FirecrackerNftChanges {
+    pub nftables: Nftables<'static>,
+    pub script: String,
+}
+
+/// The nftables changes of a [FirecrackerNetworkOperation] on a [FirecrackerNetwork], as produced by [render].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FirecrackerNetworkRendering {
+    /// The changes in the netns that fcnet is run in.
+    pub outer: FirecrackerNftChanges,
+    /// The changes inside the netns of a namespaced network with the nftables rule backend, which are made when adding
+    /// it and when deleting it from an attached netns, since deleting a created netns removes it along with its rules.
+    pub inner: Option<FirecrackerNftChanges>,
+}
+
+/// Render the nftables changes that running a [FirecrackerNetworkOperation] on a [FirecrackerNetwork] via the given
+/// [Backend] would make to the current ruleset, without making them. Checks and flushes make no changes, and rules
+/// that are applied via iptables aren't rendered.
+pub async fn render<B: Backend>(
+    network: &FirecrackerNetwork,
+    operation: FirecrackerNetworkOperation,
+) -> Result<FirecrackerNetworkRendering, FirecrackerNetworkError> {
+    let (connection, netlink_handle, _) =
+        rtnetlink::new_connection_with_socket::<B::NetlinkSocket>().map_err(FirecrackerNetworkError::IoError)?;
+    B::spawn_connection(connection);
+
+    let network = util::resolve_iface_name(network, &netlink_handle).await?;
+    let network = network.as_ref();
+
+    let current_ruleset = B::NftablesDriver::get_current_ruleset_with_args(network.nft_program(), NO_NFT_ARGS)
+        .await
+        .map_err(FirecrackerNetworkError::NftablesError)?;
+    let mut batch = Batch::new();
+    let context = network.rule_context();
+    policy::validate(network)?;
+    let mark_rules: Vec<NetworkRule> = network
+        .policy_route
+        .map(|policy_route| policy::mark_rule(network, &policy_route))
+        .into_iter()
+        .collect();
+
+    // the changes are added in the order that running the operation makes them in
+    match operation {
+        FirecrackerNetworkOperation::Add => {
+            // base chains are only added along with rules that need them, like when running the operation
+            let rules = nft_network_rules(network, &netlink_handle, operation).await?;
+            if !rules.is_empty() {
+                add_nft_rules_to_batch(&context, &current_ruleset, &rules, &mut batch)?;
+            }
+
+            flowtable::add_to_batch_if_needed(network, &current_ruleset, &mut batch)?;
+
+            if !mark_rules.is_empty() {
+                add_nft_rules_to_batch(&context, &current_ruleset, &mark_rules, &mut batch)?;
+            }
+        }
+        FirecrackerNetworkOperation::Delete => {
+            delete_nft_rules_to_batch(&context, &current_ruleset, &mark_rules, &mut batch)?;
+            delete_nft_rules_to_batch(
+                &context,
+                &current_ruleset,
+                &nft_network_rules(network, &netlink_handle, operation).await?,
+                &mut batch,
+            )?;
+            if flowtable::deletes_flow_rule(network, &netlink_handle, NftRuleDeletion::Lookup).await? {
+                flowtable::delete_to_batch_if_needed(network, &current_ruleset, &mut batch)?;
+            }
+        }
+        FirecrackerNetworkOperation::Check | FirecrackerNetworkOperation::Flush => {}
+    }
+
+    #[allow(unused_mut)]
+    let mut inner = None;
+
+    #[cfg(feature = "namespaced")]
+    if let FirecrackerNetworkType::Namespaced { .. } = network.network_type {
+        inner = crate::namespaced::inner_nft_changes::<B>(network, operation)
+            .await?
+            .map(FirecrackerNftChanges::new)
+            .transpose()?;
+    }
+
+    Ok(FirecrackerNetworkRendering {
+        outer: FirecrackerNftChanges::new(batch.to_nftables())?,
+        inner,
+    })
+}
+
+impl FirecrackerNftChanges {
+    fn new(nftables: Nftables<'static>) -> Result<Self, FirecrackerNetworkError> {
+        let mut script = String::new();
+
+        for object in nftables.objects.iter() {
+            match object {
+                NfObject::CmdObject(cmd) => script.push_str(&cmd_line(cmd)?),
+                NfObject::ListObject(_) => return Err(unsupported("list object", object)),
+            }
+
+            script.push('\n');
+        }
+
+        Ok(Self { nftables, script })
+    }
+}
+
+/// The rules of the network's type that are applied via nftables in the outer netns. The rules of a bridge are only
+/// deleted along with its last port.
+#[allow(unused_variables)]
+pub async fn nft_network_rules(
+    network: &FirecrackerNetwork,
+    netlink_handle: &rtnetlink::Handle,
+    operation: FirecrackerNetworkOperation,
+) -> Result<Vec<NetworkRule>, FirecrackerNetworkError> {
+    match &network.network_type {
+        #[cfg(feature = "simple")]
+        FirecrackerNetworkType::Simple => match network.rule_backend {
+            FirecrackerRuleBackend::Nftables => crate::simple::network_rules(network),
+            FirecrackerRuleBackend::Iptables { .. } => Ok(Vec::new()),
+        },
+        #[cfg(feature = "namespaced")]
+        FirecrackerNetworkType::Namespaced { .. } => match network.rule_backend {
+            FirecrackerRuleBackend::Nftables => crate::namespaced::network_rules(network),
+            FirecrackerRuleBackend::Iptables { .. } => Ok(Vec::new()),
+        },
+        #[cfg(feature = "routed")]
+        FirecrackerNetworkType::Routed { .. } => crate::routed::network_rules(network),
+        #[cfg(feature = "bridge")]
+        FirecrackerNetworkType::Bridge { bridge_name } => {
+            if operation == FirecrackerNetworkOperation::Delete
+                && !crate::bridge::deletes_rules(network, netlink_handle, bridge_name).await?
+            {
+                return Ok(Vec::new());
+            }
+
+            Ok(crate::bridge::network_rules(bridge_name))
+        }
+        #[allow(unreachable_patterns)]
+        _ => Ok(Vec::new()),
+    }
+}
+
+fn cmd_line(cmd: &NfCmd) -> Result<String, FirecrackerNetworkError> {
+    match cmd {
+        NfCmd::Add(NfListObject::Table(Table { family, name, .. })) => {
+            Ok(format!("add table {} {name}", nf_family_name(*family)))
+        }
+        NfCmd::Add(NfListObject::Chain(chain)) => chain_line(chain),
+        NfCmd::Add(NfListObject::Rule(rule)) => Ok(format!("add rule {}", rule_spec(rule)?)),
+        NfCmd::Insert(NfListObject::Rule(rule)) => Ok(format!("insert rule {}", rule_spec(rule)?)),
+        NfCmd::Add(NfListObject::FlowTable(flowtable)) => flowtable_line(flowtable),
+        NfCmd::Delete(NfListObject::Rule(Rule {
+            family,
+            table,
+            chain,
+            handle: Some(handle),
+            ..
+        })) => Ok(format!(
+            "delete rule {} {table} {chain} handle {handle}",
+            nf_family_name(*family)
+        )),
+        NfCmd::Delete(NfListObject::FlowTable(FlowTable {
+            family,
+            table,
+            handle: Some(handle),
+            ..
+        })) => Ok(format!(
+            "delete flowtable {} {table} handle {handle}",
+            nf_family_name(*family)
+        )),
+        _ => Err(unsupported("command", cmd)),
+    }
+}
+
+fn chain_line(chain: &Chain) -> Result<String, FirecrackerNetworkError> {
+    let Chain {
+        family,
+        table,
+        name,
+        _type: Some(chain_type),
+        hook: Some(hook),
+        prio: Some(prio),
+        policy,
+        ..
+    } = chain
+    else {
+        return Err(unsupported("chain", chain));
+    };
+
+    let mut line = format!(
+        "add chain {} {table} {name} {{ type {} hook {} priority {prio};",
+        nf_family_name(*family),
+        chain_type_name(*chain_type),
+        hook_name(*hook),
+    );
+
+    if let Some(policy) = policy {
+        let _ = write!(line, " policy {};", policy_name(*policy));
+    }
+
+    line.push_str(" }");
+    Ok(line)
+}
+
+fn flowtable_line(flowtable: &FlowTable) -> Result<String, FirecrackerNetworkError> {
+    let FlowTable {
+        family,
+        table,
+        name,
+        hook: Some(hook),
+        prio: Some(prio),
+        dev: Some(devices),
+        ..
+    } = flowtable
+    else {
+        return Err(unsupported("flowtable", flowtable));
+    };
+
+    let devices = devices
+        .iter()
+        .map(|device| format!("\"{device}\""))
+        .collect::<Vec<_>>()
+        .join(", ");
+    Ok(format!(
+        "add flowtable {} {table} {name} {{ hook {} priority {prio}; devices = {{ {devices} }}; }}",
+        nf_family_name(*family),
+        hook_name(*hook),
+    ))
+}
+
+fn rule_spec(rule: &Rule) -> Result<String, FirecrackerNetworkError> {
+    let statements = rule.expr.iter().map(statement).collect::<Result<Vec<_>, _>>()?;
+    Ok(format!(
+        "{} {} {} {}",
+        nf_family_name(rule.family),
+        rule.table,
+        rule.chain,
+        statements.join(" ")
+    ))
+}
+
+fn statement(statement: &Statement) -> Result<String, FirecrackerNetworkError> {
+    match statement {
+        Statement::Match(Match { left, right, op }) => {
+            // interface names are matched as strings, while addresses and conntrack states are keywords
+            let quoted = matches!(
+                left,
+                Expression::Named(NamedExpression::Meta(Meta {
+                    key: MetaKey::Iifname | MetaKey::Oifname
+                }))
+            );
+            let op = match op {
+                Operator::EQ | Operator::IN => "",
+                Operator::NEQ => "!= ",
+                _ => return Err(unsupported("operator", op)),
+            };
+
+            Ok(format!("{} {op}{}", expression(left, false)?, expression(right, quoted)?))
+        }
+        Statement::Accept(None) => Ok("accept".into()),
+        Statement::Drop(None) => Ok("drop".into()),
+        Statement::Masquerade(None) => Ok("masquerade".into()),
+        Statement::SNAT(Some(nat)) => nat_statement("snat", nat),
+        Statement::DNAT(Some(nat)) => nat_statement("dnat", nat),
+        Statement::Flow(Flow {
+            op: SetOp::Add,
+            flowtable,
+        }) => Ok(format!("flow add {flowtable}")),
+        Statement::Mangle(Mangle { key, value }) => Ok(format!("{} set {}", expression(key, false)?, expression(value, false)?)),
+        _ => Err(unsupported("statement", statement)),
+    }
+}
+
+fn nat_statement(keyword: &str, nat: &NAT) -> Result<String, FirecrackerNetworkError> {
+    let NAT {
+        addr: Some(addr),
+        family,
+        port,
+        flags: None,
+    } = nat
+    else {
+        return Err(unsupported("NAT statement", nat));
+    };
+
+    let addr = expression(addr, false)?;
+    let family = match family {
+        Some(NATFamily::IP) => "ip ",
+        Some(NATFamily::IP6) => "ip6 ",
+        None => "",
+    };
+
+    let target = match port {
+        None => addr,
+        Some(port) if addr.contains(':') => format!("[{addr}]:{}", expression(port, false)?),
+        Some(port) => format!("{addr}:{}", expression(port, false)?),
+    };
+
+    Ok(format!("{keyword} {family}to {target}"))
+}
+
+fn expression(expression: &Expression, quoted: bool) -> Result<String, FirecrackerNetworkError> {
+    match expression {
+        Expression::String(value) if quoted => Ok(format!("\"{value}\"")),
+        Expression::String(value) => Ok(value.to_string()),
+        Expression::Number(value) => Ok(value.to_string()),
+        Expression::Named(NamedExpression::Meta(Meta { key })) => match key {
+            MetaKey::Iifname => Ok("meta iifname".into()),
+            MetaKey::Oifname => Ok("meta oifname".into()),
+            MetaKey::Mark => Ok("meta mark".into()),
+            _ => Err(unsupported("meta key", key)),
+        },
+        Expression::Named(NamedExpression::Payload(Payload::PayloadField(PayloadField { protocol, field }))) => {
+            Ok(format!("{protocol} {field}"))
+        }
+        Expression::Named(NamedExpression::Prefix(Prefix { addr, len })) => {
+            Ok(format!("{}/{len}", self::expression(addr, false)?))
+        }
+        Expression::Named(NamedExpression::CT(CT {
+            key,
+            family: None,
+            dir: None,
+        })) => Ok(format!("ct {key}")),
+        _ => Err(unsupported("expression", expression)),
+    }
+}
+
+#[inline]
+fn chain_type_name(chain_type: NfChainType) -> &'static str {
+    match chain_type {
+        NfChainType::Filter => "filter",
+        NfChainType::Route => "route",
+        NfChainType::NAT => "nat",
+    }
+}
+
+#[inline]
+fn hook_name(hook: NfHook) -> &'static str {
+    match hook {
+        NfHook::Ingress => "ingress",
+        NfHook::Prerouting => "prerouting",
+        NfHook::Forward => "forward",
+        NfHook::Input => "input",
+        NfHook::Output => "output",
+        NfHook::Postrouting => "postrouting",
+        NfHook::Egress => "egress",
+    }
+}
+
+#[inline]
+fn policy_name(policy: NfChainPolicy) -> &'static str {
+    match policy {
+        NfChainPolicy::Accept => "accept",
+        NfChainPolicy::Drop => "drop",
+    }
+}
+
+#[inline]
+fn unsupported(kind: &str, object: &impl std::fmt::Debug) -> FirecrackerNetworkError {
+    FirecrackerNetworkError::RenderError(format!("The {kind} has no nft script equivalent: {object:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use fcnet_types::{
+        FirecrackerIpStack, FirecrackerNetwork, FirecrackerNetworkType, FirecrackerPortForward, FirecrackerPortProtocol,
+        FirecrackerRuleBackend,
+    };
+    use nftables::{
+        batch::Batch,
+        schema::{NfCmd, NfListObject, NfObject, Nftables},
+    };
+
+    use super::FirecrackerNftChanges;
+    use crate::{
+        flowtable,
+        rules::{add_nft_rules_to_batch, delete_nft_rules_to_batch},
+        simple::network_rules,
+        util::FirecrackerNetworkExt,
+    };
+
+    fn network() -> FirecrackerNetwork {
+        FirecrackerNetwork {
+            nft_path: None,
+            nft_config: Default::default(),
+            rule_backend: FirecrackerRuleBackend::Nftables,
+            ip_stack: FirecrackerIpStack::V4,
+            iface_name: Some("eth0".to_string()),
+            tap_name: "tap0".to_string(),
+            tap_ip: "172.16.0.1/24".parse().unwrap(),
+            guest_ip: "172.16.0.2/24".parse().unwrap(),
+            guest_mac: None,
+            network_type: FirecrackerNetworkType::Simple,
+            nat_ip: Some("10.0.0.5".parse().unwrap()),
+            port_forwards: vec![
+                FirecrackerPortForward {
+                    host_ip: None,
+                    host_port: 8080,
+                    guest_port: 80,
+                    protocol: FirecrackerPortProtocol::Tcp,
+                },
+                FirecrackerPortForward {
+                    host_ip: Some("10.0.0.5".parse().unwrap()),
+                    host_port: 5353,
+                    guest_port: 53,
+                    protocol: FirecrackerPortProtocol::Udp,
+                },
+            ],
+            vlan_id: None,
+            vlan_ip: None,
+            policy_route: None,
+            flowtable: true,
+        }
+    }
+
+    fn add_changes(network: &FirecrackerNetwork) -> FirecrackerNftChanges {
+        let current_ruleset = Nftables {
+            objects: Vec::new().into(),
+        };
+        let mut batch = Batch::new();
+        add_nft_rules_to_batch(
+            &network.rule_context(),
+            &current_ruleset,
+            &network_rules(network).unwrap(),
+            &mut batch,
+        )
+        .unwrap();
+        flowtable::add_to_batch_if_needed(network, &current_ruleset, &mut batch).unwrap();
+        FirecrackerNftChanges::new(batch.to_nftables()).unwrap()
+    }
+
+    /// The ruleset that applying the changes to an empty one yields, with the objects numbered by handles in order.
+    fn applied_ruleset(changes: &FirecrackerNftChanges) -> Nftables<'static> {
+        let objects = changes
+            .nftables
+            .objects
+            .iter()
+            .zip(1..)
+            .map(|(object, handle)| match object {
+                NfObject::CmdObject(NfCmd::Add(NfListObject::Rule(rule)) | NfCmd::Insert(NfListObject::Rule(rule))) => {
+                    let mut rule = rule.clone();
+                    rule.handle = Some(handle);
+                    NfObject::ListObject(NfListObject::Rule(rule))
+                }
+                NfObject::CmdObject(NfCmd::Add(NfListObject::FlowTable(flowtable))) => {
+                    let mut flowtable = flowtable.clone();
+                    flowtable.handle = Some(handle);
+                    NfObject::ListObject(NfListObject::FlowTable(flowtable))
+                }
+                NfObject::CmdObject(NfCmd::Add(object)) => NfObject::ListObject(object.clone()),
+                object => panic!("Unexpected object in the changes: {object:?}"),
+            })
+            .collect::<Vec<_>>();
+
+        Nftables { objects: objects.into() }
+    }
+
+    #[test]
+    fn simple_network_with_port_forwards_is_rendered() {
+        assert_eq!(
+            add_changes(&network()).script,
+            "add table ip fcnet
+add chain ip fcnet postrouting { type nat hook postrouting priority 100; policy accept; }
+add chain ip fcnet prerouting { type nat hook prerouting priority -100; policy accept; }
+add chain ip fcnet filter { type filter hook forward priority 0; policy accept; }
+add rule ip fcnet postrouting ip saddr 172.16.0.2 meta oifname \"eth0\" snat to 10.0.0.5
+add rule ip fcnet filter meta iifname \"tap0\" meta oifname \"eth0\" accept
+add rule ip fcnet prerouting meta iifname \"eth0\" tcp dport 8080 dnat to 172.16.0.2:80
+add rule ip fcnet filter meta iifname \"eth0\" meta oifname \"tap0\" ip daddr 172.16.0.2 tcp dport 80 accept
+add rule ip fcnet prerouting meta iifname \"eth0\" ip daddr 10.0.0.5 udp dport 5353 dnat to 172.16.0.2:53
+add rule ip fcnet filter meta iifname \"eth0\" meta oifname \"tap0\" ip daddr 172.16.0.2 udp dport 53 accept
+add rule ip fcnet prerouting meta iifname \"eth0\" ip daddr 10.0.0.5 dnat to 172.16.0.2
+add rule ip fcnet filter meta iifname \"eth0\" meta oifname \"tap0\" ip daddr 172.16.0.2 accept
+add flowtable ip fcnet flowtable { hook ingress priority 0; devices = { \"tap0\", \"eth0\" }; }
+insert rule ip fcnet filter meta iifname \"tap0\" ct state established flow add @flowtable
+"
+        );
+    }
+
+    #[test]
+    fn simple_network_deletion_is_rendered() {
+        let network = network();
+        let applied_ruleset = applied_ruleset(&add_changes(&network));
+        let mut batch = Batch::new();
+        delete_nft_rules_to_batch(
+            &network.rule_context(),
+            &applied_ruleset,
+            &network_rules(&network).unwrap(),
+            &mut batch,
+        )
+        .unwrap();
+        flowtable::delete_to_batch_if_needed(&network, &applied_ruleset, &mut batch).unwrap();
+
+        assert_eq!(
+            FirecrackerNftChanges::new(batch.to_nftables()).unwrap().script,
+            "delete rule ip fcnet postrouting handle 5
+delete rule ip fcnet filter handle 6
+delete rule ip fcnet prerouting handle 7
+delete rule ip fcnet filter handle 8
+delete rule ip fcnet prerouting handle 9
+delete rule ip fcnet filter handle 10
+delete rule ip fcnet prerouting handle 11
+delete rule ip fcnet filter handle 12
+delete rule ip fcnet filter handle 14
+delete flowtable ip fcnet handle 13
+"
+        );
+    }
+}
@@ -0,0 +1,271 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use fcnet_types::FirecrackerNetwork;
+use futures_util::TryStreamExt;
+use rtnetlink::{
+    packet_route::{
+        neighbour::{NeighbourAddress, NeighbourAttribute, NeighbourFlags, NeighbourMessage},
+        route::{RouteAddress, RouteAttribute, RouteMessage},
+    },
+    RouteMessageBuilder,
+};
+use tokio_tun::TunBuilder;
+
+use crate::{
+    backend::Backend,
+    conntrack,
+    rules::{ensure_nftables, NetworkRule, NftRuleDeletion, NftablesRuleBackend, RuleBackend, RuleChain},
+    util::{
+        add_guest_neighbour, check_guest_neighbour, ensure_no_nat, get_link_index, tap_egress_forward_expr,
+        tap_ingress_forward_expr, FirecrackerNetworkExt,
+    },
+    FirecrackerNetworkError, FirecrackerNetworkObjectType, FirecrackerNetworkOperation,
+};
+
+pub async fn run<B: Backend>(
+    network: &FirecrackerNetwork,
+    netlink_handle: rtnetlink::Handle,
+    operation: FirecrackerNetworkOperation,
+    proxy_neighbour: bool,
+    deletion: NftRuleDeletion,
+) -> Result<(), FirecrackerNetworkError> {
+    ensure_nftables(&network.rule_backend, "routed networks")?;
+    ensure_no_nat(network, "Routed networks")?;
+
+    match operation {
+        FirecrackerNetworkOperation::Add => add::<B>(network, netlink_handle, proxy_neighbour).await,
+        FirecrackerNetworkOperation::Check => check::<B>(network, netlink_handle, proxy_neighbour).await,
+        FirecrackerNetworkOperation::Delete => delete::<B>(network, netlink_handle, proxy_neighbour, deletion).await,
+        FirecrackerNetworkOperation::Flush => conntrack::flush::<B>(&[network.guest_ip.address()]).await,
+    }
+}
+
+async fn add<B: Backend>(
+    network: &FirecrackerNetwork,
+    netlink_handle: rtnetlink::Handle,
+    proxy_neighbour: bool,
+) -> Result<(), FirecrackerNetworkError> {
+    TunBuilder::new()
+        .name(&network.tap_name)
+        .tap()
+        .persist()
+        .up()
+        .build()
+        .map_err(FirecrackerNetworkError::TapDeviceError)?;
+    let tap_idx = get_link_index(network.tap_name.clone(), &netlink_handle).await?;
+    netlink_handle
+        .address()
+        .add(tap_idx, network.tap_ip.address(), network.tap_ip.network_length())
+        .execute()
+        .await
+        .map_err(FirecrackerNetworkError::NetlinkOperationError)?;
+
+    add_guest_neighbour(
+        network.guest_ip.address(),
+        network.guest_mac.as_deref(),
+        tap_idx,
+        &netlink_handle,
+    )
+    .await?;
+
+    // route packets going to the guest ip directly into the tap, since no NAT is performed
+    netlink_handle
+        .route()
+        .add(guest_route_message(network, tap_idx))
+        .execute()
+        .await
+        .map_err(FirecrackerNetworkError::NetlinkOperationError)?;
+
+    if proxy_neighbour {
+        std::fs::write(proxy_sysctl_path(network)?, "1").map_err(FirecrackerNetworkError::IoError)?;
+
+        // unlike proxy ARP, proxy NDP only answers for addresses that have an explicit proxy entry
+        if network.guest_ip.address().is_ipv6() {
+            let iface_idx = get_link_index(network.uplink_name()?, &netlink_handle).await?;
+            netlink_handle
+                .neighbours()
+                .add(iface_idx, network.guest_ip.address())
+                .flags(NeighbourFlags::Proxy)
+                .execute()
+                .await
+                .map_err(FirecrackerNetworkError::NetlinkOperationError)?;
+        }
+    }
+
+    NftablesRuleBackend::add_rules::<B>(&network.rule_context(), &network_rules(network)?).await
+}
+
+async fn delete<B: Backend>(
+    network: &FirecrackerNetwork,
+    netlink_handle: rtnetlink::Handle,
+    proxy_neighbour: bool,
+    deletion: NftRuleDeletion,
+) -> Result<(), FirecrackerNetworkError> {
+    // the host route to the guest is removed by the kernel together with the tap
+    let tap_idx = get_link_index(network.tap_name.clone(), &netlink_handle).await?;
+    netlink_handle
+        .link()
+        .del(tap_idx)
+        .execute()
+        .await
+        .map_err(FirecrackerNetworkError::NetlinkOperationError)?;
+
+    // the proxy ARP sysctl is left enabled, since other routed networks on the same host interface may rely on it
+    if proxy_neighbour && network.guest_ip.address().is_ipv6() {
+        let neighbour_message =
+            get_proxy_neighbour(network, &netlink_handle)
+                .await?
+                .ok_or(FirecrackerNetworkError::ObjectNotFound(
+                    FirecrackerNetworkObjectType::IpNeighbour,
+                ))?;
+        netlink_handle
+            .neighbours()
+            .del(neighbour_message)
+            .execute()
+            .await
+            .map_err(FirecrackerNetworkError::NetlinkOperationError)?;
+    }
+
+    match deletion {
+        NftRuleDeletion::Lookup => {
+            NftablesRuleBackend::delete_rules::<B>(&network.rule_context(), &network_rules(network)?).await
+        }
+        NftRuleDeletion::Skip => Ok(()),
+    }
+}
+
+async fn check<B: Backend>(
+    network: &FirecrackerNetwork,
+    netlink_handle: rtnetlink::Handle,
+    proxy_neighbour: bool,
+) -> Result<(), FirecrackerNetworkError> {
+    let tap_idx = get_link_index(network.tap_name.clone(), &netlink_handle).await?;
+    check_guest_route(network, &netlink_handle, tap_idx).await?;
+    check_guest_neighbour(
+        network.guest_ip.address(),
+        network.guest_mac.as_deref(),
+        tap_idx,
+        &netlink_handle,
+    )
+    .await?;
+
+    if proxy_neighbour {
+        let sysctl_value = std::fs::read_to_string(proxy_sysctl_path(network)?).map_err(FirecrackerNetworkError::IoError)?;
+        if sysctl_value.trim() != "1" {
+            return Err(FirecrackerNetworkError::ObjectNotFound(FirecrackerNetworkObjectType::Sysctl));
+        }
+
+        if network.guest_ip.address().is_ipv6() && get_proxy_neighbour(network, &netlink_handle).await?.is_none() {
+            return Err(FirecrackerNetworkError::ObjectNotFound(
+                FirecrackerNetworkObjectType::IpNeighbour,
+            ));
+        }
+    }
+
+    NftablesRuleBackend::check_rules::<B>(&network.rule_context(), &network_rules(network)?).await
+}
+
+/// The rules of a routed network, which only accept forwarded packets since no NAT is performed.
+pub fn network_rules(network: &FirecrackerNetwork) -> Result<Vec<NetworkRule>, FirecrackerNetworkError> {
+    Ok(vec![
+        NetworkRule {
+            chain: RuleChain::Filter,
+            expr: tap_egress_forward_expr(network)?,
+            object_type: FirecrackerNetworkObjectType::NfEgressForwardRule,
+        },
+        NetworkRule {
+            chain: RuleChain::Filter,
+            expr: tap_ingress_forward_expr(network)?,
+            object_type: FirecrackerNetworkObjectType::NfIngressForwardRule,
+        },
+    ])
+}
+
+async fn check_guest_route(
+    network: &FirecrackerNetwork,
+    netlink_handle: &rtnetlink::Handle,
+    tap_idx: u32,
+) -> Result<(), FirecrackerNetworkError> {
+    let mut route_message_stream = netlink_handle
+        .route()
+        .get(RouteMessageBuilder::<IpAddr>::new().build())
+        .execute();
+
+    while let Ok(Some(route_message)) = route_message_stream.try_next().await {
+        let mut destination_matches = false;
+        let mut oif_matches = false;
+
+        for attribute in &route_message.attributes {
+            match attribute {
+                RouteAttribute::Destination(RouteAddress::Inet(i)) => {
+                    destination_matches = IpAddr::V4(*i) == network.guest_ip.address();
+                }
+                RouteAttribute::Destination(RouteAddress::Inet6(i)) => {
+                    destination_matches = IpAddr::V6(*i) == network.guest_ip.address();
+                }
+                RouteAttribute::Oif(oif) => {
+                    oif_matches = *oif == tap_idx;
+                }
+                _ => continue,
+            }
+        }
+
+        if destination_matches && oif_matches {
+            return Ok(());
+        }
+    }
+
+    Err(FirecrackerNetworkError::ObjectNotFound(FirecrackerNetworkObjectType::IpRoute))
+}
+
+async fn get_proxy_neighbour(
+    network: &FirecrackerNetwork,
+    netlink_handle: &rtnetlink::Handle,
+) -> Result<Option<NeighbourMessage>, FirecrackerNetworkError> {
+    let iface_idx = get_link_index(network.uplink_name()?, netlink_handle).await?;
+    let mut neighbour_message_stream = netlink_handle.neighbours().get().proxies().execute();
+
+    while let Some(neighbour_message) = neighbour_message_stream
+        .try_next()
+        .await
+        .map_err(FirecrackerNetworkError::NetlinkOperationError)?
+    {
+        if neighbour_message.header.ifindex != iface_idx {
+            continue;
+        }
+
+        for attribute in &neighbour_message.attributes {
+            if let NeighbourAttribute::Destination(NeighbourAddress::Inet6(i)) = attribute {
+                if IpAddr::V6(*i) == network.guest_ip.address() {
+                    return Ok(Some(neighbour_message));
+                }
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+#[inline]
+fn guest_route_message(network: &FirecrackerNetwork, tap_idx: u32) -> RouteMessage {
+    match network.guest_ip.address() {
+        IpAddr::V4(v4) => RouteMessageBuilder::<Ipv4Addr>::new()
+            .destination_prefix(v4, 32)
+            .output_interface(tap_idx)
+            .build(),
+        IpAddr::V6(v6) => RouteMessageBuilder::<Ipv6Addr>::new()
+            .destination_prefix(v6, 128)
+            .output_interface(tap_idx)
+            .build(),
+    }
+}
+
+#[inline]
+fn proxy_sysctl_path(network: &FirecrackerNetwork) -> Result<String, FirecrackerNetworkError> {
+    let uplink_name = network.uplink_name()?;
+
+    Ok(match network.guest_ip.address() {
+        IpAddr::V4(_) => format!("/proc/sys/net/ipv4/conf/{uplink_name}/proxy_arp"),
+        IpAddr::V6(_) => format!("/proc/sys/net/ipv6/conf/{uplink_name}/proxy_ndp"),
+    })
+}
@@ -0,0 +1,845 @@
+use std::{ffi::OsStr, future::Future, net::IpAddr, process::Output};
+
+use fcnet_types::{FirecrackerNetworkOperation, FirecrackerNftConfig, FirecrackerRuleBackend};
+use nftables::{
+    batch::Batch,
+    expr::{Expression, Meta, MetaKey, NamedExpression, Payload, PayloadField},
+    schema::{NfCmd, NfListObject, NfObject, Nftables, Rule},
+    stmt::{Match, Operator, Statement, NAT},
+    types::NfFamily,
+};
+use nftables_async::{driver::Driver, helper::Helper};
+
+use crate::{
+    backend::Backend,
+    util::{add_base_chains_if_needed, check_base_chains, matches_interface, NO_NFT_ARGS},
+    FirecrackerNetworkError, FirecrackerNetworkObjectType,
+};
+
+/// The chain of fcnet's rule set that a [NetworkRule] belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuleChain {
+    /// The NAT chain on the postrouting hook, used for masquerading and SNAT.
+    Postrouting,
+    /// The NAT chain on the prerouting hook, used for DNAT.
+    Prerouting,
+    /// The filter chain on the prerouting hook, used for marking packets for policy routing.
+    Mangle,
+    /// The filter chain on the forward hook, used for accepting forwarded packets.
+    Filter,
+}
+
+/// A NAT or forwarding rule of a network, expressed as nftables statements that every [RuleBackend] translates into
+/// its own representation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NetworkRule {
+    pub chain: RuleChain,
+    pub expr: Vec<Statement<'static>>,
+    pub object_type: FirecrackerNetworkObjectType,
+}
+
+/// The configuration needed by a [RuleBackend] to place rules, which is either derived from a network or, inside a
+/// netns, from an owned copy of its data.
+pub struct RuleContext<'a> {
+    pub nf_family: NfFamily,
+    pub nft_path: Option<&'a str>,
+    pub nft_config: &'a FirecrackerNftConfig,
+    pub rule_backend: &'a FirecrackerRuleBackend,
+    pub needs_prerouting_chain: bool,
+    pub needs_mangle_chain: bool,
+    pub needs_filter_chain: bool,
+}
+
+/// How the nftables rules of a network in the current netns are deleted along with its other objects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NftRuleDeletion {
+    /// The rules are looked up in the current ruleset and deleted.
+    Lookup,
+    /// The rules were already deleted by the handles stored in a [fcnet_types::FirecrackerNetworkHandle].
+    Skip,
+}
+
+/// A backend that applies [NetworkRule]s to the host in the current netns.
+pub trait RuleBackend {
+    /// Add the base chains that are missing, as well as all given rules that don't exist yet.
+    fn add_rules<B: Backend>(
+        context: &RuleContext<'_>,
+        rules: &[NetworkRule],
+    ) -> impl Future<Output = Result<(), FirecrackerNetworkError>> + Send;
+
+    /// Check that the base chains and all given rules exist.
+    fn check_rules<B: Backend>(
+        context: &RuleContext<'_>,
+        rules: &[NetworkRule],
+    ) -> impl Future<Output = Result<(), FirecrackerNetworkError>> + Send;
+
+    /// Delete all given rules, without deleting any of them if one doesn't exist.
+    fn delete_rules<B: Backend>(
+        context: &RuleContext<'_>,
+        rules: &[NetworkRule],
+    ) -> impl Future<Output = Result<(), FirecrackerNetworkError>> + Send;
+}
+
+/// A [RuleBackend] placing rules into the configured nftables table and chains via nftables' JSON API.
+pub struct NftablesRuleBackend;
+
+/// A [RuleBackend] placing rules into dedicated "FCNET-*" chains via the iptables and ip6tables binaries, which work
+/// the same way regardless of whether they are backed by iptables-nft or iptables-legacy.
+pub struct IptablesRuleBackend;
+
+pub async fn add_rules<B: Backend>(context: &RuleContext<'_>, rules: &[NetworkRule]) -> Result<(), FirecrackerNetworkError> {
+    match context.rule_backend {
+        FirecrackerRuleBackend::Nftables => NftablesRuleBackend::add_rules::<B>(context, rules).await,
+        FirecrackerRuleBackend::Iptables { .. } => IptablesRuleBackend::add_rules::<B>(context, rules).await,
+    }
+}
+
+pub async fn check_rules<B: Backend>(context: &RuleContext<'_>, rules: &[NetworkRule]) -> Result<(), FirecrackerNetworkError> {
+    match context.rule_backend {
+        FirecrackerRuleBackend::Nftables => NftablesRuleBackend::check_rules::<B>(context, rules).await,
+        FirecrackerRuleBackend::Iptables { .. } => IptablesRuleBackend::check_rules::<B>(context, rules).await,
+    }
+}
+
+pub async fn delete_rules<B: Backend>(context: &RuleContext<'_>, rules: &[NetworkRule]) -> Result<(), FirecrackerNetworkError> {
+    match context.rule_backend {
+        FirecrackerRuleBackend::Nftables => NftablesRuleBackend::delete_rules::<B>(context, rules).await,
+        FirecrackerRuleBackend::Iptables { .. } => IptablesRuleBackend::delete_rules::<B>(context, rules).await,
+    }
+}
+
+/// Delete the rules via the configured backend like [delete_rules], unless they're nftables rules that were already
+/// deleted by their handles.
+pub async fn delete_rules_unless_skipped<B: Backend>(
+    context: &RuleContext<'_>,
+    rules: &[NetworkRule],
+    deletion: NftRuleDeletion,
+) -> Result<(), FirecrackerNetworkError> {
+    match (context.rule_backend, deletion) {
+        (FirecrackerRuleBackend::Nftables, NftRuleDeletion::Skip) => Ok(()),
+        _ => delete_rules::<B>(context, rules).await,
+    }
+}
+
+impl RuleBackend for NftablesRuleBackend {
+    async fn add_rules<B: Backend>(context: &RuleContext<'_>, rules: &[NetworkRule]) -> Result<(), FirecrackerNetworkError> {
+        let batch = nft_rules_batch::<B>(context, rules, FirecrackerNetworkOperation::Add).await?;
+
+        B::NftablesDriver::apply_ruleset_with_args(&batch.to_nftables(), context.nft_path, NO_NFT_ARGS)
+            .await
+            .map_err(FirecrackerNetworkError::NftablesError)
+    }
+
+    async fn check_rules<B: Backend>(context: &RuleContext<'_>, rules: &[NetworkRule]) -> Result<(), FirecrackerNetworkError> {
+        let current_ruleset = B::NftablesDriver::get_current_ruleset_with_args(context.nft_path, NO_NFT_ARGS)
+            .await
+            .map_err(FirecrackerNetworkError::NftablesError)?;
+        check_base_chains(context, &current_ruleset)?;
+
+        for rule in unique_rules(rules) {
+            if find_nft_rule_handle(context, &current_ruleset, rule).is_none() {
+                return Err(FirecrackerNetworkError::ObjectNotFound(rule.object_type));
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn delete_rules<B: Backend>(context: &RuleContext<'_>, rules: &[NetworkRule]) -> Result<(), FirecrackerNetworkError> {
+        let batch = nft_rules_batch::<B>(context, rules, FirecrackerNetworkOperation::Delete).await?;
+
+        B::NftablesDriver::apply_ruleset_with_args(&batch.to_nftables(), context.nft_path, NO_NFT_ARGS)
+            .await
+            .map_err(FirecrackerNetworkError::NftablesError)
+    }
+}
+
+impl RuleBackend for IptablesRuleBackend {
+    async fn add_rules<B: Backend>(context: &RuleContext<'_>, rules: &[NetworkRule]) -> Result<(), FirecrackerNetworkError> {
+        for chain in base_chains(context, rules) {
+            let (table, builtin_chain, chain_name) = iptables_chain(chain);
+
+            for program in iptables_programs(context, context.nf_family) {
+                if !iptables_query::<B>(program, &["-t", table, "-S", chain_name]).await? {
+                    iptables_apply::<B>(program, &["-t", table, "-N", chain_name]).await?;
+                }
+
+                // the jump is inserted first, so that the rules aren't shadowed by rules appended to the built-in chain
+                if !iptables_query::<B>(program, &["-t", table, "-C", builtin_chain, "-j", chain_name]).await? {
+                    iptables_apply::<B>(program, &["-t", table, "-I", builtin_chain, "-j", chain_name]).await?;
+                }
+            }
+        }
+
+        for rule in unique_rules(rules) {
+            let (table, _, chain_name) = iptables_chain(rule.chain);
+            let rule_args = iptables_rule_args(rule)?;
+
+            for program in iptables_programs(context, rule_nf_family(context, rule)) {
+                if !iptables_query::<B>(program, &iptables_args(table, "-C", chain_name, &rule_args)).await? {
+                    iptables_apply::<B>(program, &iptables_args(table, "-A", chain_name, &rule_args)).await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn check_rules<B: Backend>(context: &RuleContext<'_>, rules: &[NetworkRule]) -> Result<(), FirecrackerNetworkError> {
+        for chain in base_chains(context, rules) {
+            let (table, builtin_chain, chain_name) = iptables_chain(chain);
+
+            for program in iptables_programs(context, context.nf_family) {
+                if !iptables_query::<B>(program, &["-t", table, "-S", chain_name]).await?
+                    || !iptables_query::<B>(program, &["-t", table, "-C", builtin_chain, "-j", chain_name]).await?
+                {
+                    return Err(FirecrackerNetworkError::ObjectNotFound(chain_object_type(chain)));
+                }
+            }
+        }
+
+        for rule in unique_rules(rules) {
+            let (table, _, chain_name) = iptables_chain(rule.chain);
+            let rule_args = iptables_rule_args(rule)?;
+
+            for program in iptables_programs(context, rule_nf_family(context, rule)) {
+                if !iptables_query::<B>(program, &iptables_args(table, "-C", chain_name, &rule_args)).await? {
+                    return Err(FirecrackerNetworkError::ObjectNotFound(rule.object_type));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn delete_rules<B: Backend>(context: &RuleContext<'_>, rules: &[NetworkRule]) -> Result<(), FirecrackerNetworkError> {
+        let mut deletions = Vec::new();
+
+        // iptables has no transactions, so all rules are looked up before the first one is deleted
+        for rule in unique_rules(rules) {
+            let (table, _, chain_name) = iptables_chain(rule.chain);
+            let rule_args = iptables_rule_args(rule)?;
+
+            for program in iptables_programs(context, rule_nf_family(context, rule)) {
+                if !iptables_query::<B>(program, &iptables_args(table, "-C", chain_name, &rule_args)).await? {
+                    return Err(FirecrackerNetworkError::ObjectNotFound(rule.object_type));
+                }
+
+                deletions.push((program, table, chain_name, rule_args.clone()));
+            }
+        }
+
+        for (program, table, chain_name, rule_args) in deletions {
+            iptables_apply::<B>(program, &iptables_args(table, "-D", chain_name, &rule_args)).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Add the missing base chains and rules to the batch, judging by the given ruleset.
+pub fn add_nft_rules_to_batch(
+    context: &RuleContext<'_>,
+    current_ruleset: &Nftables,
+    rules: &[NetworkRule],
+    batch: &mut Batch<'static>,
+) -> Result<(), FirecrackerNetworkError> {
+    add_base_chains_if_needed(context, current_ruleset, batch)?;
+
+    for rule in unique_rules(rules) {
+        if find_nft_rule_handle(context, current_ruleset, rule).is_none() {
+            batch.add(NfListObject::Rule(nft_rule(context, rule, None)));
+        }
+    }
+
+    Ok(())
+}
+
+/// Build the batch that adding or deleting the rules makes to the current ruleset of the current netns, which is shared
+/// by applying the rules via the [NftablesRuleBackend] and rendering them. Other operations yield an empty batch.
+pub async fn nft_rules_batch<B: Backend>(
+    context: &RuleContext<'_>,
+    rules: &[NetworkRule],
+    operation: FirecrackerNetworkOperation,
+) -> Result<Batch<'static>, FirecrackerNetworkError> {
+    let current_ruleset = B::NftablesDriver::get_current_ruleset_with_args(context.nft_path, NO_NFT_ARGS)
+        .await
+        .map_err(FirecrackerNetworkError::NftablesError)?;
+    let mut batch = Batch::new();
+
+    match operation {
+        FirecrackerNetworkOperation::Add => add_nft_rules_to_batch(context, &current_ruleset, rules, &mut batch)?,
+        FirecrackerNetworkOperation::Delete => delete_nft_rules_to_batch(context, &current_ruleset, rules, &mut batch)?,
+        FirecrackerNetworkOperation::Check | FirecrackerNetworkOperation::Flush => {}
+    }
+
+    Ok(batch)
+}
+
+/// Add the deletions of the rules to the batch by their handles in the given ruleset, failing if one doesn't exist.
+pub fn delete_nft_rules_to_batch(
+    context: &RuleContext<'_>,
+    current_ruleset: &Nftables,
+    rules: &[NetworkRule],
+    batch: &mut Batch<'static>,
+) -> Result<(), FirecrackerNetworkError> {
+    for rule in unique_rules(rules) {
+        let handle = find_nft_rule_handle(context, current_ruleset, rule)
+            .ok_or(FirecrackerNetworkError::ObjectNotFound(rule.object_type))?;
+        batch.delete(NfListObject::Rule(nft_rule(context, rule, Some(handle))));
+    }
+
+    Ok(())
+}
+
+/// Add in-place replacements of the rules that exist in the given ruleset with their counterparts at the same position,
+/// skipping rules that are unchanged, missing or already replaced via an earlier repetition.
+pub fn replace_nft_rules_to_batch(
+    context: &RuleContext<'_>,
+    current_ruleset: &Nftables,
+    from_rules: &[NetworkRule],
+    to_rules: &[NetworkRule],
+    batch: &mut Batch<'static>,
+) -> bool {
+    let mut replaced_handles = Vec::new();
+
+    for (from_rule, to_rule) in from_rules.iter().zip(to_rules) {
+        if from_rule.chain != to_rule.chain || from_rule.expr == to_rule.expr {
+            continue;
+        }
+
+        if let Some(handle) = find_nft_rule_handle(context, current_ruleset, from_rule) {
+            if !replaced_handles.contains(&handle) {
+                batch.add_cmd(NfCmd::Replace(nft_rule(context, to_rule, Some(handle))));
+                replaced_handles.push(handle);
+            }
+        }
+    }
+
+    !replaced_handles.is_empty()
+}
+
+/// Whether any rule in fcnet's nftables table or iptables chains matches the interface as its input or output
+/// interface. Both backends are inspected regardless of the context's backend, since other networks sharing the
+/// interface may use either one, while a backend that isn't installed on the host has no rules. Rules of other tables
+/// and chains belong to the host's firewall and don't keep the interface in use by fcnet.
+pub async fn any_rule_matches_interface<B: Backend>(
+    context: &RuleContext<'_>,
+    interface_name: &str,
+) -> Result<bool, FirecrackerNetworkError> {
+    let uses_iptables = matches!(context.rule_backend, FirecrackerRuleBackend::Iptables { .. });
+
+    match B::NftablesDriver::get_current_ruleset_with_args(context.nft_path, NO_NFT_ARGS).await {
+        Ok(current_ruleset) => {
+            if current_ruleset.objects.iter().any(|object| match object {
+                NfObject::ListObject(NfListObject::Rule(rule)) if rule.table == context.nft_config.table_name => {
+                    rule.expr.iter().any(|statement| matches_interface(statement, interface_name))
+                }
+                _ => false,
+            }) {
+                return Ok(true);
+            }
+        }
+        Err(_) if uses_iptables => {}
+        Err(err) => return Err(FirecrackerNetworkError::NftablesError(err)),
+    }
+
+    for chain in [
+        RuleChain::Postrouting,
+        RuleChain::Prerouting,
+        RuleChain::Mangle,
+        RuleChain::Filter,
+    ] {
+        let (table, _, chain_name) = iptables_chain(chain);
+
+        for program in iptables_programs(context, NfFamily::INet) {
+            let output = match run_iptables::<B>(program, &["-t", table, "-S", chain_name]).await {
+                Ok(output) => output,
+                Err(_) if !uses_iptables => continue,
+                Err(err) => return Err(err),
+            };
+
+            // the chain doesn't exist if no network has added rules to it via this binary
+            if !output.status.success() {
+                continue;
+            }
+
+            if String::from_utf8_lossy(&output.stdout).lines().any(|line| {
+                line.split_whitespace()
+                    .collect::<Vec<_>>()
+                    .windows(2)
+                    .any(|pair| matches!(pair, ["-i" | "-o", name] if *name == interface_name))
+            }) {
+                return Ok(true);
+            }
+        }
+    }
+
+    Ok(false)
+}
+
+/// Find the handle of the rule in the given ruleset, matching it by its chain and statements.
+pub fn find_nft_rule_handle(context: &RuleContext<'_>, current_ruleset: &Nftables, rule: &NetworkRule) -> Option<u32> {
+    current_ruleset.objects.iter().find_map(|object| match object {
+        NfObject::ListObject(NfListObject::Rule(current_rule))
+            if current_rule.family == context.nf_family
+                && current_rule.table == context.nft_config.table_name
+                && current_rule.chain == nft_chain_name(context, rule.chain)
+                && current_rule.expr == rule.expr =>
+        {
+            current_rule.handle
+        }
+        _ => None,
+    })
+}
+
+/// Ensure that the rule backend is nftables for a feature whose rules can only be applied via nftables.
+pub fn ensure_nftables(rule_backend: &FirecrackerRuleBackend, feature: &'static str) -> Result<(), FirecrackerNetworkError> {
+    match rule_backend {
+        FirecrackerRuleBackend::Nftables => Ok(()),
+        FirecrackerRuleBackend::Iptables { .. } => Err(FirecrackerNetworkError::UnsupportedRuleBackend(feature)),
+    }
+}
+
+/// The given rules without repetitions, keeping the first occurrence. Different objects of a network may translate into
+/// the same rule, e.g. the accept rules of two port forwards to the same guest port, which must only be added and
+/// deleted once.
+pub fn unique_rules(rules: &[NetworkRule]) -> Vec<&NetworkRule> {
+    let mut unique: Vec<&NetworkRule> = Vec::with_capacity(rules.len());
+
+    for rule in rules {
+        if !unique
+            .iter()
+            .any(|unique_rule| unique_rule.chain == rule.chain && unique_rule.expr == rule.expr)
+        {
+            unique.push(rule);
+        }
+    }
+
+    unique
+}
+
+#[inline]
+fn nft_rule(context: &RuleContext<'_>, rule: &NetworkRule, handle: Option<u32>) -> Rule<'static> {
+    Rule {
+        family: context.nf_family,
+        table: context.nft_config.table_name.clone().into(),
+        chain: nft_chain_name(context, rule.chain).to_string().into(),
+        expr: rule.expr.clone().into(),
+        handle,
+        index: None,
+        comment: None,
+    }
+}
+
+#[inline]
+pub fn nft_chain_name<'a>(context: &'a RuleContext<'_>, chain: RuleChain) -> &'a str {
+    match chain {
+        RuleChain::Postrouting => &context.nft_config.postrouting_chain_name,
+        RuleChain::Prerouting => &context.nft_config.prerouting_chain_name,
+        RuleChain::Mangle => &context.nft_config.mangle_chain_name,
+        RuleChain::Filter => &context.nft_config.filter_chain_name,
+    }
+}
+
+/// The chains that are needed by the context or contain any of the given rules, in the order that nftables base
+/// chains are created in. The mangle chain is only created for rules placed into it, since the mark rules of policy
+/// routing are always applied via nftables.
+fn base_chains(context: &RuleContext<'_>, rules: &[NetworkRule]) -> Vec<RuleChain> {
+    [
+        (RuleChain::Postrouting, true),
+        (RuleChain::Prerouting, context.needs_prerouting_chain),
+        (RuleChain::Mangle, false),
+        (RuleChain::Filter, context.needs_filter_chain),
+    ]
+    .into_iter()
+    .filter(|(chain, needed)| *needed || rules.iter().any(|rule| rule.chain == *chain))
+    .map(|(chain, _)| chain)
+    .collect()
+}
+
+/// The iptables table, built-in chain and dedicated chain of a [RuleChain].
+#[inline]
+fn iptables_chain(chain: RuleChain) -> (&'static str, &'static str, &'static str) {
+    match chain {
+        RuleChain::Postrouting => ("nat", "POSTROUTING", "FCNET-POSTROUTING"),
+        RuleChain::Prerouting => ("nat", "PREROUTING", "FCNET-PREROUTING"),
+        RuleChain::Mangle => ("mangle", "PREROUTING", "FCNET-MANGLE"),
+        RuleChain::Filter => ("filter", "FORWARD", "FCNET-FORWARD"),
+    }
+}
+
+#[inline]
+fn chain_object_type(chain: RuleChain) -> FirecrackerNetworkObjectType {
+    match chain {
+        RuleChain::Postrouting => FirecrackerNetworkObjectType::NfPostroutingChain,
+        RuleChain::Prerouting => FirecrackerNetworkObjectType::NfPreroutingChain,
+        RuleChain::Mangle => FirecrackerNetworkObjectType::NfMangleChain,
+        RuleChain::Filter => FirecrackerNetworkObjectType::NfFilterChain,
+    }
+}
+
+/// The family of a rule, which is narrowed down from an "inet" context to the family of the addresses it matches
+/// or, without such a match, translates to, since iptables and ip6tables rules can't be shared between both families.
+fn rule_nf_family(context: &RuleContext<'_>, rule: &NetworkRule) -> NfFamily {
+    let match_family = rule.expr.iter().find_map(|statement| match statement {
+        Statement::Match(Match {
+            left: Expression::Named(NamedExpression::Payload(Payload::PayloadField(PayloadField { protocol, field: _ }))),
+            right: _,
+            op: _,
+        }) => match protocol.as_ref() {
+            "ip" => Some(NfFamily::IP),
+            "ip6" => Some(NfFamily::IP6),
+            _ => None,
+        },
+        _ => None,
+    });
+
+    let nat_family = || {
+        rule.expr.iter().find_map(|statement| match statement {
+            Statement::SNAT(Some(NAT {
+                addr: Some(Expression::String(addr)),
+                ..
+            }))
+            | Statement::DNAT(Some(NAT {
+                addr: Some(Expression::String(addr)),
+                ..
+            })) => match addr.parse::<IpAddr>() {
+                Ok(IpAddr::V4(_)) => Some(NfFamily::IP),
+                Ok(IpAddr::V6(_)) => Some(NfFamily::IP6),
+                Err(_) => None,
+            },
+            _ => None,
+        })
+    };
+
+    match_family.or_else(nat_family).unwrap_or(context.nf_family)
+}
+
+fn iptables_programs<'a>(context: &'a RuleContext<'_>, nf_family: NfFamily) -> Vec<&'a str> {
+    let (iptables_path, ip6tables_path) = match context.rule_backend {
+        FirecrackerRuleBackend::Iptables {
+            iptables_path,
+            ip6tables_path,
+        } => (iptables_path.as_deref(), ip6tables_path.as_deref()),
+        FirecrackerRuleBackend::Nftables => (None, None),
+    };
+    let iptables_program = iptables_path.unwrap_or("iptables");
+    let ip6tables_program = ip6tables_path.unwrap_or("ip6tables");
+
+    match nf_family {
+        NfFamily::IP => vec![iptables_program],
+        NfFamily::IP6 => vec![ip6tables_program],
+        _ => vec![iptables_program, ip6tables_program],
+    }
+}
+
+/// Translate the statements of a rule into the iptables arguments that follow the chain name, failing for statements
+/// that have no iptables equivalent.
+fn iptables_rule_args(rule: &NetworkRule) -> Result<Vec<String>, FirecrackerNetworkError> {
+    let mut args = Vec::new();
+
+    for statement in &rule.expr {
+        let statement_args: Vec<String> = match statement {
+            Statement::Match(Match {
+                left: Expression::Named(NamedExpression::Meta(Meta { key })),
+                right: Expression::String(name),
+                op: Operator::EQ,
+            }) => match key {
+                MetaKey::Iifname => vec!["-i".into(), name.to_string()],
+                MetaKey::Oifname => vec!["-o".into(), name.to_string()],
+                _ => return Err(unsupported_statement(statement)),
+            },
+            Statement::Match(Match {
+                left: Expression::Named(NamedExpression::Payload(Payload::PayloadField(PayloadField { protocol, field }))),
+                right,
+                op: Operator::EQ,
+            }) => match (protocol.as_ref(), field.as_ref(), right) {
+                ("ip" | "ip6", "saddr", Expression::String(addr)) => vec!["-s".into(), addr.to_string()],
+                ("ip" | "ip6", "daddr", Expression::String(addr)) => vec!["-d".into(), addr.to_string()],
+                ("tcp" | "udp", "dport", Expression::Number(port)) => {
+                    vec!["-p".into(), protocol.to_string(), "--dport".into(), port.to_string()]
+                }
+                _ => return Err(unsupported_statement(statement)),
+            },
+            Statement::Accept(None) => vec!["-j".into(), "ACCEPT".into()],
+            Statement::Masquerade(None) => vec!["-j".into(), "MASQUERADE".into()],
+            Statement::SNAT(Some(NAT {
+                addr: Some(Expression::String(addr)),
+                port: None,
+                ..
+            })) => vec!["-j".into(), "SNAT".into(), "--to-source".into(), addr.to_string()],
+            Statement::DNAT(Some(NAT {
+                addr: Some(Expression::String(addr)),
+                port,
+                ..
+            })) => {
+                let destination = match port {
+                    None => addr.to_string(),
+                    Some(Expression::Number(port)) if addr.contains(':') => format!("[{addr}]:{port}"),
+                    Some(Expression::Number(port)) => format!("{addr}:{port}"),
+                    Some(_) => return Err(unsupported_statement(statement)),
+                };
+
+                vec!["-j".into(), "DNAT".into(), "--to-destination".into(), destination]
+            }
+            _ => return Err(unsupported_statement(statement)),
+        };
+
+        args.extend(statement_args);
+    }
+
+    Ok(args)
+}
+
+#[inline]
+fn iptables_args<'a>(table: &'a str, command: &'a str, chain_name: &'a str, rule_args: &'a [String]) -> Vec<&'a str> {
+    let mut args = vec!["-t", table, command, chain_name];
+    args.extend(rule_args.iter().map(String::as_str));
+    args
+}
+
+#[inline]
+fn unsupported_statement(statement: &Statement) -> FirecrackerNetworkError {
+    FirecrackerNetworkError::IptablesError(format!("The statement has no iptables equivalent: {statement:?}"))
+}
+
+/// Run an iptables command that only inspects the current rules, which exits with 1 when the inspected rule or
+/// chain doesn't exist.
+async fn iptables_query<B: Backend>(program: &str, args: &[&str]) -> Result<bool, FirecrackerNetworkError> {
+    let output = run_iptables::<B>(program, args).await?;
+
+    match output.status.code() {
+        Some(0) => Ok(true),
+        Some(1) => Ok(false),
+        _ => Err(iptables_error(&output)),
+    }
+}
+
+async fn iptables_apply<B: Backend>(program: &str, args: &[&str]) -> Result<(), FirecrackerNetworkError> {
+    let output = run_iptables::<B>(program, args).await?;
+
+    match output.status.success() {
+        true => Ok(()),
+        false => Err(iptables_error(&output)),
+    }
+}
+
+async fn run_iptables<B: Backend>(program: &str, args: &[&str]) -> Result<Output, FirecrackerNetworkError> {
+    // wait for the xtables lock instead of failing when another process holds it
+    let mut process_args = vec![OsStr::new("-w")];
+    process_args.extend(args.iter().map(OsStr::new));
+
+    B::NftablesDriver::run_process(OsStr::new(program), &process_args, None)
+        .await
+        .map_err(FirecrackerNetworkError::IoError)
+}
+
+#[inline]
+fn iptables_error(output: &Output) -> FirecrackerNetworkError {
+    FirecrackerNetworkError::IptablesError(String::from_utf8_lossy(&output.stderr).trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use fcnet_types::{
+        FirecrackerIpStack, FirecrackerNetwork, FirecrackerNetworkType, FirecrackerPortForward, FirecrackerPortProtocol,
+        FirecrackerRuleBackend,
+    };
+    use nftables::{stmt::Statement, types::NfFamily};
+
+    use super::{iptables_rule_args, rule_nf_family, unique_rules, NetworkRule, RuleChain};
+    use crate::util::FirecrackerNetworkExt;
+    use crate::{simple::network_rules, FirecrackerNetworkError, FirecrackerNetworkObjectType};
+
+    fn network(ip_stack: FirecrackerIpStack, guest_ip: &str, tap_ip: &str) -> FirecrackerNetwork {
+        FirecrackerNetwork {
+            nft_path: None,
+            nft_config: Default::default(),
+            rule_backend: FirecrackerRuleBackend::Iptables {
+                iptables_path: None,
+                ip6tables_path: None,
+            },
+            ip_stack,
+            iface_name: Some("eth0".to_string()),
+            tap_name: "tap0".to_string(),
+            tap_ip: tap_ip.parse().unwrap(),
+            guest_ip: guest_ip.parse().unwrap(),
+            guest_mac: None,
+            network_type: FirecrackerNetworkType::Simple,
+            nat_ip: None,
+            port_forwards: Vec::new(),
+            vlan_id: None,
+            vlan_ip: None,
+            policy_route: None,
+            flowtable: false,
+        }
+    }
+
+    fn rule_args(rules: &[NetworkRule], object_type: FirecrackerNetworkObjectType) -> Vec<Vec<String>> {
+        rules
+            .iter()
+            .filter(|rule| rule.object_type == object_type)
+            .map(|rule| iptables_rule_args(rule).unwrap())
+            .collect()
+    }
+
+    fn args(args: &str) -> Vec<String> {
+        args.split(' ').map(String::from).collect()
+    }
+
+    #[test]
+    fn masquerade_and_forward_rules_are_translated() {
+        let rules = network_rules(&network(FirecrackerIpStack::V4, "172.16.0.2/24", "172.16.0.1/24")).unwrap();
+
+        assert_eq!(
+            rule_args(&rules, FirecrackerNetworkObjectType::NfMasqueradeRule),
+            [args("-s 172.16.0.2 -o eth0 -j MASQUERADE")]
+        );
+        assert_eq!(
+            rule_args(&rules, FirecrackerNetworkObjectType::NfEgressForwardRule),
+            [args("-i tap0 -o eth0 -j ACCEPT")]
+        );
+    }
+
+    #[test]
+    fn snat_and_dnat_rules_are_translated() {
+        let mut network = network(FirecrackerIpStack::V4, "172.16.0.2/24", "172.16.0.1/24");
+        network.nat_ip = Some("10.0.0.5".parse().unwrap());
+        let rules = network_rules(&network).unwrap();
+
+        assert_eq!(
+            rule_args(&rules, FirecrackerNetworkObjectType::NfMasqueradeRule),
+            [args("-s 172.16.0.2 -o eth0 -j SNAT --to-source 10.0.0.5")]
+        );
+        assert_eq!(
+            rule_args(&rules, FirecrackerNetworkObjectType::NfIngressDnatRule),
+            [args("-i eth0 -d 10.0.0.5 -j DNAT --to-destination 172.16.0.2")]
+        );
+        assert_eq!(
+            rule_args(&rules, FirecrackerNetworkObjectType::NfIngressForwardRule),
+            [args("-i eth0 -o tap0 -d 172.16.0.2 -j ACCEPT")]
+        );
+    }
+
+    #[test]
+    fn port_forward_rules_are_translated() {
+        let mut network = network(FirecrackerIpStack::V4, "172.16.0.2/24", "172.16.0.1/24");
+        network.port_forwards = vec![
+            FirecrackerPortForward {
+                host_ip: None,
+                host_port: 8080,
+                protocol: FirecrackerPortProtocol::Tcp,
+                guest_port: 80,
+            },
+            FirecrackerPortForward {
+                host_ip: Some("10.0.0.5".parse().unwrap()),
+                host_port: 5353,
+                protocol: FirecrackerPortProtocol::Udp,
+                guest_port: 53,
+            },
+        ];
+        let rules = network_rules(&network).unwrap();
+
+        assert_eq!(
+            rule_args(&rules, FirecrackerNetworkObjectType::NfIngressDnatRule),
+            [
+                args("-i eth0 -p tcp --dport 8080 -j DNAT --to-destination 172.16.0.2:80"),
+                args("-i eth0 -d 10.0.0.5 -p udp --dport 5353 -j DNAT --to-destination 172.16.0.2:53"),
+            ]
+        );
+        assert_eq!(
+            rule_args(&rules, FirecrackerNetworkObjectType::NfIngressForwardRule),
+            [
+                args("-i eth0 -o tap0 -d 172.16.0.2 -p tcp --dport 80 -j ACCEPT"),
+                args("-i eth0 -o tap0 -d 172.16.0.2 -p udp --dport 53 -j ACCEPT"),
+            ]
+        );
+    }
+
+    #[test]
+    fn ipv6_dnat_destination_is_bracketed() {
+        let mut network = network(FirecrackerIpStack::V6, "fd00::2/64", "fd00::1/64");
+        network.port_forwards = vec![FirecrackerPortForward {
+            host_ip: None,
+            host_port: 8080,
+            protocol: FirecrackerPortProtocol::Tcp,
+            guest_port: 80,
+        }];
+        let rules = network_rules(&network).unwrap();
+
+        assert_eq!(
+            rule_args(&rules, FirecrackerNetworkObjectType::NfIngressDnatRule),
+            [args("-i eth0 -p tcp --dport 8080 -j DNAT --to-destination [fd00::2]:80")]
+        );
+    }
+
+    #[test]
+    fn dual_stack_dnat_family_follows_the_guest_address() {
+        let mut network = network(FirecrackerIpStack::Dual, "172.16.0.2/24", "172.16.0.1/24");
+        network.port_forwards = vec![FirecrackerPortForward {
+            host_ip: None,
+            host_port: 8080,
+            protocol: FirecrackerPortProtocol::Tcp,
+            guest_port: 80,
+        }];
+        let rules = network_rules(&network).unwrap();
+        let context = network.rule_context();
+
+        let dnat_families = rules
+            .iter()
+            .filter(|rule| rule.object_type == FirecrackerNetworkObjectType::NfIngressDnatRule)
+            .map(|rule| rule_nf_family(&context, rule))
+            .collect::<Vec<_>>();
+
+        assert_eq!(dnat_families, [NfFamily::IP]);
+    }
+
+    #[test]
+    fn unresolved_host_interface_is_rejected() {
+        let mut network = network(FirecrackerIpStack::V4, "172.16.0.2/24", "172.16.0.1/24");
+        network.iface_name = None;
+
+        assert!(matches!(
+            network_rules(&network),
+            Err(FirecrackerNetworkError::UnresolvedIfaceName)
+        ));
+    }
+
+    #[test]
+    fn statements_without_equivalent_are_rejected() {
+        let rule = NetworkRule {
+            chain: RuleChain::Filter,
+            expr: vec![Statement::Drop(None)],
+            object_type: FirecrackerNetworkObjectType::NfEgressForwardRule,
+        };
+
+        assert!(iptables_rule_args(&rule).is_err());
+    }
+
+    #[test]
+    fn identical_rules_are_deduplicated() {
+        let mut network = network(FirecrackerIpStack::V4, "172.16.0.2/24", "172.16.0.1/24");
+        network.port_forwards = [8080, 8081]
+            .map(|host_port| FirecrackerPortForward {
+                host_ip: None,
+                host_port,
+                protocol: FirecrackerPortProtocol::Tcp,
+                guest_port: 80,
+            })
+            .to_vec();
+        let rules = network_rules(&network).unwrap();
+        let unique = unique_rules(&rules);
+
+        // both DNAT rules are kept, while the accept rule for the shared guest port is only kept once
+        assert_eq!(rules.len(), 6);
+        assert_eq!(unique.len(), 5);
+        assert_eq!(
+            unique
+                .iter()
+                .filter(|rule| rule.object_type == FirecrackerNetworkObjectType::NfIngressDnatRule)
+                .count(),
+            2
+        );
+    }
+}
@@ -1,31 +1,35 @@
-use fcnet_types::FirecrackerNetwork;
+use std::net::IpAddr;
+
+use fcnet_types::{FirecrackerNetwork, FirecrackerPortForward};
 use nftables::{
-    batch::Batch,
     expr::{Expression, Meta, MetaKey, NamedExpression, Payload, PayloadField},
-    schema::{NfListObject, NfObject, Rule},
     stmt::{Match, Operator, Statement},
 };
-use nftables_async::helper::Helper;
 use tokio_tun::TunBuilder;
 
 use crate::{
     backend::Backend,
+    conntrack,
+    rules::{self, NetworkRule, NftRuleDeletion, RuleChain},
     util::{
-        add_base_chains_if_needed, check_base_chains, get_link_index, nat_proto_from_addr, FirecrackerNetworkExt, NO_NFT_ARGS,
+        add_guest_neighbour, check_guest_neighbour, egress_nat_statement, get_link_index, nat_ip_dnat_expr, nat_proto_from_addr,
+        port_forward_accept_expr, port_forward_dnat_expr, tap_egress_forward_expr, tap_ingress_forward_expr,
+        FirecrackerNetworkExt,
     },
-    FirecrackerNetworkError, FirecrackerNetworkObjectType, FirecrackerNetworkOperation, NFT_FILTER_CHAIN, NFT_POSTROUTING_CHAIN,
-    NFT_TABLE,
+    FirecrackerNetworkError, FirecrackerNetworkObjectType, FirecrackerNetworkOperation,
 };
 
 pub async fn run<B: Backend>(
     network: &FirecrackerNetwork,
     netlink_handle: rtnetlink::Handle,
     operation: FirecrackerNetworkOperation,
+    deletion: NftRuleDeletion,
 ) -> Result<(), FirecrackerNetworkError> {
     match operation {
         FirecrackerNetworkOperation::Add => add::<B>(network, netlink_handle).await,
         FirecrackerNetworkOperation::Check => check::<B>(network, netlink_handle).await,
-        FirecrackerNetworkOperation::Delete => delete::<B>(network, netlink_handle).await,
+        FirecrackerNetworkOperation::Delete => delete::<B>(network, netlink_handle, deletion).await,
+        FirecrackerNetworkOperation::Flush => conntrack::flush::<B>(&[network.guest_ip.address()]).await,
     }
 }
 
@@ -44,59 +48,21 @@ async fn add<B: Backend>(network: &FirecrackerNetwork, netlink_handle: rtnetlink
         .execute()
         .await
         .map_err(FirecrackerNetworkError::NetlinkOperationError)?;
-
-    let current_ruleset = B::NftablesDriver::get_current_ruleset_with_args(network.nft_program(), NO_NFT_ARGS)
-        .await
-        .map_err(FirecrackerNetworkError::NftablesError)?;
-    let mut masquerade_rule_exists = false;
-
-    for object in current_ruleset.objects.iter() {
-        match object {
-            NfObject::ListObject(object) => match object {
-                NfListObject::Rule(rule)
-                    if rule.chain == NFT_POSTROUTING_CHAIN && rule.table == NFT_TABLE && rule.expr == masq_expr(network) =>
-                {
-                    masquerade_rule_exists = true;
-                }
-                _ => continue,
-            },
-            _ => continue,
-        }
-    }
-
-    let mut batch = Batch::new();
-    add_base_chains_if_needed(&network, &current_ruleset, &mut batch)?;
-
-    batch.add(NfListObject::Rule(Rule {
-        family: network.nf_family(),
-        table: NFT_TABLE.into(),
-        chain: NFT_FILTER_CHAIN.into(),
-        expr: forward_expr(network).into(),
-        handle: None,
-        index: None,
-        comment: None,
-    }));
-
-    if !masquerade_rule_exists {
-        batch.add(NfListObject::Rule(Rule {
-            family: network.nf_family(),
-            table: NFT_TABLE.into(),
-            chain: NFT_POSTROUTING_CHAIN.into(),
-            expr: masq_expr(network).into(),
-            handle: None,
-            index: None,
-            comment: None,
-        }));
-    }
-
-    B::NftablesDriver::apply_ruleset_with_args(&batch.to_nftables(), network.nft_program(), NO_NFT_ARGS)
-        .await
-        .map_err(FirecrackerNetworkError::NftablesError)
+    add_guest_neighbour(
+        network.guest_ip.address(),
+        network.guest_mac.as_deref(),
+        tap_idx,
+        &netlink_handle,
+    )
+    .await?;
+
+    rules::add_rules::<B>(&network.rule_context(), &network_rules(network)?).await
 }
 
 async fn delete<B: Backend>(
     network: &FirecrackerNetwork,
     netlink_handle: rtnetlink::Handle,
+    deletion: NftRuleDeletion,
 ) -> Result<(), FirecrackerNetworkError> {
     let tap_idx = get_link_index(network.tap_name.clone(), &netlink_handle).await?;
     netlink_handle
@@ -106,113 +72,79 @@ async fn delete<B: Backend>(
         .await
         .map_err(FirecrackerNetworkError::NetlinkOperationError)?;
 
-    let current_ruleset = B::NftablesDriver::get_current_ruleset_with_args(network.nft_program(), NO_NFT_ARGS)
-        .await
-        .map_err(FirecrackerNetworkError::NftablesError)?;
-
-    let mut forward_rule_handle = None;
-    let mut masquerade_rule_handle = None;
-
-    for object in current_ruleset.objects.iter() {
-        match object {
-            NfObject::ListObject(object) => match object {
-                NfListObject::Rule(rule) if rule.table == NFT_TABLE => {
-                    if rule.chain == NFT_FILTER_CHAIN && rule.expr == forward_expr(network) {
-                        forward_rule_handle = rule.handle;
-                    } else if rule.chain == NFT_POSTROUTING_CHAIN && rule.expr == masq_expr(network) {
-                        masquerade_rule_handle = rule.handle;
-                    }
-                }
-                _ => continue,
-            },
-            _ => continue,
-        }
-    }
-
-    if forward_rule_handle.is_none() {
-        return Err(FirecrackerNetworkError::ObjectNotFound(
-            FirecrackerNetworkObjectType::NfEgressForwardRule,
-        ));
-    }
-    if masquerade_rule_handle.is_none() {
-        return Err(FirecrackerNetworkError::ObjectNotFound(
-            FirecrackerNetworkObjectType::NfMasqueradeRule,
-        ));
-    }
+    rules::delete_rules_unless_skipped::<B>(&network.rule_context(), &network_rules(network)?, deletion).await?;
 
-    let mut batch = Batch::new();
-    batch.delete(NfListObject::Rule(Rule {
-        family: network.nf_family(),
-        table: NFT_TABLE.into(),
-        chain: NFT_FILTER_CHAIN.into(),
-        expr: forward_expr(network).into(),
-        handle: forward_rule_handle,
-        index: None,
-        comment: None,
-    }));
-    batch.delete(NfListObject::Rule(Rule {
-        family: network.nf_family(),
-        table: NFT_TABLE.into(),
-        chain: NFT_POSTROUTING_CHAIN.into(),
-        expr: masq_expr(network).into(),
-        handle: masquerade_rule_handle,
-        index: None,
-        comment: None,
-    }));
-
-    B::NftablesDriver::apply_ruleset_with_args(&batch.to_nftables(), network.nft_program(), NO_NFT_ARGS)
-        .await
-        .map_err(FirecrackerNetworkError::NftablesError)
+    // the rules are gone, so remove the guest's NAT mappings before its IP gets reused by another VM
+    conntrack::log_failed_delete_flush(conntrack::flush::<B>(&[network.guest_ip.address()]).await);
+    Ok(())
 }
 
 async fn check<B: Backend>(
     network: &FirecrackerNetwork,
     netlink_handle: rtnetlink::Handle,
 ) -> Result<(), FirecrackerNetworkError> {
-    get_link_index(network.tap_name.clone(), &netlink_handle).await?;
-
-    let current_ruleset = B::NftablesDriver::get_current_ruleset_with_args(network.nft_program(), NO_NFT_ARGS)
-        .await
-        .map_err(FirecrackerNetworkError::NftablesError)?;
-    let mut masquerade_rule_exists = false;
-    let mut forward_rule_exists = false;
-
-    check_base_chains(network, &current_ruleset)?;
-
-    for object in current_ruleset.objects.iter() {
-        match object {
-            NfObject::ListObject(object) => match object {
-                NfListObject::Rule(rule) => {
-                    if rule.chain == NFT_POSTROUTING_CHAIN && rule.expr == masq_expr(network) {
-                        masquerade_rule_exists = true;
-                    } else if rule.chain == NFT_FILTER_CHAIN && rule.expr == forward_expr(network) {
-                        forward_rule_exists = true;
-                    }
-                }
-                _ => continue,
-            },
-            _ => continue,
-        }
-    }
+    let tap_idx = get_link_index(network.tap_name.clone(), &netlink_handle).await?;
+    check_guest_neighbour(
+        network.guest_ip.address(),
+        network.guest_mac.as_deref(),
+        tap_idx,
+        &netlink_handle,
+    )
+    .await?;
+
+    rules::check_rules::<B>(&network.rule_context(), &network_rules(network)?).await
+}
 
-    if !masquerade_rule_exists {
-        return Err(FirecrackerNetworkError::ObjectNotFound(
-            FirecrackerNetworkObjectType::NfMasqueradeRule,
-        ));
+/// The rules of a simple network, which are applied via the configured rule backend.
+pub fn network_rules(network: &FirecrackerNetwork) -> Result<Vec<NetworkRule>, FirecrackerNetworkError> {
+    let mut rules = vec![
+        NetworkRule {
+            chain: RuleChain::Postrouting,
+            expr: masq_expr(network)?,
+            object_type: FirecrackerNetworkObjectType::NfMasqueradeRule,
+        },
+        NetworkRule {
+            chain: RuleChain::Filter,
+            expr: tap_egress_forward_expr(network)?,
+            object_type: FirecrackerNetworkObjectType::NfEgressForwardRule,
+        },
+    ];
+
+    // DNAT packets arriving to the forwarded host ports to the guest and accept them in the forward chain
+    for port_forward in &network.port_forwards {
+        rules.push(NetworkRule {
+            chain: RuleChain::Prerouting,
+            expr: ingress_dnat_expr(network, port_forward)?,
+            object_type: FirecrackerNetworkObjectType::NfIngressDnatRule,
+        });
+        rules.push(NetworkRule {
+            chain: RuleChain::Filter,
+            expr: ingress_forward_expr(network, port_forward)?,
+            object_type: FirecrackerNetworkObjectType::NfIngressForwardRule,
+        });
     }
 
-    if !forward_rule_exists {
-        return Err(FirecrackerNetworkError::ObjectNotFound(
-            FirecrackerNetworkObjectType::NfEgressForwardRule,
-        ));
+    // DNAT packets arriving to the dedicated NAT ip to the guest and accept them in the forward chain.
+    // This comes after the port forwards, which would otherwise be shadowed if forwarding from the NAT ip
+    if let Some(nat_ip) = network.nat_ip {
+        rules.push(NetworkRule {
+            chain: RuleChain::Prerouting,
+            expr: nat_dnat_expr(network, nat_ip)?,
+            object_type: FirecrackerNetworkObjectType::NfIngressDnatRule,
+        });
+        rules.push(NetworkRule {
+            chain: RuleChain::Filter,
+            expr: tap_ingress_forward_expr(network)?,
+            object_type: FirecrackerNetworkObjectType::NfIngressForwardRule,
+        });
     }
 
-    Ok(())
+    Ok(rules)
 }
 
 #[inline]
-fn masq_expr(network: &FirecrackerNetwork) -> Vec<Statement<'static>> {
-    vec![
+fn masq_expr(network: &FirecrackerNetwork) -> Result<Vec<Statement<'static>>, FirecrackerNetworkError> {
+    Ok(vec![
         Statement::Match(Match {
             left: Expression::Named(NamedExpression::Payload(Payload::PayloadField(PayloadField {
                 protocol: nat_proto_from_addr(network.guest_ip.address()),
@@ -223,26 +155,45 @@ fn masq_expr(network: &FirecrackerNetwork) -> Vec<Statement<'static>> {
         }),
         Statement::Match(Match {
             left: Expression::Named(NamedExpression::Meta(Meta { key: MetaKey::Oifname })),
-            right: Expression::String(network.iface_name.clone().into()),
+            right: Expression::String(network.uplink_name()?.into()),
             op: Operator::EQ,
         }),
-        Statement::Masquerade(None),
-    ]
+        egress_nat_statement(network),
+    ])
 }
 
 #[inline]
-fn forward_expr(network: &FirecrackerNetwork) -> Vec<Statement<'static>> {
-    vec![
-        Statement::Match(Match {
-            left: Expression::Named(NamedExpression::Meta(Meta { key: MetaKey::Iifname })),
-            right: Expression::String(network.tap_name.clone().into()),
-            op: Operator::EQ,
-        }),
-        Statement::Match(Match {
-            left: Expression::Named(NamedExpression::Meta(Meta { key: MetaKey::Oifname })),
-            right: Expression::String(network.iface_name.clone().into()),
-            op: Operator::EQ,
-        }),
-        Statement::Accept(None),
-    ]
+fn nat_dnat_expr(network: &FirecrackerNetwork, nat_ip: IpAddr) -> Result<Vec<Statement<'static>>, FirecrackerNetworkError> {
+    Ok(nat_ip_dnat_expr(
+        &network.uplink_name()?,
+        nat_ip,
+        network.guest_ip.address(),
+        network.nf_family(),
+    ))
+}
+
+#[inline]
+fn ingress_dnat_expr(
+    network: &FirecrackerNetwork,
+    port_forward: &FirecrackerPortForward,
+) -> Result<Vec<Statement<'static>>, FirecrackerNetworkError> {
+    Ok(port_forward_dnat_expr(
+        &network.uplink_name()?,
+        port_forward,
+        network.guest_ip.address(),
+        network.nf_family(),
+    ))
+}
+
+#[inline]
+fn ingress_forward_expr(
+    network: &FirecrackerNetwork,
+    port_forward: &FirecrackerPortForward,
+) -> Result<Vec<Statement<'static>>, FirecrackerNetworkError> {
+    Ok(port_forward_accept_expr(
+        &network.uplink_name()?,
+        &network.tap_name,
+        port_forward,
+        network.guest_ip.address(),
+    ))
 }
@@ -0,0 +1,109 @@
+use std::net::IpAddr;
+
+use fcnet_types::{FirecrackerNetwork, FirecrackerNetworkOperation, FirecrackerRuleBackend};
+use futures_util::{Stream, StreamExt};
+use nftables::batch::Batch;
+use nftables_async::helper::Helper;
+use rtnetlink::{
+    constants::{RTMGRP_IPV4_ROUTE, RTMGRP_IPV6_ROUTE, RTMGRP_LINK},
+    sys::{AsyncSocket, SocketAddr},
+};
+
+use crate::{
+    backend::Backend,
+    flowtable, policy,
+    render::nft_network_rules,
+    rules::replace_nft_rules_to_batch,
+    util::{get_default_route_iface_name, FirecrackerNetworkExt, NO_NFT_ARGS},
+    vlan, FirecrackerNetworkError,
+};
+
+/// A change of the host interface that the default route goes through, as emitted by [watch_uplink].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UplinkChange {
+    /// The host interface that the default route went through, or [None] if there was no default route.
+    pub from: Option<String>,
+    /// The host interface that the default route goes through now, or [None] if there is no default route.
+    pub to: Option<String>,
+}
+
+/// Watch the host interface that the default route for the address family of the given [IpAddr] goes through via
+/// the given [Backend], by subscribing to the rtnetlink link and route multicast groups and re-resolving the default
+/// route on every event. The returned [Stream] only emits actual changes and ends if the netlink connection closes.
+pub async fn watch_uplink<B: Backend>(addr: IpAddr) -> Result<impl Stream<Item = UplinkChange>, FirecrackerNetworkError> {
+    let (mut connection, netlink_handle, messages) =
+        rtnetlink::new_connection_with_socket::<B::NetlinkSocket>().map_err(FirecrackerNetworkError::IoError)?;
+    connection
+        .socket_mut()
+        .socket_mut()
+        .bind(&SocketAddr::new(0, RTMGRP_LINK | RTMGRP_IPV4_ROUTE | RTMGRP_IPV6_ROUTE))
+        .map_err(FirecrackerNetworkError::IoError)?;
+    B::spawn_connection(connection);
+
+    let current_iface_name = get_default_route_iface_name(addr, &netlink_handle).await.ok();
+
+    Ok(futures_util::stream::unfold(
+        (messages, netlink_handle, current_iface_name),
+        move |(mut messages, netlink_handle, current_iface_name)| async move {
+            while messages.next().await.is_some() {
+                let iface_name = get_default_route_iface_name(addr, &netlink_handle).await.ok();
+
+                if iface_name != current_iface_name {
+                    let change = UplinkChange {
+                        from: current_iface_name,
+                        to: iface_name.clone(),
+                    };
+                    return Some((change, (messages, netlink_handle, iface_name)));
+                }
+            }
+
+            None
+        },
+    ))
+}
+
+/// Switch an added [FirecrackerNetwork] via the given [Backend] from one host interface to another, as is needed
+/// when the default route fails over for a network with an automatically resolved host interface. The network's own
+/// nftables rules that reference the previous interface are replaced in place within a single atomic nftables
+/// transaction, while a VLAN interface, flowtable and policy routing default route follow the switch. Rules of other
+/// networks are left untouched, and networks using the iptables rule backend aren't supported.
+pub async fn switch_uplink<B: Backend>(
+    network: &FirecrackerNetwork,
+    from_iface_name: &str,
+    to_iface_name: &str,
+) -> Result<(), FirecrackerNetworkError> {
+    if let FirecrackerRuleBackend::Iptables { .. } = network.rule_backend {
+        return Err(FirecrackerNetworkError::UnsupportedRuleBackend("switching the uplink"));
+    }
+
+    let (connection, netlink_handle, _) =
+        rtnetlink::new_connection_with_socket::<B::NetlinkSocket>().map_err(FirecrackerNetworkError::IoError)?;
+    B::spawn_connection(connection);
+
+    let from_network = FirecrackerNetwork {
+        iface_name: Some(from_iface_name.to_string()),
+        ..network.clone()
+    };
+    let to_network = FirecrackerNetwork {
+        iface_name: Some(to_iface_name.to_string()),
+        ..network.clone()
+    };
+    vlan::add_if_needed(&to_network, &netlink_handle).await?;
+
+    let current_ruleset = B::NftablesDriver::get_current_ruleset_with_args(network.nft_program(), NO_NFT_ARGS)
+        .await
+        .map_err(FirecrackerNetworkError::NftablesError)?;
+    let from_rules = nft_network_rules(&from_network, &netlink_handle, FirecrackerNetworkOperation::Add).await?;
+    let to_rules = nft_network_rules(&to_network, &netlink_handle, FirecrackerNetworkOperation::Add).await?;
+    let mut batch = Batch::new();
+
+    if replace_nft_rules_to_batch(&network.rule_context(), &current_ruleset, &from_rules, &to_rules, &mut batch) {
+        B::NftablesDriver::apply_ruleset_with_args(&batch.to_nftables(), network.nft_program(), NO_NFT_ARGS)
+            .await
+            .map_err(FirecrackerNetworkError::NftablesError)?;
+    }
+
+    flowtable::add_if_needed::<B>(&to_network).await?;
+    policy::switch_default_route_if_needed(&from_network, &to_network, &netlink_handle).await?;
+    vlan::delete_if_unused::<B>(&from_network, &netlink_handle).await
+}
@@ -1,14 +1,31 @@
-use std::{borrow::Cow, ffi::OsStr, net::IpAddr};
+use std::{
+    borrow::Cow,
+    ffi::OsStr,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
 
-use fcnet_types::{FirecrackerIpStack, FirecrackerNetwork};
+use cidr::IpInet;
+#[cfg(any(feature = "namespaced", feature = "bridge", feature = "macvtap", feature = "ipvlan"))]
+use fcnet_types::FirecrackerNetworkType;
+use fcnet_types::{FirecrackerIpStack, FirecrackerNetwork, FirecrackerPortForward, FirecrackerPortProtocol};
 use futures_util::TryStreamExt;
 use nftables::{
     batch::Batch,
+    expr::{Expression, Meta, MetaKey, NamedExpression, Payload, PayloadField},
     schema::{Chain, NfListObject, NfObject, Nftables, Table},
+    stmt::{Match, NATFamily, Operator, Statement, NAT},
     types::{NfChainPolicy, NfChainType, NfFamily, NfHook},
 };
+use rtnetlink::{
+    packet_route::{
+        link::LinkAttribute,
+        neighbour::{NeighbourAddress, NeighbourAttribute, NeighbourState},
+        route::{RouteAttribute, RouteHeader},
+    },
+    RouteMessageBuilder,
+};
 
-use crate::{FirecrackerNetworkError, FirecrackerNetworkObjectType, NFT_FILTER_CHAIN, NFT_POSTROUTING_CHAIN, NFT_TABLE};
+use crate::{rules::RuleContext, FirecrackerNetworkError, FirecrackerNetworkObjectType};
 
 pub const NO_NFT_ARGS: std::iter::Empty<&OsStr> = std::iter::empty();
 
@@ -26,25 +43,368 @@ pub async fn get_link_index(link: String, netlink_handle: &rtnetlink::Handle) ->
         .index)
 }
 
-pub fn add_base_chains_if_needed(
+/// Get the index and MAC address of a link, by its index if known, in which case the link must still carry the given
+/// name, or by its name otherwise.
+pub async fn get_link_identity(
+    link: &str,
+    link_index: Option<u32>,
+    netlink_handle: &rtnetlink::Handle,
+) -> Result<(u32, Option<String>), FirecrackerNetworkError> {
+    let request = netlink_handle.link().get();
+    let link_message = match link_index {
+        // the kernel rejects the lookup of an unknown index instead of returning nothing
+        Some(link_index) => request
+            .match_index(link_index)
+            .execute()
+            .try_next()
+            .await
+            .map_err(|_| FirecrackerNetworkError::ObjectNotFound(FirecrackerNetworkObjectType::IpLink))?,
+        None => request
+            .match_name(link.to_string())
+            .execute()
+            .try_next()
+            .await
+            .map_err(FirecrackerNetworkError::NetlinkOperationError)?,
+    }
+    .ok_or(FirecrackerNetworkError::ObjectNotFound(FirecrackerNetworkObjectType::IpLink))?;
+
+    let mut link_name = None;
+    let mut link_mac = None;
+
+    for attribute in link_message.attributes {
+        match attribute {
+            LinkAttribute::IfName(name) => link_name = Some(name),
+            LinkAttribute::Address(address) => {
+                link_mac = Some(address.iter().map(|byte| format!("{byte:02x}")).collect::<Vec<_>>().join(":"))
+            }
+            _ => continue,
+        }
+    }
+
+    match link_name.as_deref() == Some(link) {
+        true => Ok((link_message.header.index, link_mac)),
+        false => Err(FirecrackerNetworkError::ObjectNotFound(FirecrackerNetworkObjectType::IpLink)),
+    }
+}
+
+/// Add a permanent neighbour entry of the guest IP with the guest MAC on the tap device, if the guest MAC is set.
+pub async fn add_guest_neighbour(
+    guest_ip: IpAddr,
+    guest_mac: Option<&str>,
+    tap_idx: u32,
+    netlink_handle: &rtnetlink::Handle,
+) -> Result<(), FirecrackerNetworkError> {
+    let Some(guest_mac) = guest_mac else {
+        return Ok(());
+    };
+
+    netlink_handle
+        .neighbours()
+        .add(tap_idx, guest_ip)
+        .link_local_address(&parse_mac(guest_mac)?)
+        .state(NeighbourState::Permanent)
+        .execute()
+        .await
+        .map_err(FirecrackerNetworkError::NetlinkOperationError)
+}
+
+/// Check that the permanent neighbour entry of the guest IP exists on the tap device, if the guest MAC is set.
+pub async fn check_guest_neighbour(
+    guest_ip: IpAddr,
+    guest_mac: Option<&str>,
+    tap_idx: u32,
+    netlink_handle: &rtnetlink::Handle,
+) -> Result<(), FirecrackerNetworkError> {
+    let Some(guest_mac) = guest_mac else {
+        return Ok(());
+    };
+    let guest_mac = parse_mac(guest_mac)?;
+    let mut neighbour_message_stream = netlink_handle.neighbours().get().execute();
+
+    while let Some(neighbour_message) = neighbour_message_stream
+        .try_next()
+        .await
+        .map_err(FirecrackerNetworkError::NetlinkOperationError)?
+    {
+        if neighbour_message.header.ifindex != tap_idx || neighbour_message.header.state != NeighbourState::Permanent {
+            continue;
+        }
+
+        let mut destination = None;
+        let mut link_local_address = None;
+
+        for attribute in &neighbour_message.attributes {
+            match attribute {
+                NeighbourAttribute::Destination(NeighbourAddress::Inet(v4)) => destination = Some(IpAddr::V4(*v4)),
+                NeighbourAttribute::Destination(NeighbourAddress::Inet6(v6)) => destination = Some(IpAddr::V6(*v6)),
+                NeighbourAttribute::LinkLocalAddress(address) => link_local_address = Some(address.as_slice()),
+                _ => continue,
+            }
+        }
+
+        if destination == Some(guest_ip) && link_local_address == Some(guest_mac.as_slice()) {
+            return Ok(());
+        }
+    }
+
+    Err(FirecrackerNetworkError::ObjectNotFound(
+        FirecrackerNetworkObjectType::IpNeighbour,
+    ))
+}
+
+/// Validate the guest MAC of a network if it's set, so that an invalid MAC is rejected before the host is modified.
+pub fn validate_guest_mac(network: &FirecrackerNetwork) -> Result<(), FirecrackerNetworkError> {
+    match network.guest_mac.as_deref() {
+        Some(guest_mac) => parse_mac(guest_mac).map(|_| ()),
+        None => Ok(()),
+    }
+}
+
+/// Validate that the NAT IP of a network and the host IPs of its port forwards, if they're set, have the same family
+/// as the guest IP, so that a mismatch is rejected before the host is modified instead of only when applying the rules.
+pub fn validate_nat_ip(network: &FirecrackerNetwork) -> Result<(), FirecrackerNetworkError> {
+    let guest_is_ipv4 = network.guest_ip.address().is_ipv4();
+
+    match network
+        .nat_ip
+        .into_iter()
+        .chain(network.port_forwards.iter().filter_map(|port_forward| port_forward.host_ip))
+        .any(|host_ip| host_ip.is_ipv4() != guest_is_ipv4)
+    {
+        true => Err(FirecrackerNetworkError::ForbiddenDualStackInNat),
+        false => Ok(()),
+    }
+}
+
+/// Ensure that a network of a type whose guests have routable addresses sets neither a NAT IP nor port forwards,
+/// which only the NAT rules of simple networks implement, instead of silently ignoring them.
+#[cfg(any(feature = "routed", feature = "bridge"))]
+pub fn ensure_no_nat(network: &FirecrackerNetwork, network_type: &'static str) -> Result<(), FirecrackerNetworkError> {
+    match network.nat_ip.is_none() && network.port_forwards.is_empty() {
+        true => Ok(()),
+        false => Err(FirecrackerNetworkError::UnsupportedNat(network_type)),
+    }
+}
+
+/// Parse a MAC in the "aa:bb:cc:dd:ee:ff" notation, which must be a locally administered unicast address so that it
+/// can neither collide with a vendor-assigned MAC nor be a multicast address that the guest's NIC can't use.
+pub fn parse_mac(mac: &str) -> Result<[u8; 6], FirecrackerNetworkError> {
+    let mut octets = [0; 6];
+    let mut parts = mac.split(':');
+
+    for octet in &mut octets {
+        *octet = parts
+            .next()
+            .and_then(|part| u8::from_str_radix(part, 16).ok())
+            .ok_or_else(|| FirecrackerNetworkError::InvalidGuestMac(mac.to_string()))?;
+    }
+
+    // the least significant bit of the first octet marks multicast addresses, the one above it local administration
+    if parts.next().is_some() || octets[0] & 0b01 != 0 || octets[0] & 0b10 == 0 {
+        return Err(FirecrackerNetworkError::InvalidGuestMac(mac.to_string()));
+    }
+
+    Ok(octets)
+}
+
+pub async fn get_default_route_iface_name(
+    addr: IpAddr,
+    netlink_handle: &rtnetlink::Handle,
+) -> Result<String, FirecrackerNetworkError> {
+    let route_filter = match addr {
+        IpAddr::V4(_) => RouteMessageBuilder::<Ipv4Addr>::new().build(),
+        IpAddr::V6(_) => RouteMessageBuilder::<Ipv6Addr>::new().build(),
+    };
+    let mut route_message_stream = netlink_handle.route().get(route_filter).execute();
+    let mut default_route_oif = None;
+
+    // with several default routes, the one with the lowest metric is the one the kernel actually uses
+    while let Some(route_message) = route_message_stream
+        .try_next()
+        .await
+        .map_err(FirecrackerNetworkError::NetlinkOperationError)?
+    {
+        if route_message.header.destination_prefix_length != 0 || route_message.header.table != RouteHeader::RT_TABLE_MAIN {
+            continue;
+        }
+
+        let mut oif = None;
+        let mut priority = 0;
+
+        for attribute in &route_message.attributes {
+            match attribute {
+                RouteAttribute::Oif(idx) => oif = Some(*idx),
+                // a multipath route has no interface of its own, so its first next hop is taken as the uplink
+                RouteAttribute::MultiPath(next_hops) => oif = oif.or(next_hops.first().map(|next_hop| next_hop.interface_index)),
+                RouteAttribute::Priority(metric) => priority = *metric,
+                _ => continue,
+            }
+        }
+
+        if let Some(oif) = oif {
+            if default_route_oif.is_none_or(|(_, current_priority)| priority < current_priority) {
+                default_route_oif = Some((oif, priority));
+            }
+        }
+    }
+
+    let (oif, _) = default_route_oif.ok_or(FirecrackerNetworkError::ObjectNotFound(FirecrackerNetworkObjectType::IpRoute))?;
+    netlink_handle
+        .link()
+        .get()
+        .match_index(oif)
+        .execute()
+        .try_next()
+        .await
+        .map_err(FirecrackerNetworkError::NetlinkOperationError)?
+        .and_then(|link_message| {
+            link_message.attributes.into_iter().find_map(|attribute| match attribute {
+                LinkAttribute::IfName(name) => Some(name),
+                _ => None,
+            })
+        })
+        .ok_or(FirecrackerNetworkError::ObjectNotFound(FirecrackerNetworkObjectType::IpLink))
+}
+
+/// Fill in the host interface from the default route if it isn't configured. It's re-resolved on every operation, so
+/// that a moved default route is detected by checks.
+pub async fn resolve_iface_name<'a>(
+    network: &'a FirecrackerNetwork,
+    netlink_handle: &rtnetlink::Handle,
+) -> Result<Cow<'a, FirecrackerNetwork>, FirecrackerNetworkError> {
+    match network.iface_name {
+        Some(_) => Ok(Cow::Borrowed(network)),
+        None => Ok(Cow::Owned(FirecrackerNetwork {
+            iface_name: Some(get_default_route_iface_name(network.guest_ip.address(), netlink_handle).await?),
+            ..network.clone()
+        })),
+    }
+}
+
+pub async fn find_link_index(link: &str, netlink_handle: &rtnetlink::Handle) -> Result<Option<u32>, FirecrackerNetworkError> {
+    let mut link_message_stream = netlink_handle.link().get().execute();
+
+    while let Some(link_message) = link_message_stream
+        .try_next()
+        .await
+        .map_err(FirecrackerNetworkError::NetlinkOperationError)?
+    {
+        if link_message
+            .attributes
+            .iter()
+            .any(|attribute| matches!(attribute, LinkAttribute::IfName(name) if name == link))
+        {
+            return Ok(Some(link_message.header.index));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Check that the link carries the given IP with its prefix length.
+pub async fn check_link_address(
+    link_idx: u32,
+    ip: &IpInet,
+    netlink_handle: &rtnetlink::Handle,
+) -> Result<(), FirecrackerNetworkError> {
+    let address_message = netlink_handle
+        .address()
+        .get()
+        .set_link_index_filter(link_idx)
+        .set_address_filter(ip.address())
+        .set_prefix_length_filter(ip.network_length())
+        .execute()
+        .try_next()
+        .await
+        .map_err(FirecrackerNetworkError::NetlinkOperationError)?;
+
+    match address_message {
+        Some(_) => Ok(()),
+        None => Err(FirecrackerNetworkError::ObjectNotFound(
+            FirecrackerNetworkObjectType::IpAddress,
+        )),
+    }
+}
+
+#[cfg(any(feature = "macvtap", feature = "ipvlan"))]
+pub fn has_tap_char_device(network: &FirecrackerNetwork) -> bool {
+    match network.network_type {
+        #[cfg(feature = "macvtap")]
+        FirecrackerNetworkType::Macvtap { mode: _ } => true,
+        #[cfg(feature = "ipvlan")]
+        FirecrackerNetworkType::Ipvlan => true,
+        #[allow(unreachable_patterns)]
+        _ => false,
+    }
+}
+
+/// The character device of the macvtap or ipvtap interface named after the tap device, which the kernel names after
+/// the interface's index.
+#[cfg(any(feature = "macvtap", feature = "ipvlan"))]
+pub async fn get_tap_char_device_path(
+    network: &FirecrackerNetwork,
+    netlink_handle: &rtnetlink::Handle,
+) -> Result<String, FirecrackerNetworkError> {
+    let link_idx = get_link_index(network.tap_name.clone(), netlink_handle).await?;
+    Ok(format!("/dev/tap{link_idx}"))
+}
+
+#[cfg(any(feature = "macvtap", feature = "ipvlan"))]
+pub async fn check_stacked_link(
     network: &FirecrackerNetwork,
+    netlink_handle: &rtnetlink::Handle,
+) -> Result<(), FirecrackerNetworkError> {
+    let iface_idx = get_link_index(network.uplink_name()?, netlink_handle).await?;
+    let link_message = netlink_handle
+        .link()
+        .get()
+        .match_name(network.tap_name.clone())
+        .execute()
+        .try_next()
+        .await
+        .map_err(FirecrackerNetworkError::NetlinkOperationError)?;
+
+    match link_message {
+        Some(link_message)
+            if link_message.attributes.iter().any(
+                |attribute| matches!(attribute, rtnetlink::packet_route::link::LinkAttribute::Link(idx) if *idx == iface_idx),
+            ) =>
+        {
+            Ok(())
+        }
+        _ => Err(FirecrackerNetworkError::ObjectNotFound(FirecrackerNetworkObjectType::IpLink)),
+    }
+}
+
+pub fn add_base_chains_if_needed(
+    context: &RuleContext,
     current_ruleset: &Nftables,
     batch: &mut Batch,
 ) -> Result<(), FirecrackerNetworkError> {
     let mut table_exists = false;
     let mut postrouting_chain_exists = false;
+    let mut prerouting_chain_exists = false;
+    let mut mangle_chain_exists = false;
     let mut filter_chain_exists = false;
 
     for object in current_ruleset.objects.iter() {
         match object {
             NfObject::ListObject(object) => match object {
-                NfListObject::Table(table) if table.name == NFT_TABLE && table.family == network.nf_family() => {
+                NfListObject::Table(table)
+                    if table.name == context.nft_config.table_name && table.family == context.nf_family =>
+                {
                     table_exists = true;
                 }
                 NfListObject::Chain(chain) => {
-                    if chain.name == NFT_POSTROUTING_CHAIN && chain.table == NFT_TABLE {
+                    if chain.name == context.nft_config.postrouting_chain_name && chain.table == context.nft_config.table_name {
                         postrouting_chain_exists = true;
-                    } else if chain.name == NFT_FILTER_CHAIN && chain.table == NFT_TABLE {
+                    } else if chain.name == context.nft_config.prerouting_chain_name
+                        && chain.table == context.nft_config.table_name
+                    {
+                        prerouting_chain_exists = true;
+                    } else if chain.name == context.nft_config.mangle_chain_name && chain.table == context.nft_config.table_name {
+                        mangle_chain_exists = true;
+                    } else if chain.name == context.nft_config.filter_chain_name && chain.table == context.nft_config.table_name {
                         filter_chain_exists = true;
                     }
                 }
@@ -56,20 +416,52 @@ pub fn add_base_chains_if_needed(
 
     if !table_exists {
         batch.add(NfListObject::Table(Table {
-            family: network.nf_family(),
-            name: NFT_TABLE.into(),
+            family: context.nf_family,
+            name: context.nft_config.table_name.clone().into(),
             handle: None,
         }));
     }
 
     if !postrouting_chain_exists {
         batch.add(NfListObject::Chain(Chain {
-            family: network.nf_family(),
-            table: NFT_TABLE.into(),
-            name: NFT_POSTROUTING_CHAIN.into(),
+            family: context.nf_family,
+            table: context.nft_config.table_name.clone().into(),
+            name: context.nft_config.postrouting_chain_name.clone().into(),
             _type: Some(NfChainType::NAT),
             hook: Some(NfHook::Postrouting),
-            prio: Some(100),
+            prio: Some(context.nft_config.postrouting_priority),
+            policy: Some(NfChainPolicy::Accept),
+            newname: None,
+            dev: None,
+            handle: None,
+        }));
+    }
+
+    // the prerouting chain is only needed for port forwarding and 1:1 NAT
+    if !prerouting_chain_exists && context.needs_prerouting_chain {
+        batch.add(NfListObject::Chain(Chain {
+            family: context.nf_family,
+            table: context.nft_config.table_name.clone().into(),
+            name: context.nft_config.prerouting_chain_name.clone().into(),
+            _type: Some(NfChainType::NAT),
+            hook: Some(NfHook::Prerouting),
+            prio: Some(context.nft_config.prerouting_priority),
+            policy: Some(NfChainPolicy::Accept),
+            newname: None,
+            dev: None,
+            handle: None,
+        }));
+    }
+
+    // the mangle chain is only needed for marking packets for policy routing
+    if !mangle_chain_exists && context.needs_mangle_chain {
+        batch.add(NfListObject::Chain(Chain {
+            family: context.nf_family,
+            table: context.nft_config.table_name.clone().into(),
+            name: context.nft_config.mangle_chain_name.clone().into(),
+            _type: Some(NfChainType::Filter),
+            hook: Some(NfHook::Prerouting),
+            prio: Some(context.nft_config.mangle_priority),
             policy: Some(NfChainPolicy::Accept),
             newname: None,
             dev: None,
@@ -77,14 +469,15 @@ pub fn add_base_chains_if_needed(
         }));
     }
 
-    if !filter_chain_exists {
+    // the filter chain is only needed where fcnet accepts forwarded packets, which isn't the case inside a netns
+    if !filter_chain_exists && context.needs_filter_chain {
         batch.add(NfListObject::Chain(Chain {
-            family: network.nf_family(),
-            table: NFT_TABLE.into(),
-            name: NFT_FILTER_CHAIN.into(),
+            family: context.nf_family,
+            table: context.nft_config.table_name.clone().into(),
+            name: context.nft_config.filter_chain_name.clone().into(),
             _type: Some(NfChainType::Filter),
             hook: Some(NfHook::Forward),
-            prio: Some(0),
+            prio: Some(context.nft_config.filter_priority),
             policy: Some(NfChainPolicy::Accept),
             handle: None,
             newname: None,
@@ -95,22 +488,31 @@ pub fn add_base_chains_if_needed(
     Ok(())
 }
 
-pub fn check_base_chains(network: &FirecrackerNetwork, current_ruleset: &Nftables) -> Result<(), FirecrackerNetworkError> {
+pub fn check_base_chains(context: &RuleContext, current_ruleset: &Nftables) -> Result<(), FirecrackerNetworkError> {
     let mut table_exists = false;
     let mut postrouting_chain_exists = false;
+    let mut prerouting_chain_exists = false;
+    let mut mangle_chain_exists = false;
     let mut filter_chain_exists = false;
 
     for object in current_ruleset.objects.iter() {
         match object {
             NfObject::ListObject(object) => match object {
-                NfListObject::Table(table) if table.name == NFT_TABLE && table.family == network.nf_family() => {
+                NfListObject::Table(table)
+                    if table.name == context.nft_config.table_name && table.family == context.nf_family =>
+                {
                     table_exists = true;
                 }
-                NfListObject::Chain(chain) if chain.table == NFT_TABLE => {
-                    if chain.name == NFT_POSTROUTING_CHAIN {
-                        postrouting_chain_exists = true;
-                    } else if chain.name == NFT_FILTER_CHAIN {
-                        filter_chain_exists = true;
+                // a chain with a different priority than configured is ordered wrongly relative to other base chains
+                NfListObject::Chain(chain) if chain.table == context.nft_config.table_name => {
+                    if chain.name == context.nft_config.postrouting_chain_name {
+                        postrouting_chain_exists = chain.prio == Some(context.nft_config.postrouting_priority);
+                    } else if chain.name == context.nft_config.prerouting_chain_name {
+                        prerouting_chain_exists = chain.prio == Some(context.nft_config.prerouting_priority);
+                    } else if chain.name == context.nft_config.mangle_chain_name {
+                        mangle_chain_exists = chain.prio == Some(context.nft_config.mangle_priority);
+                    } else if chain.name == context.nft_config.filter_chain_name {
+                        filter_chain_exists = chain.prio == Some(context.nft_config.filter_priority);
                     }
                 }
                 _ => continue,
@@ -129,7 +531,19 @@ pub fn check_base_chains(network: &FirecrackerNetwork, current_ruleset: &Nftable
         ));
     }
 
-    if !filter_chain_exists {
+    if !prerouting_chain_exists && context.needs_prerouting_chain {
+        return Err(FirecrackerNetworkError::ObjectNotFound(
+            FirecrackerNetworkObjectType::NfPreroutingChain,
+        ));
+    }
+
+    if !mangle_chain_exists && context.needs_mangle_chain {
+        return Err(FirecrackerNetworkError::ObjectNotFound(
+            FirecrackerNetworkObjectType::NfMangleChain,
+        ));
+    }
+
+    if !filter_chain_exists && context.needs_filter_chain {
         return Err(FirecrackerNetworkError::ObjectNotFound(
             FirecrackerNetworkObjectType::NfFilterChain,
         ));
@@ -138,6 +552,201 @@ pub fn check_base_chains(network: &FirecrackerNetwork, current_ruleset: &Nftable
     Ok(())
 }
 
+#[inline]
+pub fn port_forward_dnat_expr(
+    iif_name: &str,
+    port_forward: &FirecrackerPortForward,
+    target_addr: IpAddr,
+    nf_family: NfFamily,
+) -> Vec<Statement<'static>> {
+    let mut expr = vec![Statement::Match(Match {
+        left: Expression::Named(NamedExpression::Meta(Meta { key: MetaKey::Iifname })),
+        right: Expression::String(iif_name.to_string().into()),
+        op: Operator::EQ,
+    })];
+
+    if let Some(host_ip) = port_forward.host_ip {
+        expr.push(Statement::Match(Match {
+            left: Expression::Named(NamedExpression::Payload(Payload::PayloadField(PayloadField {
+                protocol: nat_proto_from_addr(host_ip),
+                field: "daddr".into(),
+            }))),
+            right: Expression::String(host_ip.to_string().into()),
+            op: Operator::EQ,
+        }));
+    }
+
+    expr.push(Statement::Match(Match {
+        left: Expression::Named(NamedExpression::Payload(Payload::PayloadField(PayloadField {
+            protocol: transport_proto_from_port_forward(port_forward),
+            field: "dport".into(),
+        }))),
+        right: Expression::Number(port_forward.host_port.into()),
+        op: Operator::EQ,
+    }));
+    expr.push(Statement::DNAT(Some(NAT {
+        addr: Some(Expression::String(target_addr.to_string().into())),
+        family: match nf_family {
+            NfFamily::INet => Some(nat_family_from_addr(target_addr)),
+            _ => None,
+        },
+        port: Some(Expression::Number(port_forward.guest_port.into())),
+        flags: None,
+    })));
+    expr
+}
+
+#[inline]
+pub fn nat_ip_dnat_expr(iif_name: &str, nat_ip: IpAddr, target_addr: IpAddr, nf_family: NfFamily) -> Vec<Statement<'static>> {
+    vec![
+        Statement::Match(Match {
+            left: Expression::Named(NamedExpression::Meta(Meta { key: MetaKey::Iifname })),
+            right: Expression::String(iif_name.to_string().into()),
+            op: Operator::EQ,
+        }),
+        Statement::Match(Match {
+            left: Expression::Named(NamedExpression::Payload(Payload::PayloadField(PayloadField {
+                protocol: nat_proto_from_addr(nat_ip),
+                field: "daddr".into(),
+            }))),
+            right: Expression::String(nat_ip.to_string().into()),
+            op: Operator::EQ,
+        }),
+        Statement::DNAT(Some(NAT {
+            addr: Some(Expression::String(target_addr.to_string().into())),
+            family: match nf_family {
+                NfFamily::INet => Some(nat_family_from_addr(target_addr)),
+                _ => None,
+            },
+            port: None,
+            flags: None,
+        })),
+    ]
+}
+
+#[inline]
+pub fn egress_nat_statement(network: &FirecrackerNetwork) -> Statement<'static> {
+    match network.nat_ip {
+        Some(nat_ip) => Statement::SNAT(Some(NAT {
+            addr: Some(Expression::String(nat_ip.to_string().into())),
+            family: match network.nf_family() {
+                NfFamily::INet => Some(nat_family_from_addr(nat_ip)),
+                _ => None,
+            },
+            port: None,
+            flags: None,
+        })),
+        None => Statement::Masquerade(None),
+    }
+}
+
+#[inline]
+pub fn tap_egress_forward_expr(network: &FirecrackerNetwork) -> Result<Vec<Statement<'static>>, FirecrackerNetworkError> {
+    Ok(vec![
+        Statement::Match(Match {
+            left: Expression::Named(NamedExpression::Meta(Meta { key: MetaKey::Iifname })),
+            right: Expression::String(network.tap_name.clone().into()),
+            op: Operator::EQ,
+        }),
+        Statement::Match(Match {
+            left: Expression::Named(NamedExpression::Meta(Meta { key: MetaKey::Oifname })),
+            right: Expression::String(network.uplink_name()?.into()),
+            op: Operator::EQ,
+        }),
+        Statement::Accept(None),
+    ])
+}
+
+#[inline]
+pub fn tap_ingress_forward_expr(network: &FirecrackerNetwork) -> Result<Vec<Statement<'static>>, FirecrackerNetworkError> {
+    Ok(vec![
+        Statement::Match(Match {
+            left: Expression::Named(NamedExpression::Meta(Meta { key: MetaKey::Iifname })),
+            right: Expression::String(network.uplink_name()?.into()),
+            op: Operator::EQ,
+        }),
+        Statement::Match(Match {
+            left: Expression::Named(NamedExpression::Meta(Meta { key: MetaKey::Oifname })),
+            right: Expression::String(network.tap_name.clone().into()),
+            op: Operator::EQ,
+        }),
+        Statement::Match(Match {
+            left: Expression::Named(NamedExpression::Payload(Payload::PayloadField(PayloadField {
+                protocol: nat_proto_from_addr(network.guest_ip.address()),
+                field: "daddr".into(),
+            }))),
+            right: Expression::String(network.guest_ip.address().to_string().into()),
+            op: Operator::EQ,
+        }),
+        Statement::Accept(None),
+    ])
+}
+
+#[inline]
+pub fn port_forward_accept_expr(
+    iif_name: &str,
+    oif_name: &str,
+    port_forward: &FirecrackerPortForward,
+    target_addr: IpAddr,
+) -> Vec<Statement<'static>> {
+    vec![
+        Statement::Match(Match {
+            left: Expression::Named(NamedExpression::Meta(Meta { key: MetaKey::Iifname })),
+            right: Expression::String(iif_name.to_string().into()),
+            op: Operator::EQ,
+        }),
+        Statement::Match(Match {
+            left: Expression::Named(NamedExpression::Meta(Meta { key: MetaKey::Oifname })),
+            right: Expression::String(oif_name.to_string().into()),
+            op: Operator::EQ,
+        }),
+        Statement::Match(Match {
+            left: Expression::Named(NamedExpression::Payload(Payload::PayloadField(PayloadField {
+                protocol: nat_proto_from_addr(target_addr),
+                field: "daddr".into(),
+            }))),
+            right: Expression::String(target_addr.to_string().into()),
+            op: Operator::EQ,
+        }),
+        Statement::Match(Match {
+            left: Expression::Named(NamedExpression::Payload(Payload::PayloadField(PayloadField {
+                protocol: transport_proto_from_port_forward(port_forward),
+                field: "dport".into(),
+            }))),
+            right: Expression::Number(port_forward.guest_port.into()),
+            op: Operator::EQ,
+        }),
+        Statement::Accept(None),
+    ]
+}
+
+#[inline]
+pub fn matches_interface(statement: &Statement, interface_name: &str) -> bool {
+    match statement {
+        Statement::Match(Match {
+            left:
+                Expression::Named(NamedExpression::Meta(Meta {
+                    key: MetaKey::Iifname | MetaKey::Oifname,
+                })),
+            right: Expression::String(name),
+            op: _,
+        }) => name == interface_name,
+        _ => false,
+    }
+}
+
+#[inline]
+pub fn nf_family_name(family: NfFamily) -> &'static str {
+    match family {
+        NfFamily::IP => "ip",
+        NfFamily::IP6 => "ip6",
+        NfFamily::INet => "inet",
+        NfFamily::ARP => "arp",
+        NfFamily::Bridge => "bridge",
+        NfFamily::NetDev => "netdev",
+    }
+}
+
 #[inline]
 pub fn nat_proto_from_addr(addr: IpAddr) -> Cow<'static, str> {
     match addr {
@@ -146,9 +755,30 @@ pub fn nat_proto_from_addr(addr: IpAddr) -> Cow<'static, str> {
     }
 }
 
+#[inline]
+pub fn transport_proto_from_port_forward(port_forward: &FirecrackerPortForward) -> Cow<'static, str> {
+    match port_forward.protocol {
+        FirecrackerPortProtocol::Tcp => "tcp".into(),
+        FirecrackerPortProtocol::Udp => "udp".into(),
+    }
+}
+
+#[inline]
+pub fn nat_family_from_addr(addr: IpAddr) -> NATFamily {
+    match addr {
+        IpAddr::V4(_) => NATFamily::IP,
+        IpAddr::V6(_) => NATFamily::IP6,
+    }
+}
+
 pub trait FirecrackerNetworkExt {
     fn nf_family(&self) -> NfFamily;
     fn nft_program(&self) -> Option<&str>;
+    fn needs_prerouting_chain(&self) -> bool;
+    fn host_iface_name(&self) -> Result<&str, FirecrackerNetworkError>;
+    fn uplink_name(&self) -> Result<String, FirecrackerNetworkError>;
+    fn forward_iface_name(&self) -> Option<&str>;
+    fn rule_context(&self) -> RuleContext<'_>;
 }
 
 impl FirecrackerNetworkExt for FirecrackerNetwork {
@@ -163,6 +793,56 @@ impl FirecrackerNetworkExt for FirecrackerNetwork {
 
     #[inline]
     fn nft_program(&self) -> Option<&str> {
-        self.nft_path.as_ref().map(|p| p.as_str())
+        self.nft_path.as_deref()
+    }
+
+    #[inline]
+    fn needs_prerouting_chain(&self) -> bool {
+        self.nat_ip.is_some() || !self.port_forwards.is_empty()
+    }
+
+    #[inline]
+    fn host_iface_name(&self) -> Result<&str, FirecrackerNetworkError> {
+        self.iface_name.as_deref().ok_or(FirecrackerNetworkError::UnresolvedIfaceName)
+    }
+
+    #[inline]
+    fn uplink_name(&self) -> Result<String, FirecrackerNetworkError> {
+        let host_iface_name = self.host_iface_name()?;
+
+        Ok(match self.vlan_id {
+            Some(vlan_id) => format!("{host_iface_name}.{vlan_id}"),
+            None => host_iface_name.to_string(),
+        })
+    }
+
+    #[inline]
+    fn forward_iface_name(&self) -> Option<&str> {
+        // macvtap and ipvlan traffic isn't forwarded by the host at all
+        match self.network_type {
+            #[cfg(feature = "namespaced")]
+            FirecrackerNetworkType::Namespaced { ref veth1_name, .. } => Some(veth1_name),
+            #[cfg(feature = "bridge")]
+            FirecrackerNetworkType::Bridge { ref bridge_name } => Some(bridge_name),
+            #[cfg(feature = "macvtap")]
+            FirecrackerNetworkType::Macvtap { .. } => None,
+            #[cfg(feature = "ipvlan")]
+            FirecrackerNetworkType::Ipvlan => None,
+            #[allow(unreachable_patterns)]
+            _ => Some(&self.tap_name),
+        }
+    }
+
+    #[inline]
+    fn rule_context(&self) -> RuleContext<'_> {
+        RuleContext {
+            nf_family: self.nf_family(),
+            nft_path: self.nft_program(),
+            nft_config: &self.nft_config,
+            rule_backend: &self.rule_backend,
+            needs_prerouting_chain: self.needs_prerouting_chain(),
+            needs_mangle_chain: self.policy_route.is_some(),
+            needs_filter_chain: true,
+        }
     }
 }
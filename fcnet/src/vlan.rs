@@ -0,0 +1,354 @@
+use fcnet_types::FirecrackerNetwork;
+#[cfg(any(feature = "bridge", feature = "macvtap", feature = "ipvlan"))]
+use fcnet_types::FirecrackerNetworkType;
+use futures_util::TryStreamExt;
+use rtnetlink::{
+    packet_route::link::{InfoData, InfoVlan, LinkAttribute, LinkInfo, LinkMessage},
+    LinkVlan,
+};
+
+use crate::{
+    backend::Backend,
+    rules,
+    util::{check_link_address, find_link_index, get_link_index, FirecrackerNetworkExt},
+    FirecrackerNetworkError, FirecrackerNetworkObjectType,
+};
+
+/// Validate that the guest's egress traffic can actually leave via the VLAN interface. Macvtap and ipvlan devices are
+/// stacked on it directly, while the traffic forwarded by the host for other network types is only routed into it via
+/// the policy route, and needs the VLAN IP as the source of masquerading and to reach the policy route's gateway.
+/// The rules of bridged networks don't match the host interface at all, so they can't be restricted to a VLAN.
+pub fn validate(network: &FirecrackerNetwork) -> Result<(), FirecrackerNetworkError> {
+    if network.vlan_id.is_none() {
+        return Ok(());
+    }
+
+    match network.network_type {
+        #[cfg(feature = "bridge")]
+        FirecrackerNetworkType::Bridge { .. } => {
+            return Err(FirecrackerNetworkError::InvalidVlan("bridged networks don't support VLANs"));
+        }
+        #[cfg(feature = "macvtap")]
+        FirecrackerNetworkType::Macvtap { .. } => return Ok(()),
+        #[cfg(feature = "ipvlan")]
+        FirecrackerNetworkType::Ipvlan => return Ok(()),
+        #[allow(unreachable_patterns)]
+        _ => {}
+    }
+
+    let Some(vlan_ip) = network.vlan_ip else {
+        return Err(FirecrackerNetworkError::InvalidVlan(
+            "a VLAN IP is needed for the guest's traffic to leave via the VLAN interface",
+        ));
+    };
+
+    let Some(policy_route) = network.policy_route else {
+        return Err(FirecrackerNetworkError::InvalidVlan(
+            "a policy route is needed to route the guest's traffic into the VLAN interface",
+        ));
+    };
+
+    match policy_route.gateway {
+        Some(gateway) if !vlan_ip.network().contains(&gateway) => Err(FirecrackerNetworkError::InvalidVlan(
+            "the policy route's gateway isn't in the network of the VLAN IP",
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Add the VLAN interface if it's missing, with the VLAN IP if it's set, or otherwise check that the existing link
+/// is a VLAN interface with the network's VLAN ID on the host interface that carries the VLAN IP. Returns whether the
+/// VLAN interface was created, so that it can be deleted again if adding the rest of the network fails.
+pub async fn add_if_needed(
+    network: &FirecrackerNetwork,
+    netlink_handle: &rtnetlink::Handle,
+) -> Result<bool, FirecrackerNetworkError> {
+    let Some(vlan_id) = network.vlan_id else {
+        return Ok(false);
+    };
+
+    let iface_idx = get_link_index(network.host_iface_name()?.to_string(), netlink_handle).await?;
+
+    if let Some(link_message) = get_vlan_link(network, netlink_handle).await? {
+        if !is_vlan_of(&link_message, iface_idx, vlan_id) {
+            return Err(FirecrackerNetworkError::InvalidVlan(
+                "the existing link named like the VLAN interface isn't a VLAN with this ID on the host interface",
+            ));
+        }
+
+        return match network.vlan_ip {
+            Some(ref vlan_ip) => check_link_address(link_message.header.index, vlan_ip, netlink_handle)
+                .await
+                .map(|_| false),
+            None => Ok(false),
+        };
+    }
+
+    netlink_handle
+        .link()
+        .add(LinkVlan::new(&network.uplink_name()?, iface_idx, vlan_id).up().build())
+        .execute()
+        .await
+        .map_err(FirecrackerNetworkError::NetlinkOperationError)?;
+
+    if let Some(vlan_ip) = network.vlan_ip {
+        let vlan_idx = get_link_index(network.uplink_name()?, netlink_handle).await?;
+        netlink_handle
+            .address()
+            .add(vlan_idx, vlan_ip.address(), vlan_ip.network_length())
+            .execute()
+            .await
+            .map_err(FirecrackerNetworkError::NetlinkOperationError)?;
+    }
+
+    Ok(true)
+}
+
+pub async fn check_if_needed(
+    network: &FirecrackerNetwork,
+    netlink_handle: &rtnetlink::Handle,
+) -> Result<(), FirecrackerNetworkError> {
+    let Some(vlan_id) = network.vlan_id else {
+        return Ok(());
+    };
+
+    let iface_idx = get_link_index(network.host_iface_name()?.to_string(), netlink_handle).await?;
+    let link_message = get_vlan_link(network, netlink_handle)
+        .await?
+        .filter(|link_message| is_vlan_of(link_message, iface_idx, vlan_id))
+        .ok_or(FirecrackerNetworkError::ObjectNotFound(FirecrackerNetworkObjectType::IpLink))?;
+
+    match network.vlan_ip {
+        Some(ref vlan_ip) => check_link_address(link_message.header.index, vlan_ip, netlink_handle).await,
+        None => Ok(()),
+    }
+}
+
+pub async fn delete_if_unused<B: Backend>(
+    network: &FirecrackerNetwork,
+    netlink_handle: &rtnetlink::Handle,
+) -> Result<(), FirecrackerNetworkError> {
+    if network.vlan_id.is_none() {
+        return Ok(());
+    }
+
+    let uplink_name = network.uplink_name()?;
+    let vlan_idx = get_link_index(uplink_name.clone(), netlink_handle).await?;
+
+    // the VLAN interface is reference-counted by what's left of the other networks using it once this network's
+    // objects are gone: links stacked on top of it and rules of fcnet's table or iptables chains matching it as an
+    // input or output interface
+    let mut link_message_stream = netlink_handle.link().get().execute();
+
+    while let Some(link_message) = link_message_stream
+        .try_next()
+        .await
+        .map_err(FirecrackerNetworkError::NetlinkOperationError)?
+    {
+        if link_message
+            .attributes
+            .iter()
+            .any(|attribute| matches!(attribute, LinkAttribute::Link(idx) if *idx == vlan_idx))
+        {
+            return Ok(());
+        }
+    }
+
+    if rules::any_rule_matches_interface::<B>(&network.rule_context(), &uplink_name).await? {
+        return Ok(());
+    }
+
+    delete(vlan_idx, netlink_handle).await
+}
+
+/// Delete the VLAN interface created by [add_if_needed] after adding the rest of the network failed, along with the
+/// links that were already stacked on top of it.
+pub async fn delete_created(
+    network: &FirecrackerNetwork,
+    netlink_handle: &rtnetlink::Handle,
+) -> Result<(), FirecrackerNetworkError> {
+    let vlan_idx = get_link_index(network.uplink_name()?, netlink_handle).await?;
+    delete(vlan_idx, netlink_handle).await
+}
+
+async fn delete(vlan_idx: u32, netlink_handle: &rtnetlink::Handle) -> Result<(), FirecrackerNetworkError> {
+    netlink_handle
+        .link()
+        .del(vlan_idx)
+        .execute()
+        .await
+        .map_err(FirecrackerNetworkError::NetlinkOperationError)
+}
+
+async fn get_vlan_link(
+    network: &FirecrackerNetwork,
+    netlink_handle: &rtnetlink::Handle,
+) -> Result<Option<LinkMessage>, FirecrackerNetworkError> {
+    match find_link_index(&network.uplink_name()?, netlink_handle).await? {
+        Some(vlan_idx) => netlink_handle
+            .link()
+            .get()
+            .match_index(vlan_idx)
+            .execute()
+            .try_next()
+            .await
+            .map_err(FirecrackerNetworkError::NetlinkOperationError),
+        None => Ok(None),
+    }
+}
+
+/// Whether the link is a VLAN interface with the given VLAN ID on top of the given host interface.
+fn is_vlan_of(link_message: &LinkMessage, iface_idx: u32, vlan_id: u16) -> bool {
+    let mut on_iface = false;
+    let mut has_vlan_id = false;
+
+    for attribute in &link_message.attributes {
+        match attribute {
+            LinkAttribute::Link(idx) => on_iface = *idx == iface_idx,
+            LinkAttribute::LinkInfo(link_infos) => {
+                has_vlan_id = link_infos.iter().any(|link_info| {
+                    matches!(link_info, LinkInfo::Data(InfoData::Vlan(info_vlans)) if info_vlans.contains(&InfoVlan::Id(vlan_id)))
+                })
+            }
+            _ => continue,
+        }
+    }
+
+    on_iface && has_vlan_id
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use fcnet_types::{
+        FirecrackerIpStack, FirecrackerNetwork, FirecrackerNetworkType, FirecrackerPolicyRoute, FirecrackerRuleBackend,
+    };
+    use rtnetlink::packet_route::{
+        link::{InfoData, InfoVlan, LinkAttribute, LinkInfo, LinkMessage},
+        route::{RouteAddress, RouteAttribute},
+    };
+
+    use super::{is_vlan_of, validate};
+    use crate::{policy, simple::network_rules, util::matches_interface, FirecrackerNetworkError, FirecrackerNetworkObjectType};
+
+    fn network() -> FirecrackerNetwork {
+        FirecrackerNetwork {
+            nft_path: None,
+            nft_config: Default::default(),
+            rule_backend: FirecrackerRuleBackend::Nftables,
+            ip_stack: FirecrackerIpStack::V4,
+            iface_name: Some("eth0".to_string()),
+            tap_name: "tap0".to_string(),
+            tap_ip: "172.16.0.1/24".parse().unwrap(),
+            guest_ip: "172.16.0.2/24".parse().unwrap(),
+            guest_mac: None,
+            network_type: FirecrackerNetworkType::Simple,
+            nat_ip: None,
+            port_forwards: Vec::new(),
+            vlan_id: Some(100),
+            vlan_ip: Some("192.168.100.2/24".parse().unwrap()),
+            policy_route: Some(FirecrackerPolicyRoute {
+                fwmark: 100,
+                table_id: 1000,
+                gateway: Some("192.168.100.1".parse().unwrap()),
+            }),
+            flowtable: false,
+        }
+    }
+
+    fn matches_vlan_interface(network: &FirecrackerNetwork, object_type: FirecrackerNetworkObjectType) -> bool {
+        network_rules(network)
+            .unwrap()
+            .iter()
+            .filter(|rule| rule.object_type == object_type)
+            .all(|rule| rule.expr.iter().any(|statement| matches_interface(statement, "eth0.100")))
+    }
+
+    #[test]
+    fn egress_is_masqueraded_and_routed_via_the_vlan_interface() {
+        let network = network();
+        let policy_route = network.policy_route.unwrap();
+        validate(&network).unwrap();
+
+        // the guest's traffic is masqueraded and forwarded when leaving via the VLAN interface
+        assert!(matches_vlan_interface(
+            &network,
+            FirecrackerNetworkObjectType::NfMasqueradeRule
+        ));
+        assert!(matches_vlan_interface(
+            &network,
+            FirecrackerNetworkObjectType::NfEgressForwardRule
+        ));
+
+        // which is where the policy route's table sends it, to a gateway on the VLAN
+        let vlan_idx = 7;
+        let route_message = policy::default_route_message(&network, &policy_route, vlan_idx).unwrap();
+        assert_eq!(route_message.header.destination_prefix_length, 0);
+        assert!(route_message.attributes.contains(&RouteAttribute::Oif(vlan_idx)));
+        assert!(route_message.attributes.contains(&RouteAttribute::Table(1000)));
+        assert!(route_message
+            .attributes
+            .contains(&RouteAttribute::Gateway(RouteAddress::Inet(Ipv4Addr::new(192, 168, 100, 1)))));
+    }
+
+    #[test]
+    fn vlan_without_ip_or_policy_route_is_rejected() {
+        let without_ip = FirecrackerNetwork {
+            vlan_ip: None,
+            ..network()
+        };
+        assert!(matches!(validate(&without_ip), Err(FirecrackerNetworkError::InvalidVlan(_))));
+
+        let without_policy_route = FirecrackerNetwork {
+            policy_route: None,
+            ..network()
+        };
+        assert!(matches!(
+            validate(&without_policy_route),
+            Err(FirecrackerNetworkError::InvalidVlan(_))
+        ));
+    }
+
+    #[test]
+    fn gateway_outside_the_vlan_is_rejected() {
+        let mut network = network();
+        network.policy_route.as_mut().unwrap().gateway = Some("10.0.0.1".parse().unwrap());
+        assert!(matches!(validate(&network), Err(FirecrackerNetworkError::InvalidVlan(_))));
+    }
+
+    #[test]
+    fn stacked_networks_need_no_vlan_ip() {
+        let network = FirecrackerNetwork {
+            network_type: FirecrackerNetworkType::Ipvlan,
+            vlan_ip: None,
+            policy_route: None,
+            ..network()
+        };
+        assert!(validate(&network).is_ok());
+
+        let network = FirecrackerNetwork {
+            network_type: FirecrackerNetworkType::Bridge {
+                bridge_name: "br0".to_string(),
+            },
+            ..network
+        };
+        assert!(matches!(validate(&network), Err(FirecrackerNetworkError::InvalidVlan(_))));
+    }
+
+    #[test]
+    fn existing_link_must_be_vlan_with_id_on_host_interface() {
+        let mut link_message = LinkMessage::default();
+        link_message.attributes.push(LinkAttribute::Link(2));
+        link_message
+            .attributes
+            .push(LinkAttribute::LinkInfo(vec![LinkInfo::Data(InfoData::Vlan(vec![
+                InfoVlan::Id(100),
+            ]))]));
+
+        assert!(is_vlan_of(&link_message, 2, 100));
+        assert!(!is_vlan_of(&link_message, 2, 200));
+        assert!(!is_vlan_of(&link_message, 3, 100));
+        assert!(!is_vlan_of(&LinkMessage::default(), 2, 100));
+    }
+}
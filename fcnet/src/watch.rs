@@ -0,0 +1,366 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+};
+
+use fcnet_types::FirecrackerNetwork;
+#[cfg(any(
+    feature = "namespaced",
+    feature = "routed",
+    feature = "bridge",
+    feature = "macvtap",
+    feature = "ipvlan"
+))]
+use fcnet_types::FirecrackerNetworkType;
+use futures_util::{Stream, StreamExt};
+use rtnetlink::{
+    constants::{RTMGRP_IPV4_IFADDR, RTMGRP_IPV4_ROUTE, RTMGRP_IPV6_IFADDR, RTMGRP_IPV6_ROUTE, RTMGRP_LINK},
+    packet_core::NetlinkPayload,
+    packet_route::{
+        address::{AddressAttribute, AddressMessage},
+        link::{LinkAttribute, LinkMessage},
+        route::{RouteAddress, RouteAttribute, RouteMessage},
+        RouteNetlinkMessage,
+    },
+    sys::{AsyncSocket, SocketAddr},
+};
+
+use crate::{
+    backend::Backend,
+    util::{get_link_index, FirecrackerNetworkExt},
+    FirecrackerNetworkError, FirecrackerNetworkObjectType,
+};
+
+/// A notification emitted by [watch] when an object belonging to a watched [FirecrackerNetwork] was deleted
+/// outside of fcnet, so that the network no longer passes a check.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FirecrackerNetworkDrift {
+    /// The watched network that drifted.
+    pub network: FirecrackerNetwork,
+    /// The type of the deleted object.
+    pub object_type: FirecrackerNetworkObjectType,
+    /// The name of the deleted link, or the deleted address or route destination.
+    pub object: String,
+}
+
+/// Watch for drift of [FirecrackerNetwork]s via the given [Backend], by subscribing to the rtnetlink link, address
+/// and route multicast groups of the default netns and mapping deletions to the networks returned by the given
+/// closure at the time of each event. A netns disappearing is reported via the deletion of its veth pair, while
+/// objects residing inside a netns aren't observed. The returned [Stream] ends if the netlink connection closes.
+pub fn watch<B: Backend>(
+    networks: impl FnMut() -> Vec<FirecrackerNetwork>,
+) -> Result<impl Stream<Item = FirecrackerNetworkDrift>, FirecrackerNetworkError> {
+    let (mut connection, netlink_handle, messages) =
+        rtnetlink::new_connection_with_socket::<B::NetlinkSocket>().map_err(FirecrackerNetworkError::IoError)?;
+    connection
+        .socket_mut()
+        .socket_mut()
+        .bind(&SocketAddr::new(
+            0,
+            RTMGRP_LINK | RTMGRP_IPV4_IFADDR | RTMGRP_IPV6_IFADDR | RTMGRP_IPV4_ROUTE | RTMGRP_IPV6_ROUTE,
+        ))
+        .map_err(FirecrackerNetworkError::IoError)?;
+    B::spawn_connection(connection);
+
+    Ok(futures_util::stream::unfold(
+        (messages, VecDeque::new(), networks, netlink_handle),
+        |(mut messages, mut pending_drifts, mut networks, netlink_handle)| async move {
+            loop {
+                if let Some(drift) = pending_drifts.pop_front() {
+                    return Some((drift, (messages, pending_drifts, networks, netlink_handle)));
+                }
+
+                let (message, _) = messages.next().await?;
+                if let NetlinkPayload::InnerMessage(message) = message.payload {
+                    let networks = networks();
+                    let link_indices = match message {
+                        RouteNetlinkMessage::DelAddress(_) => get_address_link_indices(&networks, &netlink_handle).await,
+                        _ => HashMap::new(),
+                    };
+
+                    pending_drifts.extend(get_drifts(networks, &message, &link_indices));
+                }
+            }
+        },
+    ))
+}
+
+/// The indices of the links carrying the addresses of the given networks, which deleted addresses are matched against.
+/// Links that no longer exist are left out, since their deletion is reported as the drift of the link instead.
+async fn get_address_link_indices(networks: &[FirecrackerNetwork], netlink_handle: &rtnetlink::Handle) -> HashMap<String, u32> {
+    let mut link_indices = HashMap::new();
+
+    for network in networks {
+        let Some((link_name, _)) = expected_address(network) else {
+            continue;
+        };
+
+        if link_indices.contains_key(link_name) {
+            continue;
+        }
+
+        if let Ok(link_index) = get_link_index(link_name.to_string(), netlink_handle).await {
+            link_indices.insert(link_name.to_string(), link_index);
+        }
+    }
+
+    link_indices
+}
+
+fn get_drifts<'a>(
+    networks: Vec<FirecrackerNetwork>,
+    message: &'a RouteNetlinkMessage,
+    link_indices: &'a HashMap<String, u32>,
+) -> impl Iterator<Item = FirecrackerNetworkDrift> + 'a {
+    networks.into_iter().filter_map(move |network| {
+        let (object_type, object) = match message {
+            RouteNetlinkMessage::DelLink(link_message) => link_drift(&network, link_message)?,
+            RouteNetlinkMessage::DelAddress(address_message) => address_drift(&network, address_message, link_indices)?,
+            RouteNetlinkMessage::DelRoute(route_message) => route_drift(&network, route_message)?,
+            _ => return None,
+        };
+
+        Some(FirecrackerNetworkDrift {
+            network,
+            object_type,
+            object,
+        })
+    })
+}
+
+fn link_drift(network: &FirecrackerNetwork, link_message: &LinkMessage) -> Option<(FirecrackerNetworkObjectType, String)> {
+    let name = link_message.attributes.iter().find_map(|attribute| match attribute {
+        LinkAttribute::IfName(name) => Some(name),
+        _ => None,
+    })?;
+
+    let mut link_names = vec![network.tap_name.as_str()];
+
+    match network.network_type {
+        #[cfg(feature = "namespaced")]
+        FirecrackerNetworkType::Namespaced { ref veth1_name, .. } => link_names = vec![veth1_name.as_str()],
+        #[cfg(feature = "bridge")]
+        FirecrackerNetworkType::Bridge { ref bridge_name } => link_names.push(bridge_name.as_str()),
+        #[allow(unreachable_patterns)]
+        _ => {}
+    }
+
+    let uplink_name = network.uplink_name().ok();
+    link_names.extend(network.iface_name.as_deref());
+    link_names.extend(uplink_name.as_deref());
+
+    match link_names.contains(&name.as_str()) {
+        true => Some((FirecrackerNetworkObjectType::IpLink, name.clone())),
+        false => None,
+    }
+}
+
+fn address_drift(
+    network: &FirecrackerNetwork,
+    address_message: &AddressMessage,
+    link_indices: &HashMap<String, u32>,
+) -> Option<(FirecrackerNetworkObjectType, String)> {
+    let address = address_message.attributes.iter().find_map(|attribute| match attribute {
+        AddressAttribute::Address(address) => Some(*address),
+        _ => None,
+    })?;
+
+    // IPv6 addresses carry no label, so the address is attributed to its link by the index in the header instead
+    let (link_name, expected_address) = expected_address(network)?;
+    let link_index = *link_indices.get(link_name)?;
+
+    match address_message.header.index == link_index && address == expected_address {
+        true => Some((FirecrackerNetworkObjectType::IpAddress, address.to_string())),
+        false => None,
+    }
+}
+
+/// The name of the link carrying the address that fcnet adds for a network, along with that address.
+fn expected_address(network: &FirecrackerNetwork) -> Option<(&str, IpAddr)> {
+    match network.network_type {
+        #[cfg(feature = "namespaced")]
+        FirecrackerNetworkType::Namespaced {
+            ref veth1_name,
+            ref veth1_ip,
+            ..
+        } => Some((veth1_name, veth1_ip.address())),
+        #[cfg(feature = "bridge")]
+        FirecrackerNetworkType::Bridge { ref bridge_name } => Some((bridge_name, network.tap_ip.address())),
+        #[cfg(feature = "macvtap")]
+        FirecrackerNetworkType::Macvtap { .. } => None,
+        #[cfg(feature = "ipvlan")]
+        FirecrackerNetworkType::Ipvlan => Some((&network.tap_name, network.guest_ip.address())),
+        #[allow(unreachable_patterns)]
+        _ => Some((&network.tap_name, network.tap_ip.address())),
+    }
+}
+
+fn route_drift(network: &FirecrackerNetwork, route_message: &RouteMessage) -> Option<(FirecrackerNetworkObjectType, String)> {
+    let mut destination = None;
+    let mut table_id = route_message.header.table as u32;
+
+    for attribute in &route_message.attributes {
+        match attribute {
+            RouteAttribute::Destination(RouteAddress::Inet(i)) => destination = Some(IpAddr::V4(*i)),
+            RouteAttribute::Destination(RouteAddress::Inet6(i)) => destination = Some(IpAddr::V6(*i)),
+            RouteAttribute::Table(table) => table_id = *table,
+            _ => continue,
+        }
+    }
+
+    // the default route of a policy routing table is the only route without a destination that fcnet adds
+    if let (Some(policy_route), None) = (network.policy_route, destination) {
+        return match policy_route.table_id == table_id {
+            true => Some((FirecrackerNetworkObjectType::IpRoute, "default".to_string())),
+            false => None,
+        };
+    }
+
+    let destination = destination?;
+    let expected_destination = match network.network_type {
+        #[cfg(feature = "routed")]
+        FirecrackerNetworkType::Routed { .. } => Some(network.guest_ip.address()),
+        #[cfg(feature = "namespaced")]
+        FirecrackerNetworkType::Namespaced { forwarded_guest_ip, .. } => forwarded_guest_ip,
+        #[allow(unreachable_patterns)]
+        _ => None,
+    };
+
+    match expected_destination == Some(destination) {
+        true => Some((FirecrackerNetworkObjectType::IpRoute, destination.to_string())),
+        false => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, net::IpAddr};
+
+    use fcnet_types::{
+        FirecrackerIpStack, FirecrackerNetwork, FirecrackerNetworkType, FirecrackerPolicyRoute, FirecrackerRuleBackend,
+    };
+    use rtnetlink::packet_route::{
+        address::{AddressAttribute, AddressMessage},
+        link::{LinkAttribute, LinkMessage},
+        route::{RouteAttribute, RouteMessage},
+        RouteNetlinkMessage,
+    };
+
+    use super::get_drifts;
+    use crate::FirecrackerNetworkObjectType;
+
+    fn network(tap_name: &str, tap_ip: &str, guest_ip: &str) -> FirecrackerNetwork {
+        FirecrackerNetwork {
+            nft_path: None,
+            nft_config: Default::default(),
+            rule_backend: FirecrackerRuleBackend::Nftables,
+            ip_stack: FirecrackerIpStack::V4,
+            iface_name: Some("eth0".to_string()),
+            tap_name: tap_name.to_string(),
+            tap_ip: tap_ip.parse().unwrap(),
+            guest_ip: guest_ip.parse().unwrap(),
+            guest_mac: None,
+            network_type: FirecrackerNetworkType::Simple,
+            nat_ip: None,
+            port_forwards: Vec::new(),
+            vlan_id: None,
+            vlan_ip: None,
+            policy_route: None,
+            flowtable: false,
+        }
+    }
+
+    fn del_address(link_index: u32, address: &str) -> RouteNetlinkMessage {
+        let mut address_message = AddressMessage::default();
+        address_message.header.index = link_index;
+        address_message
+            .attributes
+            .push(AddressAttribute::Address(address.parse::<IpAddr>().unwrap()));
+        RouteNetlinkMessage::DelAddress(address_message)
+    }
+
+    fn drifts(
+        networks: Vec<FirecrackerNetwork>,
+        message: &RouteNetlinkMessage,
+        link_indices: &HashMap<String, u32>,
+    ) -> Vec<(String, FirecrackerNetworkObjectType, String)> {
+        get_drifts(networks, message, link_indices)
+            .map(|drift| (drift.network.tap_name, drift.object_type, drift.object))
+            .collect()
+    }
+
+    #[test]
+    fn deleted_tap_link_drifts() {
+        let mut link_message = LinkMessage::default();
+        link_message.attributes.push(LinkAttribute::IfName("tap1".to_string()));
+        let networks = vec![
+            network("tap0", "172.16.0.1/24", "172.16.0.2/24"),
+            network("tap1", "172.16.1.1/24", "172.16.1.2/24"),
+        ];
+
+        assert_eq!(
+            drifts(networks, &RouteNetlinkMessage::DelLink(link_message), &HashMap::new()),
+            [("tap1".to_string(), FirecrackerNetworkObjectType::IpLink, "tap1".to_string())]
+        );
+    }
+
+    #[test]
+    fn deleted_ipv6_address_drifts_by_link_index() {
+        let networks = vec![
+            network("tap0", "fd00::1/64", "fd00::2/64"),
+            network("tap1", "fd00::1/64", "fd00::2/64"),
+        ];
+        let link_indices = HashMap::from([("tap0".to_string(), 5), ("tap1".to_string(), 6)]);
+
+        assert_eq!(
+            drifts(networks, &del_address(6, "fd00::1"), &link_indices),
+            [(
+                "tap1".to_string(),
+                FirecrackerNetworkObjectType::IpAddress,
+                "fd00::1".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn address_deleted_from_another_link_doesnt_drift() {
+        let networks = vec![network("tap0", "172.16.0.1/24", "172.16.0.2/24")];
+        let link_indices = HashMap::from([("tap0".to_string(), 5)]);
+
+        assert!(drifts(networks.clone(), &del_address(7, "172.16.0.1"), &link_indices).is_empty());
+        assert!(drifts(networks, &del_address(5, "172.16.0.3"), &link_indices).is_empty());
+    }
+
+    #[test]
+    fn deleted_policy_default_route_drifts() {
+        let mut network = network("tap0", "172.16.0.1/24", "172.16.0.2/24");
+        network.policy_route = Some(FirecrackerPolicyRoute {
+            fwmark: 100,
+            table_id: 1000,
+            gateway: None,
+        });
+        let mut route_message = RouteMessage::default();
+        route_message.attributes.push(RouteAttribute::Table(1000));
+        let mut other_route_message = RouteMessage::default();
+        other_route_message.attributes.push(RouteAttribute::Table(1001));
+
+        assert_eq!(
+            drifts(
+                vec![network.clone()],
+                &RouteNetlinkMessage::DelRoute(route_message),
+                &HashMap::new()
+            ),
+            [(
+                "tap0".to_string(),
+                FirecrackerNetworkObjectType::IpRoute,
+                "default".to_string()
+            )]
+        );
+        assert!(drifts(
+            vec![network],
+            &RouteNetlinkMessage::DelRoute(other_route_message),
+            &HashMap::new()
+        )
+        .is_empty());
+    }
+}
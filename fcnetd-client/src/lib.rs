@@ -6,7 +6,7 @@ use std::path::Path;
 #[cfg(feature = "deadpool")]
 use std::path::PathBuf;
 
-use fcnet_types::{FirecrackerNetwork, FirecrackerNetworkOperation};
+use fcnet_types::{FirecrackerNetwork, FirecrackerNetworkHandle, FirecrackerNetworkOperation};
 use serde::Serialize;
 use socket::Socket;
 
@@ -19,6 +19,8 @@ pub enum FcnetdError {
     RequestWriteError(std::io::Error),
     RequestSerializeError(serde_json::Error),
     ResponseReadError(std::io::Error),
+    ResponseParseError(serde_json::Error),
+    HandleUnavailable(String),
     ConnectionClosed,
     OperationFailed(String),
 }
@@ -31,6 +33,13 @@ impl std::fmt::Display for FcnetdError {
             FcnetdError::RequestWriteError(err) => write!(f, "Writing the request to the socket failed: {err}"),
             FcnetdError::RequestSerializeError(err) => write!(f, "Serializing the request to JSON failed: {err}"),
             FcnetdError::ResponseReadError(err) => write!(f, "Reading the response from the connection failed: {err}"),
+            FcnetdError::ResponseParseError(err) => write!(f, "Parsing the handle in the response failed: {err}"),
+            FcnetdError::HandleUnavailable(detail) => {
+                write!(
+                    f,
+                    "The network was added, but the daemon couldn't provide its handle: {detail}"
+                )
+            }
             FcnetdError::ConnectionClosed => write!(f, "The connection was closed before a response could be received"),
             FcnetdError::OperationFailed(detail) => {
                 write!(f, "The daemon returned a failure of the requested operation: {detail}")
@@ -46,6 +55,10 @@ pub struct FcnetdConnection<S: Socket>(S);
 struct Request<'net> {
     operation: FirecrackerNetworkOperation,
     network: &'net FirecrackerNetwork,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    handle: Option<&'net FirecrackerNetworkHandle>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    with_handle: bool,
 }
 
 #[derive(Debug)]
@@ -114,23 +127,63 @@ impl<S: Socket> FcnetdConnection<S> {
     }
 
     pub async fn run(&mut self, network: &FirecrackerNetwork, operation: FirecrackerNetworkOperation) -> Result<(), FcnetdError> {
-        let request = Request { operation, network };
+        self.request(network, operation, None, false).await
+    }
+
+    pub async fn run_with_handle(
+        &mut self,
+        network: &FirecrackerNetwork,
+        operation: FirecrackerNetworkOperation,
+        handle: &FirecrackerNetworkHandle,
+    ) -> Result<(), FcnetdError> {
+        self.request(network, operation, Some(handle), false).await
+    }
+
+    /// Add the network, returning the handle of the created objects. If the daemon adds the network, but can't provide
+    /// its handle, a [FcnetdError::HandleUnavailable] is returned while the network stays added.
+    pub async fn add(&mut self, network: &FirecrackerNetwork) -> Result<FirecrackerNetworkHandle, FcnetdError> {
+        self.request(network, FirecrackerNetworkOperation::Add, None, true).await?;
+
+        // the handle follows the OK on a separate line, which is anything but JSON if it's unavailable
+        let handle_line = self.read_response().await?;
+        match handle_line.starts_with('{') {
+            true => serde_json::from_str(&handle_line).map_err(FcnetdError::ResponseParseError),
+            false => Err(FcnetdError::HandleUnavailable(handle_line)),
+        }
+    }
+
+    async fn request(
+        &mut self,
+        network: &FirecrackerNetwork,
+        operation: FirecrackerNetworkOperation,
+        handle: Option<&FirecrackerNetworkHandle>,
+        with_handle: bool,
+    ) -> Result<(), FcnetdError> {
+        let request = Request {
+            operation,
+            network,
+            handle,
+            with_handle,
+        };
         let request_json = serde_json::to_string(&request).map_err(FcnetdError::RequestSerializeError)?;
         self.0
             .write_line(request_json)
             .await
             .map_err(FcnetdError::RequestWriteError)?;
 
-        let response = match self.0.read_line().await {
-            Ok(Some(response)) => response,
-            Ok(None) => return Err(FcnetdError::ConnectionClosed),
-            Err(err) => return Err(FcnetdError::ResponseReadError(err)),
-        };
-
+        let response = self.read_response().await?;
         if response != OK_RESPONSE {
             return Err(FcnetdError::OperationFailed(response));
         }
 
         Ok(())
     }
+
+    async fn read_response(&mut self) -> Result<String, FcnetdError> {
+        match self.0.read_line().await {
+            Ok(Some(response)) => Ok(response),
+            Ok(None) => Err(FcnetdError::ConnectionClosed),
+            Err(err) => Err(FcnetdError::ResponseReadError(err)),
+        }
+    }
 }
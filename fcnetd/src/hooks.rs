@@ -0,0 +1,105 @@
+use std::process::Stdio;
+
+use fcnet::{FirecrackerNetworkError, FirecrackerNetworkHooks};
+use fcnet_types::{FirecrackerNetwork, FirecrackerNetworkOperation};
+use tokio::{io::AsyncWriteExt, process::Command};
+
+use crate::HookArgs;
+
+impl HookArgs {
+    fn get(&self, operation: FirecrackerNetworkOperation, post: bool) -> Option<&str> {
+        match (operation, post) {
+            (FirecrackerNetworkOperation::Add, false) => self.pre_add.as_deref(),
+            (FirecrackerNetworkOperation::Add, true) => self.post_add.as_deref(),
+            (FirecrackerNetworkOperation::Check, false) => self.pre_check.as_deref(),
+            (FirecrackerNetworkOperation::Check, true) => self.post_check.as_deref(),
+            (FirecrackerNetworkOperation::Delete, false) => self.pre_delete.as_deref(),
+            (FirecrackerNetworkOperation::Delete, true) => self.post_delete.as_deref(),
+            (FirecrackerNetworkOperation::Flush, _) => None,
+        }
+    }
+}
+
+impl FirecrackerNetworkHooks for HookArgs {
+    async fn pre_operation(&self, network: &FirecrackerNetwork, operation: FirecrackerNetworkOperation) -> Result<(), String> {
+        match self.get(operation, false) {
+            Some(path) => run_hook(path, network, operation, "pre", None).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn post_operation(
+        &self,
+        network: &FirecrackerNetwork,
+        operation: FirecrackerNetworkOperation,
+        result: &Result<(), FirecrackerNetworkError>,
+    ) -> Result<(), String> {
+        match self.get(operation, true) {
+            Some(path) => run_hook(path, network, operation, "post", Some(result)).await,
+            None => Ok(()),
+        }
+    }
+}
+
+/// Run a hook executable with the network as JSON on stdin, the hook's stage and operation in the "FCNET_HOOK"
+/// environment variable (e.g. "post-add") and, for post-hooks, the operation's result ("OK" or the error) in the
+/// "FCNET_RESULT" environment variable.
+#[tracing::instrument(skip(network, result))]
+async fn run_hook(
+    path: &str,
+    network: &FirecrackerNetwork,
+    operation: FirecrackerNetworkOperation,
+    stage: &str,
+    result: Option<&Result<(), FirecrackerNetworkError>>,
+) -> Result<(), String> {
+    let network_json = serde_json::to_vec(network).map_err(|err| format!("Could not serialize the network: {err}"))?;
+    let operation = match operation {
+        FirecrackerNetworkOperation::Add => "add",
+        FirecrackerNetworkOperation::Check => "check",
+        FirecrackerNetworkOperation::Delete => "delete",
+        FirecrackerNetworkOperation::Flush => "flush",
+    };
+
+    let mut command = Command::new(path);
+    command
+        .env("FCNET_HOOK", format!("{stage}-{operation}"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped());
+
+    match result {
+        Some(Ok(_)) => command.env("FCNET_RESULT", "OK"),
+        Some(Err(err)) => command.env("FCNET_RESULT", err.to_string()),
+        None => &mut command,
+    };
+
+    let mut child = command.spawn().map_err(|err| format!("Could not spawn {path}: {err}"))?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        // a hook that doesn't read its stdin may exit before the write completes, so a broken pipe is left to
+        // the exit status to judge
+        match stdin.write_all(&network_json).await {
+            Err(err) if err.kind() != std::io::ErrorKind::BrokenPipe => {
+                return Err(format!("Could not write the network to {path}: {err}"));
+            }
+            _ => {}
+        }
+    }
+
+    let output = child
+        .wait_with_output()
+        .await
+        .map_err(|err| format!("Could not wait for {path}: {err}"))?;
+
+    match output.status.success() {
+        true => {
+            tracing::debug!("Hook succeeded");
+            Ok(())
+        }
+        false => Err(format!(
+            "{path} exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )),
+    }
+}
@@ -1,6 +1,7 @@
 use clap::Parser;
 use tracing::level_filters::LevelFilter;
 
+mod hooks;
 mod server;
 use server::start;
 
@@ -27,11 +28,55 @@ struct Cli {
         short = 'p'
     )]
     pub password: Option<String>,
+    #[arg(
+        help = "Watch the default routes and switch networks with an automatically resolved host interface over when they move",
+        long = "watch-uplink",
+        short = 'W'
+    )]
+    pub watch_uplink: bool,
+    #[arg(
+        help = "Watch for links, addresses and routes of added networks being deleted externally and log such drift",
+        long = "watch-drift"
+    )]
+    pub watch_drift: bool,
+    #[arg(
+        help = "Periodically converge the host to the list of networks in a JSON config file, creating, deleting and repairing them",
+        long = "config",
+        short = 'c'
+    )]
+    pub config_path: Option<String>,
+    #[arg(
+        help = "The interval in seconds between reconciliations with the config file",
+        long = "reconcile-interval",
+        default_value_t = 30,
+        requires = "config_path"
+    )]
+    pub reconcile_interval: u64,
+    #[command(flatten)]
+    pub hooks: HookArgs,
     #[arg(help = "The logging level to use", long = "log-level", short = 'L', default_value_t = CliLogLevel::Debug)]
     pub log_level: CliLogLevel,
     pub socket_path: String,
 }
 
+/// Executables that are run around network operations with the network as JSON on stdin. A failing pre-hook aborts
+/// the operation, while a failing post-hook rolls back a successful add or delete.
+#[derive(clap::Args)]
+struct HookArgs {
+    #[arg(help = "An executable to run before adding a network", long = "pre-add-hook")]
+    pub pre_add: Option<String>,
+    #[arg(help = "An executable to run after adding a network", long = "post-add-hook")]
+    pub post_add: Option<String>,
+    #[arg(help = "An executable to run before checking a network", long = "pre-check-hook")]
+    pub pre_check: Option<String>,
+    #[arg(help = "An executable to run after checking a network", long = "post-check-hook")]
+    pub post_check: Option<String>,
+    #[arg(help = "An executable to run before deleting a network", long = "pre-delete-hook")]
+    pub pre_delete: Option<String>,
+    #[arg(help = "An executable to run after deleting a network", long = "post-delete-hook")]
+    pub post_delete: Option<String>,
+}
+
 #[derive(clap::ValueEnum, Clone, Copy)]
 enum CliLogLevel {
     Trace,
@@ -41,16 +86,15 @@ enum CliLogLevel {
     Error,
 }
 
-impl ToString for CliLogLevel {
-    fn to_string(&self) -> String {
-        match self {
+impl std::fmt::Display for CliLogLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
             CliLogLevel::Trace => "trace",
             CliLogLevel::Debug => "debug",
             CliLogLevel::Info => "info",
             CliLogLevel::Warn => "warn",
             CliLogLevel::Error => "error",
-        }
-        .to_string()
+        })
     }
 }
 
@@ -1,7 +1,13 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-use fcnet::backend::TokioBackend;
-use fcnet_types::{FirecrackerNetwork, FirecrackerNetworkOperation};
+use fcnet::{backend::TokioBackend, UplinkChange};
+use fcnet_types::{FirecrackerNetwork, FirecrackerNetworkHandle, FirecrackerNetworkOperation};
+use futures_util::StreamExt;
 use nix::unistd::{Gid, Uid};
 use serde::Deserialize;
 use tokio::{
@@ -15,6 +21,10 @@ use crate::Cli;
 struct Request {
     operation: FirecrackerNetworkOperation,
     network: FirecrackerNetwork,
+    #[serde(default)]
+    handle: Option<FirecrackerNetworkHandle>,
+    #[serde(default)]
+    with_handle: bool,
 }
 
 #[tracing::instrument(skip(cli))]
@@ -23,8 +33,23 @@ pub async fn start(cli: Cli) {
     tracing::info!("Starting to serve over the socket: {}", cli.socket_path);
 
     let cli = Arc::new(cli);
+    let watched_networks = Arc::new(Mutex::new(Vec::new()));
     let mut connection_id = 0;
 
+    if cli.watch_uplink {
+        for addr in [IpAddr::V4(Ipv4Addr::UNSPECIFIED), IpAddr::V6(Ipv6Addr::UNSPECIFIED)] {
+            tokio::task::spawn(watch_uplink(addr, watched_networks.clone()));
+        }
+    }
+
+    if cli.watch_drift {
+        tokio::task::spawn(watch_drift(watched_networks.clone()));
+    }
+
+    if let Some(ref config_path) = cli.config_path {
+        tokio::task::spawn(reconcile(cli.clone(), config_path.clone(), watched_networks.clone()));
+    }
+
     loop {
         let stream = match listener.accept().await {
             Ok((stream, addr)) => {
@@ -37,13 +62,18 @@ pub async fn start(cli: Cli) {
             }
         };
 
-        tokio::task::spawn(serve_connection(cli.clone(), stream, connection_id));
+        tokio::task::spawn(serve_connection(cli.clone(), watched_networks.clone(), stream, connection_id));
         connection_id += 1;
     }
 }
 
-#[tracing::instrument(skip(cli, stream))]
-async fn serve_connection(cli: Arc<Cli>, mut stream: UnixStream, connection_id: u64) {
+#[tracing::instrument(skip(cli, watched_networks, stream))]
+async fn serve_connection(
+    cli: Arc<Cli>,
+    watched_networks: Arc<Mutex<Vec<FirecrackerNetwork>>>,
+    mut stream: UnixStream,
+    connection_id: u64,
+) {
     if let Some(ref password) = cli.password {
         let mut line_reader = BufReader::new(&mut stream).lines();
         let provided_password = match line_reader.next_line().await {
@@ -91,10 +121,59 @@ async fn serve_connection(cli: Arc<Cli>, mut stream: UnixStream, connection_id:
             continue;
         };
 
-        match fcnet::run::<TokioBackend>(&request.network, request.operation).await {
+        let result = match request.handle {
+            Some(ref handle) => {
+                fcnet::run_with_handle_and_hooks::<TokioBackend, _>(&request.network, request.operation, handle, &cli.hooks).await
+            }
+            None => fcnet::run_with_hooks::<TokioBackend, _>(&request.network, request.operation, &cli.hooks).await,
+        };
+
+        match result {
             Ok(_) => {
                 tracing::info!(operation = ?request.operation, "Network operation succeeded");
-                if let Err(err) = stream.write_all(b"OK\n").await {
+
+                // conflicting base chains don't fail the check, since they may be intended by the host's firewall
+                if request.operation == FirecrackerNetworkOperation::Check {
+                    match fcnet::diagnose::<TokioBackend>(&request.network).await {
+                        Ok(conflicts) => {
+                            for conflict in conflicts {
+                                tracing::warn!(%conflict, "Network has a conflicting base chain");
+                            }
+                        }
+                        Err(err) => tracing::warn!(?err, "Diagnosing conflicting base chains failed"),
+                    }
+                }
+
+                // adds that ask for it are followed by a line with the handle of the created objects, which clients
+                // can pass back for later operations, or with the reason why it's unavailable
+                let mut response = String::from("OK\n");
+
+                if request.operation == FirecrackerNetworkOperation::Add && request.with_handle {
+                    match fcnet::get_handle::<TokioBackend>(&request.network).await {
+                        Ok(handle) => match serde_json::to_string(&handle) {
+                            Ok(handle_json) => response.push_str(&handle_json),
+                            Err(err) => response.push_str(&format!("Serializing the handle failed: {err}")),
+                        },
+                        Err(err) => {
+                            tracing::warn!(?err, "Getting the handle of the added network failed");
+                            response.push_str(&format!("Getting the handle failed: {err}"));
+                        }
+                    }
+
+                    response.push('\n');
+                }
+
+                if cli.watch_uplink || cli.watch_drift {
+                    let mut watched_networks = watched_networks.lock().expect("Watched networks mutex was poisoned");
+
+                    match request.operation {
+                        FirecrackerNetworkOperation::Add => watched_networks.push(request.network),
+                        FirecrackerNetworkOperation::Delete => watched_networks.retain(|network| *network != request.network),
+                        FirecrackerNetworkOperation::Check | FirecrackerNetworkOperation::Flush => {}
+                    }
+                }
+
+                if let Err(err) = stream.write_all(response.as_bytes()).await {
                     tracing::error!(?err, "Could not write OK response to the connection");
                 }
             }
@@ -108,6 +187,131 @@ async fn serve_connection(cli: Arc<Cli>, mut stream: UnixStream, connection_id:
     }
 }
 
+#[tracing::instrument(skip(watched_networks))]
+async fn watch_uplink(addr: IpAddr, watched_networks: Arc<Mutex<Vec<FirecrackerNetwork>>>) {
+    let mut uplink_changes = match fcnet::watch_uplink::<TokioBackend>(addr).await {
+        Ok(uplink_changes) => Box::pin(uplink_changes),
+        Err(err) => {
+            tracing::error!(?err, "Could not start watching the default route");
+            return;
+        }
+    };
+    tracing::info!("Started watching the default route");
+
+    while let Some(UplinkChange { from, to }) = uplink_changes.next().await {
+        let (Some(from), Some(to)) = (from, to) else {
+            tracing::warn!("Default route appeared or disappeared, not switching networks over");
+            continue;
+        };
+
+        let networks = watched_networks
+            .lock()
+            .expect("Watched networks mutex was poisoned")
+            .iter()
+            // only networks with an automatically resolved host interface follow the default route
+            .filter(|network| network.iface_name.is_none() && network.guest_ip.address().is_ipv4() == addr.is_ipv4())
+            .cloned()
+            .collect::<Vec<_>>();
+        tracing::info!(
+            from,
+            to,
+            networks = networks.len(),
+            "Default route moved, switching networks over"
+        );
+
+        for network in networks {
+            match fcnet::switch_uplink::<TokioBackend>(&network, &from, &to).await {
+                Ok(_) => tracing::info!(tap_name = network.tap_name, from, to, "Network was switched over"),
+                Err(err) => tracing::warn!(?err, tap_name = network.tap_name, "Network switchover failed"),
+            }
+        }
+    }
+
+    tracing::warn!("Stopped watching the default route");
+}
+
+#[tracing::instrument(skip(watched_networks))]
+async fn watch_drift(watched_networks: Arc<Mutex<Vec<FirecrackerNetwork>>>) {
+    let mut drifts =
+        match fcnet::watch::<TokioBackend>(move || watched_networks.lock().expect("Watched networks mutex was poisoned").clone())
+        {
+            Ok(drifts) => Box::pin(drifts),
+            Err(err) => {
+                tracing::error!(?err, "Could not start watching for network drift");
+                return;
+            }
+        };
+    tracing::info!("Started watching for network drift");
+
+    while let Some(drift) = drifts.next().await {
+        tracing::warn!(
+            tap_name = drift.network.tap_name,
+            object_type = ?drift.object_type,
+            object = drift.object,
+            "Network drifted due to an object being deleted externally"
+        );
+    }
+
+    tracing::warn!("Stopped watching for network drift");
+}
+
+#[tracing::instrument(skip(cli, watched_networks))]
+async fn reconcile(cli: Arc<Cli>, config_path: String, watched_networks: Arc<Mutex<Vec<FirecrackerNetwork>>>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(cli.reconcile_interval));
+    // networks that passed their check on the first reconciliation are adopted, so that they're deleted once they're
+    // removed from the config file
+    let mut applied_networks = Vec::<FirecrackerNetwork>::new();
+    tracing::info!("Started reconciling networks with the config file");
+
+    loop {
+        interval.tick().await;
+
+        let desired_networks = match std::fs::read_to_string(&config_path)
+            .map_err(|err| err.to_string())
+            .and_then(|json| serde_json::from_str::<Vec<FirecrackerNetwork>>(&json).map_err(|err| err.to_string()))
+        {
+            Ok(networks) => networks,
+            Err(err) => {
+                tracing::warn!(err, "Could not read the config file, skipping reconciliation");
+                continue;
+            }
+        };
+
+        let changes = fcnet::plan::<TokioBackend>(&desired_networks, &applied_networks).await;
+        let mut failed_changes = Vec::new();
+
+        for change in &changes {
+            match fcnet::apply_with_hooks::<TokioBackend, _>(change, &cli.hooks).await {
+                Ok(_) => tracing::info!(
+                    action = ?change.action,
+                    tap_name = change.network.tap_name,
+                    reason = ?change.reason,
+                    "Network was reconciled"
+                ),
+                Err(err) => {
+                    tracing::warn!(?err, action = ?change.action, tap_name = change.network.tap_name, "Network reconciliation failed");
+                    failed_changes.push(change);
+                }
+            }
+        }
+
+        let new_applied_networks = fcnet::applied_networks(desired_networks, &failed_changes);
+
+        if cli.watch_uplink || cli.watch_drift {
+            let mut watched_networks = watched_networks.lock().expect("Watched networks mutex was poisoned");
+            watched_networks.retain(|network| !applied_networks.contains(network) || new_applied_networks.contains(network));
+
+            for network in &new_applied_networks {
+                if !watched_networks.contains(network) {
+                    watched_networks.push(network.clone());
+                }
+            }
+        }
+
+        applied_networks = new_applied_networks;
+    }
+}
+
 #[tracing::instrument(skip(cli))]
 fn setup_socket(cli: &Cli) -> UnixListener {
     if std::fs::exists(&cli.socket_path).expect("Could not check if socket exists") {